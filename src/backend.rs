@@ -0,0 +1,386 @@
+use std::{
+  fmt,
+  sync::Arc,
+  time::Duration,
+};
+
+use async_trait::async_trait;
+use reqwest::header;
+use serde::{
+  Serialize,
+  Deserialize,
+};
+
+use crate::{
+  kobold::{
+    KoboldBackend,
+    StoredMessage,
+  },
+  ollama::OllamaBackend,
+  openai::OpenAiBackend,
+  prompt::{
+    fit_history,
+    system_with_memory,
+    TokenCounter,
+  },
+};
+
+//the knobs every backend understands in one form or another, each backend maps them onto its own api
+#[derive(Debug, Clone)]
+pub struct SamplerSettings{
+  pub temperature: f32,
+  pub top_p: f32,
+  pub top_k: i32,
+  pub min_p: f32,
+  pub typical_p: f32,
+  pub repetition_penalty: f32,
+  pub repetition_penalty_range: u32,
+  pub frequency_penalty: f32,
+  pub presence_penalty: f32,
+  pub mirostat_mode: u32,
+  pub mirostat_tau: f32,
+  pub mirostat_eta: f32,
+  pub seed: i32,
+  pub max_length: u32,
+  pub context_length: u32,
+}
+
+impl Default for SamplerSettings{
+  fn default() -> Self{
+    Self{
+      temperature: 1.0,
+      top_p: 1.0,
+      top_k: 0,
+      min_p: 0.0,
+      typical_p: 1.0,
+      repetition_penalty: 1.0,
+      repetition_penalty_range: 0,
+      frequency_penalty: 0.0,
+      presence_penalty: 0.0,
+      mirostat_mode: 0,
+      mirostat_tau: 5.0,
+      mirostat_eta: 0.1,
+      seed: -1,
+      max_length: 512,
+      context_length: 16384,
+    }
+  }
+}
+
+impl SamplerSettings{
+  //the prompt has to leave room for the reply inside the context window
+  pub fn prompt_budget(&self) -> usize{
+    self.context_length.saturating_sub(self.max_length) as usize
+  }
+
+  pub fn apply(&mut self, preset: &SamplerPreset){
+    macro_rules! merge{
+      ($($field:ident),*) => {
+        $(if let Some(value) = preset.$field{
+          self.$field = value;
+        })*
+      };
+    }
+    merge!(
+      temperature, top_p, top_k, min_p, typical_p,
+      repetition_penalty, repetition_penalty_range, frequency_penalty, presence_penalty,
+      mirostat_mode, mirostat_tau, mirostat_eta, seed, max_length, context_length
+    );
+  }
+}
+
+//a named set of overrides, anything left out keeps the default or whatever a broader preset set
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SamplerPreset{
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub temperature: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub top_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub top_k: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub min_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub typical_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub repetition_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub repetition_penalty_range: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub frequency_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub presence_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mirostat_mode: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mirostat_tau: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mirostat_eta: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub seed: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub max_length: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub context_length: Option<u32>,
+}
+
+pub struct GenerationRequest{
+  pub system: String,
+  pub messages: Vec<StoredMessage>,
+  pub bot_name: String,
+  //what happened in the channel before the oldest message that was kept verbatim
+  pub summary: Option<String>,
+  //older messages from anywhere in the guild that look related to what's being asked
+  pub recalled: Vec<String>,
+  //who asked for the generation and what's known about them
+  pub speaker: String,
+  pub facts: Vec<String>,
+  pub settings: SamplerSettings,
+  //identifies the generation to the server so it can be aborted
+  pub genkey: String,
+}
+
+#[derive(Debug)]
+pub enum GenerationError{
+  Unreachable(String),
+  Busy(String),
+  Failed(String),
+}
+
+impl fmt::Display for GenerationError{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+    match self{
+      Self::Unreachable(msg) => write!(f, "server is unreachable: {}", msg),
+      Self::Busy(msg) => write!(f, "server is busy: {}", msg),
+      Self::Failed(msg) => write!(f, "generation failed: {}", msg),
+    }
+  }
+}
+
+//anything that wants to see tokens as they come out of the backend
+#[async_trait]
+pub trait TokenSink: Send{
+  async fn push(&mut self, token: &str);
+}
+
+//for generations nobody watches being written
+pub struct DiscardTokens;
+
+#[async_trait]
+impl TokenSink for DiscardTokens{
+  async fn push(&mut self, _token: &str){}
+}
+
+#[async_trait]
+pub trait GenerationBackend: Send + Sync{
+  //shown to users when the server can't be reached
+  fn name(&self) -> &'static str;
+  //streams the reply into the sink and hands back the full text once the server is done
+  async fn generate(&self, request: &GenerationRequest, sink: &mut dyn TokenSink) -> Result<String, GenerationError>;
+  //tells the server to stop working on a generation, dropping the stream is enough for servers without an abort call
+  async fn abort(&self, _genkey: &str){}
+}
+
+pub enum BackendKind{
+  Kobold,
+  OpenAi,
+  Ollama,
+}
+
+impl BackendKind{
+  //BACKEND picks the inference server, koboldcpp when it isn't set
+  pub fn from_env() -> Result<Self, String>{
+    match std::env::var("BACKEND").unwrap_or_default().trim().to_lowercase().as_str(){
+      "" | "kobold" | "koboldcpp" => Ok(Self::Kobold),
+      "openai" => Ok(Self::OpenAi),
+      "ollama" => Ok(Self::Ollama),
+      other => Err(format!("Unknown BACKEND {}, expected kobold, openai or ollama", other)),
+    }
+  }
+
+  pub fn url_var(&self) -> &'static str{
+    match self{
+      Self::Kobold => "KOBOLD_URL",
+      Self::OpenAi => "OPENAI_URL",
+      Self::Ollama => "OLLAMA_URL",
+    }
+  }
+
+  //the variables the backend can't run without, checked once at startup so nothing fails mid conversation
+  pub fn check_env(&self) -> Result<(), String>{
+    let mut required = vec![self.url_var()];
+    if matches!(self, Self::Ollama){
+      required.push("OLLAMA_MODEL");
+    }
+    for var in required{
+      if std::env::var(var).map(|value| value.trim().is_empty()).unwrap_or(true){
+        return Err(format!("Expected {} in the environment variables", var));
+      }
+    }
+    Ok(())
+  }
+}
+
+pub fn backend_from_env() -> Result<Arc<dyn GenerationBackend>, String>{
+  Ok(match BackendKind::from_env()?{
+    BackendKind::Kobold => Arc::new(KoboldBackend::from_env()),
+    BackendKind::OpenAi => Arc::new(OpenAiBackend::from_env()),
+    BackendKind::Ollama => Arc::new(OllamaBackend::from_env()),
+  })
+}
+
+pub fn endpoint(url_var: &str, path: &str) -> String{
+  //add the / at the end of the server url if it's not there
+  let mut server = std::env::var(url_var).unwrap();
+  if !server.ends_with('/'){
+    server.push('/');
+  }
+  server.push_str(path);
+  server
+}
+
+//the stream can legitimately run for minutes, so only time out when the server goes quiet
+pub fn streaming_client(accept: &'static str) -> Result<reqwest::Client, GenerationError>{
+  let mut headers = header::HeaderMap::new();
+  headers.insert("accept", header::HeaderValue::from_static(accept));
+  headers.insert("Content-Type", header::HeaderValue::from_static("application/json"));
+  reqwest::Client::builder()
+    .default_headers(headers)
+    .connect_timeout(Duration::from_secs(10))
+    .read_timeout(Duration::from_secs(60))
+    .build()
+    .map_err(|err| GenerationError::Failed(format!("Reqwest client can't be built: {}", err)))
+}
+
+//where the first event ends, just past the blank line after it. lines may end in \n or \r\n
+fn sse_event_end(buffer: &[u8]) -> Option<usize>{
+  let mut line_start = 0;
+  for (i, byte) in buffer.iter().enumerate(){
+    if *byte != b'\n'{
+      continue;
+    }
+    let line = &buffer[line_start..i];
+    if line.is_empty() || line == b"\r"{
+      return Some(i+1);
+    }
+    line_start = i+1;
+  }
+  None
+}
+
+//pulls every complete server sent event out of the buffer and returns their data payloads,
+//anything after the last blank line is left in the buffer for the next chunk
+pub fn drain_sse_events(buffer: &mut Vec<u8>) -> Vec<String>{
+  let mut payloads = Vec::new();
+  while let Some(end) = sse_event_end(buffer){
+    let event: Vec<u8> = buffer.drain(..end).collect();
+    let event = String::from_utf8_lossy(&event);
+    let data: Vec<&str> = event.lines()
+      .filter_map(|line| line.trim_end_matches('\r').strip_prefix("data:"))
+      .map(|line| line.trim_start())
+      .collect();
+    if !data.is_empty(){
+      payloads.push(data.join("\n"));
+    }
+  }
+  payloads
+}
+
+//newline delimited json, every complete line is one object and a partial line waits for the next chunk
+pub fn drain_json_lines(buffer: &mut Vec<u8>) -> Vec<String>{
+  let mut lines = Vec::new();
+  while let Some(end) = buffer.iter().position(|b| *b == b'\n'){
+    let line: Vec<u8> = buffer.drain(..end+1).collect();
+    let line = String::from_utf8_lossy(&line).trim().to_string();
+    if !line.is_empty(){
+      lines.push(line);
+    }
+  }
+  lines
+}
+
+#[derive(Serialize)]
+pub struct ChatMessage{
+  pub role: &'static str,
+  pub content: String,
+}
+
+//for servers that apply the chat template themselves, the bot's turns only need the text and
+//everyone else needs a name in front so the model can tell the speakers apart
+pub async fn build_chat_messages(request: &GenerationRequest) -> Vec<ChatMessage>{
+  let contents: Vec<(&'static str, String)> = request.messages.iter().map(|msg| {
+    if msg.author == request.bot_name{
+      ("assistant", msg.message.clone())
+    }else{
+      ("user", format!("{}: {}", msg.author, msg.message))
+    }
+  }).collect();
+  let rendered: Vec<String> = contents.iter().map(|(_, content)| content.clone()).collect();
+  let system = system_with_memory(request);
+  let first_kept = fit_history(
+    &system,
+    "",
+    &rendered,
+    request.settings.prompt_budget(),
+    &TokenCounter::Estimate,
+  ).await;
+  let mut messages = vec![ChatMessage{
+    role: "system",
+    content: system,
+  }];
+  messages.extend(contents.into_iter().skip(first_kept).map(|(role, content)| ChatMessage{role, content}));
+  messages
+}
+
+#[cfg(test)]
+mod tests{
+  use super::*;
+
+  #[test]
+  fn sse_event_ends_after_blank_line(){
+    assert_eq!(sse_event_end(b"data: a\n\n"), Some(9));
+    assert_eq!(sse_event_end(b"data: a\r\n\r\n"), Some(11));
+    assert_eq!(sse_event_end(b"data: a\n"), None);
+    assert_eq!(sse_event_end(b"data: a\r\n\r"), None);
+  }
+
+  #[test]
+  fn sse_events_with_lf_and_crlf_framing(){
+    let mut buffer = b"data: one\n\ndata: two\r\n\r\nevent: ping\r\n\r\n".to_vec();
+    assert_eq!(drain_sse_events(&mut buffer), vec!["one", "two"]);
+    assert!(buffer.is_empty());
+  }
+
+  #[test]
+  fn sse_multi_line_data_is_joined(){
+    let mut buffer = b"data: first\r\ndata: second\r\n\r\n".to_vec();
+    assert_eq!(drain_sse_events(&mut buffer), vec!["first\nsecond"]);
+  }
+
+  #[test]
+  fn sse_event_split_across_chunks(){
+    let mut buffer = b"data: {\"text\":".to_vec();
+    assert!(drain_sse_events(&mut buffer).is_empty());
+    buffer.extend_from_slice(b" \"hi\"}\r\n");
+    assert!(drain_sse_events(&mut buffer).is_empty());
+    buffer.extend_from_slice(b"\r\ndata: next");
+    assert_eq!(drain_sse_events(&mut buffer), vec!["{\"text\": \"hi\"}"]);
+    assert_eq!(buffer, b"data: next");
+  }
+
+  #[test]
+  fn sse_done_marker_comes_through_as_payload(){
+    let mut buffer = b"data: {\"a\":1}\n\ndata: [DONE]\n\n".to_vec();
+    assert_eq!(drain_sse_events(&mut buffer), vec!["{\"a\":1}", "[DONE]"]);
+  }
+
+  #[test]
+  fn json_lines_keep_partial_line(){
+    let mut buffer = b"{\"a\":1}\r\n\n{\"b\":".to_vec();
+    assert_eq!(drain_json_lines(&mut buffer), vec!["{\"a\":1}"]);
+    assert_eq!(buffer, b"{\"b\":");
+    buffer.extend_from_slice(b"2}\n");
+    assert_eq!(drain_json_lines(&mut buffer), vec!["{\"b\":2}"]);
+    assert!(buffer.is_empty());
+  }
+}
@@ -0,0 +1,355 @@
+use std::{
+  collections::{
+    hash_map::Entry,
+    BTreeSet,
+    HashMap,
+  },
+  fmt,
+  path::PathBuf,
+};
+
+use crate::{
+  embeddings::EmbeddingJob,
+  encryption::{
+    EncryptedDatabase,
+    MessageCipher,
+  },
+  kobold::StoredMessage,
+  postgres::PostgresDatabase,
+  retention::{
+    PruneReport,
+    RetentionPolicy,
+  },
+  sqlite::SqliteDatabase,
+  storage::{
+    RecalledMessage,
+    SearchFilter,
+    SearchHit,
+    StorageMessage,
+  },
+  transfer::{
+    ExportRecord,
+    ExportScope,
+    ImportedMessage,
+  },
+};
+
+#[derive(Debug)]
+pub enum DatabaseError{
+  Sqlite(rusqlite::Error),
+  Postgres(::postgres::Error),
+  Io(std::io::Error),
+  Encryption(String),
+  Config(String),
+}
+
+impl fmt::Display for DatabaseError{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+    match self{
+      Self::Sqlite(err) => write!(f, "sqlite: {}", err),
+      Self::Postgres(err) => write!(f, "postgres: {}", err),
+      Self::Io(err) => write!(f, "{}", err),
+      Self::Encryption(err) => write!(f, "encryption: {}", err),
+      Self::Config(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+impl From<rusqlite::Error> for DatabaseError{
+  fn from(err: rusqlite::Error) -> Self{
+    Self::Sqlite(err)
+  }
+}
+
+impl From<::postgres::Error> for DatabaseError{
+  fn from(err: ::postgres::Error) -> Self{
+    Self::Postgres(err)
+  }
+}
+
+impl From<std::io::Error> for DatabaseError{
+  fn from(err: std::io::Error) -> Self{
+    Self::Io(err)
+  }
+}
+
+pub type DatabaseResult<T> = Result<T, DatabaseError>;
+
+//the columns that hold conversation text, which is what encryption at rest covers
+#[derive(Debug, Clone, Copy)]
+pub enum TextColumn{
+  Messages,
+  Summaries,
+  Facts,
+}
+
+pub const TEXT_COLUMNS: [TextColumn; 3] = [TextColumn::Messages, TextColumn::Summaries, TextColumn::Facts];
+
+//everything storage keeps, whichever server it ends up in. calls block, so they only ever run on the
+//storage thread or the read pool
+pub trait Database: Send{
+  //returns the new row ids in the order the messages were given, all of them go in as one transaction
+  fn insert_messages(&mut self, messages: &[StorageMessage]) -> DatabaseResult<Vec<i64>>;
+  //the newest rows of a channel between after and until (inclusive), oldest first
+  fn context(&mut self, channel: u64, after: i64, until: i64, limit: u32) -> DatabaseResult<Vec<StoredMessage>>;
+  //the oldest rows of a channel after the given row along with their ids
+  fn unsummarized(&mut self, channel: u64, after: i64, limit: u32) -> DatabaseResult<Vec<(i64, StoredMessage)>>;
+  //what a member said last in a guild, oldest first
+  fn member_messages(&mut self, guild: u64, user_id: u64, limit: usize) -> DatabaseResult<Vec<String>>;
+  //parts are the discord messages after the first the new text is shown in
+  fn replace_bot_message(&mut self, discord_id: u64, message: &str, parts: &[u64]) -> DatabaseResult<()>;
+  //the bot's own rows are left alone, discord also reports the edits made while streaming a reply
+  fn edit_message(&mut self, discord_id: u64, message: &str, edited_at: i64) -> DatabaseResult<()>;
+  //tombstones the rows and drops their embeddings, deleting any part of a long reply takes the whole reply
+  fn delete_messages(&mut self, discord_ids: &[u64], deleted_at: i64) -> DatabaseResult<()>;
+  //the row posted as this discord message, None when it isn't in a guild or has no text
+  fn embedding_job(&mut self, discord_id: u64) -> DatabaseResult<Option<EmbeddingJob>>;
+  fn store_embedding(&mut self, message: i64, guild: u64, model: &str, vector: &[f32]) -> DatabaseResult<()>;
+  //the closest matches among the window newest embeddings of the guild
+  fn recall(&mut self, guild: u64, model: &str, vector: &[f32], limit: usize, window: u32) -> DatabaseResult<Vec<RecalledMessage>>;
+  //best matches first
+  fn search(&mut self, filter: &SearchFilter, limit: u32) -> DatabaseResult<Vec<SearchHit>>;
+  //up to limit rows the filter allows whatever the query is, newest first and older than the row before
+  //when it's given, each with its row id. encrypted text can't be indexed, so searches page through these
+  //instead when encryption is on
+  fn search_candidates(&mut self, filter: &SearchFilter, before: Option<i64>, limit: u32) -> DatabaseResult<Vec<(i64, SearchHit)>>;
+
+  //the channel's newest summary and the last row it covers
+  fn latest_summary(&mut self, channel: u64) -> DatabaseResult<Option<(String, i64)>>;
+  fn store_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<()>;
+
+  fn guild_persona(&mut self, guild: u64) -> DatabaseResult<Option<String>>;
+  fn set_persona(&mut self, guild: u64, persona: &str) -> DatabaseResult<()>;
+  //presets are json, these are the ones that apply to the channel with the channel's own one last
+  fn active_presets(&mut self, guild: u64, channel: u64) -> DatabaseResult<Vec<String>>;
+  //every preset of the guild by name
+  fn presets(&mut self, guild: u64) -> DatabaseResult<Vec<(String, String)>>;
+  fn save_preset(&mut self, guild: u64, name: &str, preset: &str) -> DatabaseResult<()>;
+  //also drops the preset's assignments, returns whether there was a preset to delete
+  fn delete_preset(&mut self, guild: u64, name: &str) -> DatabaseResult<bool>;
+  fn assigned_preset(&mut self, guild: u64, channel: u64) -> DatabaseResult<Option<String>>;
+  //a preset of None clears the assignment
+  fn assign_preset(&mut self, guild: u64, channel: u64, preset: Option<&str>) -> DatabaseResult<()>;
+  //guild, channel and preset of every assignment in the scope
+  fn preset_assignments(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(u64, u64, String)>>;
+
+  //replaces everything known about a member, an empty list clears it
+  fn set_facts(&mut self, guild: u64, user_id: u64, facts: &[String]) -> DatabaseResult<()>;
+  fn load_facts(&mut self, guild: u64, user_id: u64) -> DatabaseResult<Vec<String>>;
+
+  //guilds without a policy get the empty one
+  fn load_policy(&mut self, guild: u64) -> DatabaseResult<RetentionPolicy>;
+  //an empty policy removes the guild's row
+  fn save_policy(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<()>;
+  fn retention_guilds(&mut self) -> DatabaseResult<Vec<u64>>;
+  //what the policy would remove right now, without removing it
+  fn prune_candidates(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<(PruneReport, BTreeSet<i64>)>;
+  //removes rows for good along with everything derived from them
+  fn delete_rows(&mut self, ids: &BTreeSet<i64>) -> DatabaseResult<()>;
+  //hands pages freed by pruning back, for servers that don't do it on their own
+  fn reclaim_space(&mut self) -> DatabaseResult<()>{
+    Ok(())
+  }
+  //after a key rotation, rewrites whatever storage still holds of the texts as they were before
+  fn scrub(&mut self) -> DatabaseResult<()>{
+    Ok(())
+  }
+  //removes or anonymizes everything a user said along with what was derived from it, returns how many messages
+  fn forget_user(&mut self, user_id: u64, guild: Option<u64>, anonymize: bool) -> DatabaseResult<usize>;
+
+  //message and summary records of the scope, each with the row it sorts by
+  fn export_messages(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>;
+  fn export_summaries(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>;
+  //matches an imported message up with an existing row before inserting it, returns the row and whether it's new
+  fn import_message(&mut self, message: &ImportedMessage) -> DatabaseResult<(i64, bool)>;
+  //false when the channel already has the same summary
+  fn import_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<bool>;
+
+  //every non empty value of the column by row id, exactly as stored
+  fn stored_texts(&mut self, column: TextColumn) -> DatabaseResult<Vec<(i64, String)>>;
+  fn rewrite_text(&mut self, column: TextColumn, id: i64, text: &str) -> DatabaseResult<()>;
+
+  //keeps other shards on the same database out of upkeep like pruning while it runs, false when one of them
+  //already holds it
+  fn lock_maintenance(&mut self) -> DatabaseResult<bool>;
+  fn unlock_maintenance(&mut self) -> DatabaseResult<()>;
+
+  //groups the calls in between into one transaction, nothing that opens its own transaction may run in between
+  fn begin(&mut self) -> DatabaseResult<()>;
+  fn commit(&mut self) -> DatabaseResult<()>;
+  fn rollback(&mut self) -> DatabaseResult<()>;
+}
+
+pub enum DatabaseKind{
+  Sqlite,
+  Postgres,
+}
+
+impl DatabaseKind{
+  //DATABASE_BACKEND picks where memory is kept, the local memory.db when it isn't set
+  pub fn from_env() -> Result<Self, String>{
+    match std::env::var("DATABASE_BACKEND").unwrap_or_default().trim().to_lowercase().as_str(){
+      "" | "sqlite" => Ok(Self::Sqlite),
+      "postgres" | "postgresql" => Ok(Self::Postgres),
+      other => Err(format!("Unknown DATABASE_BACKEND {}, expected sqlite or postgres", other)),
+    }
+  }
+
+  //checked once at startup like the backend's variables, so storage doesn't fail after the bot is up
+  pub fn check_env(&self) -> Result<(), String>{
+    if matches!(self, Self::Postgres) && database_url().is_err(){
+      return Err("Expected DATABASE_URL in the environment variables when DATABASE_BACKEND is postgres".to_string());
+    }
+    if matches!(self, Self::Postgres) && per_guild(){
+      return Err(PER_GUILD_POSTGRES.to_string());
+    }
+    Ok(())
+  }
+}
+
+const PER_GUILD_POSTGRES: &str = "DATABASE_PER_GUILD only works with sqlite, postgres keeps every guild in one database";
+
+pub fn database_url() -> DatabaseResult<String>{
+  std::env::var("DATABASE_URL").ok()
+    .filter(|url| !url.trim().is_empty())
+    .ok_or(DatabaseError::Config("DATABASE_URL is not set".to_string()))
+}
+
+//DATABASE_PATH is the sqlite file, ./memory.db in the working directory when it isn't set
+fn database_path() -> PathBuf{
+  std::env::var("DATABASE_PATH").ok()
+    .filter(|path| !path.trim().is_empty())
+    .unwrap_or("./memory.db".to_string())
+    .into()
+}
+
+//DATABASE_PER_GUILD=true gives every guild a sqlite file of its own in a folder named after DATABASE_PATH,
+//memory.db keeps direct messages and memory/<guild id>.db each guild
+pub fn per_guild() -> bool{
+  std::env::var("DATABASE_PER_GUILD").ok()
+    .and_then(|r| r.trim().parse().ok())
+    .unwrap_or(false)
+}
+
+fn guild_folder() -> PathBuf{
+  database_path().with_extension("")
+}
+
+//the file a guild's rows are kept in, None for the main one
+//DATABASE_BACKEND was checked at startup, the files it gets here can only ever be sqlite ones
+pub fn database_key(guild: Option<u64>) -> Option<u64>{
+  guild.filter(|_| per_guild() && matches!(DatabaseKind::from_env(), Ok(DatabaseKind::Sqlite)))
+}
+
+fn sqlite_path(key: Option<u64>) -> DatabaseResult<PathBuf>{
+  Ok(match key{
+    Some(guild) => {
+      std::fs::create_dir_all(guild_folder())?;
+      guild_folder().join(format!("{}.db", guild))
+    },
+    None => database_path(),
+  })
+}
+
+//every guild that has a file of its own
+fn guild_files() -> Vec<u64>{
+  let entries = match std::fs::read_dir(guild_folder()){
+    Ok(r) => r,
+    Err(_) => return Vec::new(),
+  };
+  entries.flatten()
+    .map(|entry| entry.path())
+    .filter(|path| path.extension().is_some_and(|extension| extension == "db"))
+    .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+    .collect()
+}
+
+//with a key configured everything above the database only ever sees plain text
+fn with_encryption(db: Box<dyn Database>, cipher: Option<&MessageCipher>) -> Box<dyn Database>{
+  match cipher{
+    Some(cipher) => Box::new(EncryptedDatabase::new(db, cipher.clone())),
+    None => db,
+  }
+}
+
+//opens the database a key from database_key points at and brings it up to the current schema
+pub fn open_database(key: Option<u64>, cipher: Option<&MessageCipher>) -> DatabaseResult<Box<dyn Database>>{
+  Ok(with_encryption(match DatabaseKind::from_env().map_err(DatabaseError::Config)?{
+    DatabaseKind::Sqlite => Box::new(SqliteDatabase::open(&sqlite_path(key)?, cipher.is_some())?),
+    DatabaseKind::Postgres => Box::new(PostgresDatabase::connect(true)?),
+  }, cipher))
+}
+
+//a connection for lookups next to the one open_database gave, the schema is already up to date by then.
+//a guild nothing was stored for yet gets its empty file set up first, so lookups find nothing instead of failing
+pub fn open_read_connection(key: Option<u64>, cipher: Option<&MessageCipher>) -> DatabaseResult<Box<dyn Database>>{
+  Ok(with_encryption(match DatabaseKind::from_env().map_err(DatabaseError::Config)?{
+    DatabaseKind::Sqlite => {
+      let path = sqlite_path(key)?;
+      if !path.exists(){
+        SqliteDatabase::open(&path, cipher.is_some())?;
+      }
+      Box::new(SqliteDatabase::open_read_only(&path)?)
+    },
+    DatabaseKind::Postgres => Box::new(PostgresDatabase::connect(false)?),
+  }, cipher))
+}
+
+//the databases storage writes to, just the one unless DATABASE_PER_GUILD splits guilds off into files
+//of their own, which are opened the first time they're needed
+pub struct Databases{
+  open: HashMap<Option<u64>, Box<dyn Database>>,
+  cipher: Option<MessageCipher>,
+}
+
+impl Databases{
+  pub fn open(cipher: Option<MessageCipher>) -> DatabaseResult<Self>{
+    if per_guild() && matches!(DatabaseKind::from_env().map_err(DatabaseError::Config)?, DatabaseKind::Postgres){
+      return Err(DatabaseError::Config(PER_GUILD_POSTGRES.to_string()));
+    }
+    let mut databases = Self{
+      open: HashMap::new(),
+      cipher,
+    };
+    databases.get(None)?;
+    Ok(databases)
+  }
+
+  //every database there is, open or not, the main one first
+  pub fn keys(&self) -> BTreeSet<Option<u64>>{
+    let mut keys: BTreeSet<Option<u64>> = self.open.keys().copied().collect();
+    if per_guild(){
+      keys.extend(guild_files().into_iter().map(Some));
+    }
+    keys
+  }
+
+  pub fn cipher(&self) -> Option<&MessageCipher>{
+    self.cipher.as_ref()
+  }
+
+  pub fn get(&mut self, guild: Option<u64>) -> DatabaseResult<&mut dyn Database>{
+    let key = database_key(guild);
+    let db = match self.open.entry(key){
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => entry.insert(open_database(key, self.cipher.as_ref())?),
+    };
+    Ok(db.as_mut())
+  }
+
+  //runs the job on every database in order, the main one first, for the jobs that cover all guilds. guild
+  //files that weren't open are closed again right after so a pass doesn't leave every file open.
+  //the first job to fail stops the pass
+  pub fn each(&mut self, mut job: impl FnMut(Option<u64>, &mut dyn Database) -> DatabaseResult<()>) -> DatabaseResult<()>{
+    for key in self.keys(){
+      match self.open.get_mut(&key){
+        Some(db) => job(key, db.as_mut())?,
+        None => match open_database(key, self.cipher.as_ref()){
+          Ok(mut db) => job(key, db.as_mut())?,
+          Err(err) => println!("Couldn't open the database of guild {:?}: {}", key, err),
+        },
+      }
+    }
+    Ok(())
+  }
+}
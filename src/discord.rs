@@ -2,43 +2,77 @@ use dashmap::DashMap;
 use poise::serenity_prelude as serenity;
 use ::serenity::{
   all::{
-    ChannelId as PoiseChannelId, Guild, Http, Mentionable, PartialGuild, Typing
+    ButtonStyle, ChannelId as PoiseChannelId, CreateActionRow, CreateButton, CreateMessage, EditMessage,
+    Guild, GuildChannel, Http, Mentionable, MessageId, PartialGuild, ReactionType, Typing
   }, 
+  constants::MESSAGE_CODE_LIMIT,
   async_trait
 };
+use chrono::NaiveDate;
 use songbird::{
   model::payload::Speaking,
   CoreEvent, EventContext, 
   EventHandler as VoiceEventHandler, 
   Songbird
 };
-use tokio::sync::{mpsc::UnboundedSender, Mutex as TokioMutex};
+use tokio::sync::{mpsc::UnboundedSender, oneshot, Mutex as TokioMutex};
 use futures_util::SinkExt;
 use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
 use std::{
+  collections::VecDeque,
   fmt::Debug, sync::{
     atomic::{
       AtomicBool, 
       Ordering,
     }, 
     Arc,
-  }
+  },
+  time::{
+    Duration,
+    Instant,
+  },
 };
 
 use crate::{
+  backend::{
+    SamplerPreset,
+    TokenSink,
+  },
+  database::Databases,
+  kobold::{
+    ActiveGenerations,
+    Generator,
+    KoboldRequest,
+  },
+  persona::PersonaLibrary,
+  retention::RetentionPolicy,
+  swipe::{
+    Swipes,
+    SwipeView,
+  },
+  transfer::{
+    ExportFormat,
+    ExportScope,
+    ImportTarget,
+  },
   storage::{
-    create_storage_thread, StorageMessage
+    create_storage_thread, unix_now, MessageSource, SearchFilter, SearchHit, StorageMessage, StorageRequest
   }, whisper::{spawn_whisper_thread, WhisperSink}
 };
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
 type CommandResult = Result<(), Error>;
+
 /*type DiscordStream = futures_util::stream::SplitStream<
   tokio_tungstenite::WebSocketStream<
     tokio_tungstenite::MaybeTlsStream<
       tokio::net::TcpStream>>>;*/
 
+//discord rate limits message edits, so streamed tokens are batched into one edit per interval
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1000);
+const STOP_EMOJI: &str = "🛑";
+
 
 
 
@@ -52,13 +86,14 @@ pub struct Reciever{
 #[derive(Debug)]
 struct Speaker {
   id: String,
+  user_id: u64,
   message_send: Option<TokioMutex<WhisperSink>>,
 }
 
 struct InnerReceiver{
   last_tick_was_empty: AtomicBool,
   known_ssrcs: dashmap::DashMap<u32, Speaker>,
-  storage_tx: UnboundedSender<StorageMessage>,
+  storage_tx: UnboundedSender<StorageRequest>,
 }
 
 pub fn get_http() -> Http{
@@ -66,41 +101,230 @@ pub fn get_http() -> Http{
   Http::new(&token)
 }
 
-pub async fn start_typing(channel_id: u64) -> Option<Typing>{
-  let http = get_http();
-  if let Ok(channel_op) = http.get_channel(channel_id.into()).await{
-    if let Some(channel) = channel_op.guild(){
-      return Some(channel.start_typing(&Arc::new(http)));
+pub struct StreamingReply{
+  http: Arc<Http>,
+  channel: GuildChannel,
+  typing: Typing,
+  //messages this reply has written into so far, the last one is being written now
+  written: Vec<MessageId>,
+  current: Option<MessageId>,
+  //messages left over from the alternative being regenerated, overwritten before new ones are sent
+  reuse: VecDeque<MessageId>,
+  stop_buttons: Vec<MessageId>,
+  //told about every message that gets a stop button
+  generations: Option<Arc<ActiveGenerations>>,
+  text: String,
+  dirty: bool,
+  showing_status: bool,
+  last_edit: Instant,
+}
+
+impl StreamingReply{
+  pub async fn start(channel_id: u64) -> Option<Self>{
+    Self::resume(channel_id, &[]).await
+  }
+
+  //writes over an existing reply instead of sending a new one
+  pub async fn resume(channel_id: u64, messages: &[u64]) -> Option<Self>{
+    let http = Arc::new(get_http());
+    let channel = http.get_channel(channel_id.into()).await.ok()?.guild()?;
+    let typing = channel.start_typing(&http);
+    Some(Self{
+      http,
+      channel,
+      typing,
+      written: Vec::new(),
+      current: None,
+      reuse: messages.iter().map(|id| MessageId::new(*id)).collect(),
+      stop_buttons: Vec::new(),
+      generations: None,
+      text: String::new(),
+      dirty: false,
+      showing_status: false,
+      last_edit: Instant::now(),
+    })
+  }
+
+  pub fn watch_stops(&mut self, generations: Arc<ActiveGenerations>){
+    self.generations = Some(generations);
+  }
+
+  //shows a notice in place of the reply, the first real token replaces it
+  pub async fn status(&mut self, text: &str){
+    self.text = text.to_string();
+    self.dirty = true;
+    self.showing_status = true;
+    self.flush().await;
+  }
+
+  async fn push_token(&mut self, token: &str){
+    if self.showing_status{
+      self.text = String::new();
+      self.showing_status = false;
+    }
+    //discord caps messages at 2000 codepoints, so spill over into a fresh message
+    if self.text.chars().count() + token.chars().count() > MESSAGE_CODE_LIMIT{
+      self.flush().await;
+      self.current = None;
+      self.text = String::new();
+    }
+    self.text.push_str(token);
+    self.dirty = true;
+    if self.last_edit.elapsed() >= STREAM_EDIT_INTERVAL{
+      self.flush().await;
+    }
+  }
+
+  async fn flush(&mut self){
+    if !self.dirty || self.text.trim().is_empty(){
+      return;
+    }
+    match self.current{
+      Some(message) => {
+        if let Err(err) = self.channel.id.edit_message(&self.http, message, EditMessage::new().content(self.text.clone())).await{
+          println!("Unable to edit streamed message on discord: {}", err);
+        }
+      },
+      None => {
+        let message = match self.reuse.pop_front(){
+          Some(message) => {
+            match self.channel.id.edit_message(&self.http, message, EditMessage::new().content(self.text.clone()).components(Vec::new())).await{
+              Ok(r) => Some(r.id),
+              Err(err) => {
+                println!("Unable to edit streamed message on discord: {}", err);
+                None
+              }
+            }
+          },
+          None => {
+            match self.channel.say(&self.http, self.text.clone()).await{
+              Ok(r) => Some(r.id),
+              Err(err) => {
+                println!("Unable to send message to discord: {}", err);
+                None
+              }
+            }
+          },
+        };
+        if let Some(message) = message{
+          //the reaction doubles as a stop button while the reply is still being written
+          if let Err(err) = self.channel.id.create_reaction(&self.http, message, ReactionType::Unicode(STOP_EMOJI.to_string())).await{
+            println!("Unable to add stop reaction to streamed message: {}", err);
+          }
+          self.stop_buttons.push(message);
+          if let Some(generations) = &self.generations{
+            generations.add_message(self.channel.id.get(), message.get());
+          }
+          self.written.push(message);
+          self.current = Some(message);
+        }
+      },
+    }
+    self.dirty = false;
+    self.last_edit = Instant::now();
+  }
+
+  pub async fn mark_stopped(&mut self){
+    if self.current.is_none() && (self.showing_status || self.text.trim().is_empty()){
+      self.status("*(stopped)*").await;
+      return;
+    }
+    self.push_token(" *(stopped)*").await;
+  }
+
+  //returns every message the reply ended up in
+  pub async fn finish(mut self) -> Vec<u64>{
+    self.flush().await;
+    self.typing.stop();
+    for message in &self.stop_buttons{
+      if let Err(err) = self.channel.id.delete_reaction(&self.http, *message, None, ReactionType::Unicode(STOP_EMOJI.to_string())).await{
+        println!("Unable to remove stop reaction from streamed message: {}", err);
+      }
+    }
+    //a shorter rewrite leaves the tail of the old reply behind
+    if !self.written.is_empty(){
+      for message in &self.reuse{
+        if let Err(err) = self.channel.id.delete_message(&self.http, *message).await{
+          println!("Unable to delete leftover message on discord: {}", err);
+        }
+      }
+      self.written.iter().map(|id| id.get()).collect()
+    }else{
+      self.reuse.iter().map(|id| id.get()).collect()
     }
   }
-  None
 }
 
-fn split_string(codepoints_over: usize, original_string: String) -> (String, String){
-  (original_string.char_indices().rev().nth(codepoints_over).map(|(i, _)| &original_string[..i]).unwrap().to_string(),
-  original_string.char_indices().rev().nth(codepoints_over).map(|(i, _)| &original_string[i..]).unwrap().to_string())
+//custom ids look like swipe:<action>:<first message of the reply>
+pub fn swipe_buttons(anchor: u64, index: usize, count: usize) -> Vec<CreateActionRow>{
+  vec![CreateActionRow::Buttons(vec![
+    CreateButton::new(format!("swipe:prev:{}", anchor))
+      .label("◀")
+      .style(ButtonStyle::Secondary)
+      .disabled(index == 0),
+    CreateButton::new(format!("swipe:count:{}", anchor))
+      .label(format!("{}/{}", index+1, count))
+      .style(ButtonStyle::Secondary)
+      .disabled(true),
+    CreateButton::new(format!("swipe:next:{}", anchor))
+      .label("▶")
+      .style(ButtonStyle::Secondary)
+      .disabled(index+1 >= count),
+    CreateButton::new(format!("swipe:regen:{}", anchor))
+      .label("🔄")
+      .style(ButtonStyle::Primary),
+  ])]
 }
 
-pub async fn send_discord_message(message: String, channel_id: u64, typing_state: Typing){
+fn split_reply_text(text: &str) -> Vec<String>{
+  let chars: Vec<char> = text.chars().collect();
+  chars.chunks(MESSAGE_CODE_LIMIT).map(|chunk| chunk.iter().collect()).collect()
+}
+
+//puts the buttons on the last message of a reply and shows the given text across its messages,
+//returns the messages the reply is spread over afterwards
+pub async fn show_swipe(view: &SwipeView, anchor: u64, rewrite: bool) -> Vec<u64>{
   let http = get_http();
-  if let Ok(channel_op) = http.get_channel(channel_id.into()).await{
-    if let Some(channel) = channel_op.guild(){
-      if let Err(err) = channel.say(&http, message.clone()).await{
-        if let serenity::Error::Model(serenity::ModelError::MessageTooLong(num_codepoints)) = err{
-          let (first, second) = split_string(num_codepoints, message);
-          check_msg(channel.say(&http, first).await);
-          check_msg(channel.say(&http, second).await);
-        }else{
-          println!("Unable to send message to discord: {}", err);
-        }
+  let channel = PoiseChannelId::new(view.channel);
+  let buttons = swipe_buttons(anchor, view.index, view.count);
+  if !rewrite{
+    if let Some(last) = view.messages.last(){
+      if let Err(err) = channel.edit_message(&http, *last, EditMessage::new().components(buttons)).await{
+        println!("Unable to add swipe buttons to reply: {}", err);
       }
     }
+    return view.messages.clone();
+  }
+  let chunks = split_reply_text(&view.text);
+  let mut messages = Vec::new();
+  for (i, chunk) in chunks.iter().enumerate(){
+    let components = if i+1 == chunks.len(){ buttons.clone() }else{ Vec::new() };
+    let result = match view.messages.get(i){
+      Some(existing) => channel.edit_message(&http, *existing, EditMessage::new().content(chunk).components(components)).await,
+      None => channel.send_message(&http, CreateMessage::new().content(chunk).components(components)).await,
+    };
+    match result{
+      Ok(r) => messages.push(r.id.get()),
+      Err(err) => println!("Unable to show swipe on discord: {}", err),
+    }
+  }
+  for leftover in view.messages.iter().skip(chunks.len()){
+    if let Err(err) = channel.delete_message(&http, *leftover).await{
+      println!("Unable to delete leftover message on discord: {}", err);
+    }
+  }
+  messages
+}
+
+#[async_trait]
+impl TokenSink for StreamingReply{
+  async fn push(&mut self, token: &str){
+    self.push_token(token).await;
   }
-  typing_state.stop();
 }
 
 impl Reciever{
-  pub fn new(storage_tx: UnboundedSender<StorageMessage>, channel: PoiseChannelId, guild: PartialGuild) -> Self{
+  pub fn new(storage_tx: UnboundedSender<StorageRequest>, channel: PoiseChannelId, guild: PartialGuild) -> Self{
     Self { inner: Arc::new(InnerReceiver{
         last_tick_was_empty: AtomicBool::new(true),
         known_ssrcs: DashMap::new(),
@@ -133,6 +357,7 @@ impl VoiceEventHandler for Reciever{
             }
           };
           if let Some(mut existing_speaker) = self.inner.known_ssrcs.get_mut(&ssrc){
+            existing_speaker.user_id = user.0;
             if member.user.bot{
               existing_speaker.id = "Bot".to_string();
             }else{
@@ -142,11 +367,13 @@ impl VoiceEventHandler for Reciever{
             if member.user.bot{
               self.inner.known_ssrcs.insert(*ssrc, Speaker { 
                 id: "Bot".to_string(), 
+                user_id: user.0,
                 message_send: None,
               });
             }else{
               self.inner.known_ssrcs.insert(*ssrc, Speaker { 
                 id: member.user.global_name.unwrap(), 
+                user_id: user.0,
                 message_send: None,
               });
             }
@@ -191,7 +418,7 @@ impl VoiceEventHandler for Reciever{
               None => {
                 println!("Created new speaker thread");
                 let new_mutex = TokioMutex::new(
-                  spawn_whisper_thread(self.inner.storage_tx.clone(), speaker.id.clone(), self.default_channel.get()).await
+                  spawn_whisper_thread(self.inner.storage_tx.clone(), speaker.id.clone(), speaker.user_id, self.default_channel.get(), self.guild.id.get()).await
                 );
                 if let Err(err) = new_mutex.lock().await.feed(
                   TungsteniteMessage::Binary(resample_discord_to_bytes(decoded_voice.to_vec()))
@@ -232,7 +459,11 @@ impl VoiceEventHandler for Reciever{
 
 pub struct Data {
   songbird: Arc<Songbird>,
-  storage_tx: UnboundedSender<StorageMessage>,
+  storage_tx: UnboundedSender<StorageRequest>,
+  personas: Arc<PersonaLibrary>,
+  generations: Arc<ActiveGenerations>,
+  kobold_tx: UnboundedSender<KoboldRequest>,
+  swipes: Arc<Swipes>,
 }
 
 #[poise::command(slash_command, prefix_command)]
@@ -285,31 +516,732 @@ async fn mere(ctx: Context<'_>) -> CommandResult{
   Ok(())
 }
 
+/// Stop the reply that is being written in this channel
+#[poise::command(slash_command, prefix_command)]
+async fn stop(ctx: Context<'_>) -> CommandResult{
+  if ctx.data().generations.stop(ctx.channel_id().get()){
+    ctx.send(poise::CreateReply::default().content("Stopping the reply").ephemeral(true)).await?;
+  }else{
+    ctx.send(poise::CreateReply::default().content("Nothing is being written here").ephemeral(true)).await?;
+  }
+  Ok(())
+}
+
+async fn autocomplete_persona(ctx: Context<'_>, partial: &str) -> Vec<String>{
+  ctx.data().personas.names()
+    .into_iter()
+    .filter(|name| name.to_lowercase().starts_with(&partial.to_lowercase()))
+    .collect()
+}
+
+/// Pick who the bot speaks as
+#[poise::command(slash_command, guild_only, subcommands("persona_list", "persona_set"))]
+async fn persona(_ctx: Context<'_>) -> CommandResult{
+  Ok(())
+}
+
+/// List the personas the bot can speak as
+#[poise::command(slash_command, guild_only, rename = "list")]
+async fn persona_list(ctx: Context<'_>) -> CommandResult{
+  let names = ctx.data().personas.names();
+  if names.is_empty(){
+    ctx.say("No personas are loaded, the default one is always in use").await?;
+  }else{
+    ctx.say(format!("Available personas: {}", names.join(", "))).await?;
+  }
+  Ok(())
+}
+
+/// Change the persona the bot speaks as in this server
+#[poise::command(slash_command, guild_only, rename = "set", required_permissions = "MANAGE_GUILD")]
+async fn persona_set(
+  ctx: Context<'_>,
+  #[description = "Name of the persona"]
+  #[autocomplete = "autocomplete_persona"]
+  name: String,
+) -> CommandResult{
+  let persona = match ctx.data().personas.get(&name){
+    Some(r) => r.clone(),
+    None => {
+      ctx.say(format!("There is no persona called {}", name)).await?;
+      return Ok(());
+    }
+  };
+  let guild_id = ctx.guild_id().unwrap().get();
+  ctx.data().storage_tx.send(StorageRequest::SetPersona{
+    guild: guild_id,
+    persona: persona.name.clone(),
+  })?;
+  ctx.say(format!("Now speaking as {}", persona.name)).await?;
+  if !persona.greeting.trim().is_empty(){
+    let greeting = ctx.channel_id().say(ctx.http(), &persona.greeting).await?;
+    ctx.data().storage_tx.send(StorageRequest::Store(StorageMessage{
+      channel: ctx.channel_id().get(),
+      guild: Some(guild_id),
+      message: persona.greeting,
+      author: persona.name,
+      discord_id: Some(greeting.id.get()),
+      parts: Vec::new(),
+      user_id: None,
+      source: MessageSource::Text,
+      created_at: greeting.timestamp.unix_timestamp(),
+    }))?;
+  }
+  Ok(())
+}
+
+#[derive(poise::ChoiceParameter)]
+enum PresetScope{
+  Server,
+  Channel,
+}
+
+fn describe_preset(preset: &SamplerPreset) -> String{
+  match serde_json::to_value(preset){
+    Ok(serde_json::Value::Object(map)) if !map.is_empty() => {
+      map.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<String>>().join(", ")
+    },
+    _ => "defaults".to_string(),
+  }
+}
+
+/// Manage the sampler settings replies are generated with
+#[poise::command(slash_command, guild_only, subcommands("preset_save", "preset_delete", "preset_list", "preset_use", "preset_clear"))]
+async fn preset(_ctx: Context<'_>) -> CommandResult{
+  Ok(())
+}
+
+/// Create or overwrite a sampler preset, anything left out keeps its default
+#[allow(clippy::too_many_arguments)]
+#[poise::command(slash_command, guild_only, rename = "save", required_permissions = "MANAGE_GUILD")]
+async fn preset_save(
+  ctx: Context<'_>,
+  #[description = "Name of the preset"] name: String,
+  #[description = "Randomness of the reply"] #[min = 0.0] #[max = 5.0] temperature: Option<f32>,
+  #[description = "Nucleus sampling cutoff"] #[min = 0.0] #[max = 1.0] top_p: Option<f32>,
+  #[description = "Only sample from the k most likely tokens, 0 disables"] #[min = 0] top_k: Option<i32>,
+  #[description = "Minimum probability relative to the top token"] #[min = 0.0] #[max = 1.0] min_p: Option<f32>,
+  #[description = "Typical sampling cutoff"] #[min = 0.0] #[max = 1.0] typical_p: Option<f32>,
+  #[description = "Penalty for repeating tokens"] #[min = 0.0] #[max = 3.0] repetition_penalty: Option<f32>,
+  #[description = "How many tokens back the repetition penalty looks"] repetition_penalty_range: Option<u32>,
+  #[description = "Penalty scaled by how often a token appeared"] #[min = -2.0] #[max = 2.0] frequency_penalty: Option<f32>,
+  #[description = "Penalty for tokens that appeared at all"] #[min = -2.0] #[max = 2.0] presence_penalty: Option<f32>,
+  #[description = "Mirostat mode, 0 disables"] #[max = 2] mirostat_mode: Option<u32>,
+  #[description = "Mirostat target entropy"] mirostat_tau: Option<f32>,
+  #[description = "Mirostat learning rate"] mirostat_eta: Option<f32>,
+  #[description = "Fixed seed, -1 for random"] #[min = -1] seed: Option<i32>,
+  #[description = "Maximum tokens in a reply"] #[min = 1] max_length: Option<u32>,
+  #[description = "Context window of the model"] #[min = 512] context_length: Option<u32>,
+) -> CommandResult{
+  let preset = SamplerPreset{
+    temperature,
+    top_p,
+    top_k,
+    min_p,
+    typical_p,
+    repetition_penalty,
+    repetition_penalty_range,
+    frequency_penalty,
+    presence_penalty,
+    mirostat_mode,
+    mirostat_tau,
+    mirostat_eta,
+    seed,
+    max_length,
+    context_length,
+  };
+  let description = describe_preset(&preset);
+  ctx.data().storage_tx.send(StorageRequest::SavePreset{
+    guild: ctx.guild_id().unwrap().get(),
+    name: name.clone(),
+    preset,
+  })?;
+  ctx.say(format!("Saved preset {}: {}", name, description)).await?;
+  Ok(())
+}
+
+/// Delete a sampler preset
+#[poise::command(slash_command, guild_only, rename = "delete", required_permissions = "MANAGE_GUILD")]
+async fn preset_delete(
+  ctx: Context<'_>,
+  #[description = "Name of the preset"] name: String,
+) -> CommandResult{
+  let (reply, deleted) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::DeletePreset{
+    guild: ctx.guild_id().unwrap().get(),
+    name: name.clone(),
+    reply,
+  })?;
+  if deleted.await?{
+    ctx.say(format!("Deleted preset {}", name)).await?;
+  }else{
+    ctx.say(format!("There is no preset called {}", name)).await?;
+  }
+  Ok(())
+}
+
+/// Show the sampler presets of this server and which ones are in use here
+#[poise::command(slash_command, guild_only, rename = "list")]
+async fn preset_list(ctx: Context<'_>) -> CommandResult{
+  let (reply, overview) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::ListPresets{
+    guild: ctx.guild_id().unwrap().get(),
+    channel: ctx.channel_id().get(),
+    reply,
+  })?;
+  let overview = overview.await?;
+  let mut response = if overview.presets.is_empty(){
+    "No presets saved, replies use the default sampler settings".to_string()
+  }else{
+    overview.presets.iter()
+      .map(|(name, preset)| format!("**{}**: {}", name, describe_preset(preset)))
+      .collect::<Vec<String>>()
+      .join("\n")
+  };
+  response.push_str(&format!(
+    "\n\nServer preset: {}\nChannel preset: {}",
+    overview.guild_preset.unwrap_or("none".to_string()),
+    overview.channel_preset.unwrap_or("none".to_string()),
+  ));
+  ctx.say(response).await?;
+  Ok(())
+}
+
+/// Use a sampler preset for the whole server or just this channel
+#[poise::command(slash_command, guild_only, rename = "use", required_permissions = "MANAGE_GUILD")]
+async fn preset_use(
+  ctx: Context<'_>,
+  #[description = "Name of the preset"] name: String,
+  #[description = "Where the preset applies"] scope: PresetScope,
+) -> CommandResult{
+  let guild = ctx.guild_id().unwrap().get();
+  let (reply, overview) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::ListPresets{
+    guild,
+    channel: ctx.channel_id().get(),
+    reply,
+  })?;
+  if !overview.await?.presets.iter().any(|(preset_name, _)| *preset_name == name){
+    ctx.say(format!("There is no preset called {}", name)).await?;
+    return Ok(());
+  }
+  let channel = match scope{
+    PresetScope::Server => None,
+    PresetScope::Channel => Some(ctx.channel_id().get()),
+  };
+  ctx.data().storage_tx.send(StorageRequest::AssignPreset{
+    guild,
+    channel,
+    preset: Some(name.clone()),
+  })?;
+  ctx.say(format!("Now using preset {}", name)).await?;
+  Ok(())
+}
+
+/// Stop using a sampler preset for the whole server or just this channel
+#[poise::command(slash_command, guild_only, rename = "clear", required_permissions = "MANAGE_GUILD")]
+async fn preset_clear(
+  ctx: Context<'_>,
+  #[description = "Which assignment to clear"] scope: PresetScope,
+) -> CommandResult{
+  let channel = match scope{
+    PresetScope::Server => None,
+    PresetScope::Channel => Some(ctx.channel_id().get()),
+  };
+  ctx.data().storage_tx.send(StorageRequest::AssignPreset{
+    guild: ctx.guild_id().unwrap().get(),
+    channel,
+    preset: None,
+  })?;
+  ctx.say("Preset cleared").await?;
+  Ok(())
+}
+
+fn describe_retention(policy: &RetentionPolicy) -> String{
+  if policy.is_empty(){
+    return "Everything is kept".to_string();
+  }
+  let mut rules = Vec::new();
+  if let Some(days) = policy.max_age_days{
+    rules.push(format!("messages older than {} days are removed", days));
+  }
+  if let Some(rows) = policy.max_rows_per_channel{
+    rules.push(format!("only the newest {} messages of each channel are kept", rows));
+  }
+  if policy.keep_summaries_only{
+    rules.push("messages are removed once a summary covers them".to_string());
+  }
+  rules.join("\n")
+}
+
+/// Control how long the bot remembers what was said in this server
+#[poise::command(slash_command, guild_only, subcommands("retention_show", "retention_set", "retention_clear", "retention_preview"))]
+async fn retention(_ctx: Context<'_>) -> CommandResult{
+  Ok(())
+}
+
+/// Show how long this server's history is kept
+#[poise::command(slash_command, guild_only, rename = "show")]
+async fn retention_show(ctx: Context<'_>) -> CommandResult{
+  let (reply, policy) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::GetRetention{
+    guild: ctx.guild_id().unwrap().get(),
+    reply,
+  })?;
+  ctx.say(describe_retention(&policy.await?)).await?;
+  Ok(())
+}
+
+/// Change how long this server's history is kept, anything left out stays as it was
+#[poise::command(slash_command, guild_only, rename = "set", required_permissions = "MANAGE_GUILD")]
+async fn retention_set(
+  ctx: Context<'_>,
+  #[description = "Remove messages older than this many days, 0 for no limit"] max_age_days: Option<u32>,
+  #[description = "Keep at most this many messages per channel, 0 for no limit"] max_rows_per_channel: Option<u32>,
+  #[description = "Remove messages once a summary covers them"] keep_summaries_only: Option<bool>,
+) -> CommandResult{
+  let guild = ctx.guild_id().unwrap().get();
+  let (reply, policy) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::GetRetention{guild, reply})?;
+  let mut policy = policy.await?;
+  if let Some(days) = max_age_days{
+    policy.max_age_days = Some(days).filter(|days| *days > 0);
+  }
+  if let Some(rows) = max_rows_per_channel{
+    policy.max_rows_per_channel = Some(rows).filter(|rows| *rows > 0);
+  }
+  if let Some(keep) = keep_summaries_only{
+    policy.keep_summaries_only = keep;
+  }
+  ctx.data().storage_tx.send(StorageRequest::SetRetention{
+    guild,
+    policy: policy.clone(),
+  })?;
+  ctx.say(format!("{}\n\nUse /retention preview to see what the next prune removes", describe_retention(&policy))).await?;
+  Ok(())
+}
+
+/// Keep this server's history forever
+#[poise::command(slash_command, guild_only, rename = "clear", required_permissions = "MANAGE_GUILD")]
+async fn retention_clear(ctx: Context<'_>) -> CommandResult{
+  ctx.data().storage_tx.send(StorageRequest::SetRetention{
+    guild: ctx.guild_id().unwrap().get(),
+    policy: RetentionPolicy::default(),
+  })?;
+  ctx.say("Everything is kept").await?;
+  Ok(())
+}
+
+/// See what the next prune would remove without removing anything
+#[poise::command(slash_command, guild_only, rename = "preview", required_permissions = "MANAGE_GUILD")]
+async fn retention_preview(ctx: Context<'_>) -> CommandResult{
+  let (reply, report) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::PreviewPrune{
+    guild: ctx.guild_id().unwrap().get(),
+    reply,
+  })?;
+  let report = report.await?;
+  ctx.send(poise::CreateReply::default().ephemeral(true).content(format!(
+    "The next prune removes {} messages\nToo old: {}\nOver the per channel limit: {}\nCovered by a summary: {}\nAlready deleted: {}",
+    report.total,
+    report.by_age,
+    report.by_rows,
+    report.by_summaries,
+    report.tombstones,
+  ))).await?;
+  Ok(())
+}
+
+#[derive(poise::ChoiceParameter)]
+enum ExportChoice{
+  #[name = "The whole server"]
+  Server,
+  #[name = "This channel"]
+  Channel,
+}
+
+#[derive(poise::ChoiceParameter)]
+enum TransferFormat{
+  #[name = "JSONL, can be imported again"]
+  Jsonl,
+  #[name = "Markdown, for reading"]
+  Markdown,
+}
+
+//discord refuses uploads above this for servers without boosts
+const UPLOAD_LIMIT: usize = 25*1024*1024;
+
+/// Download this server's or this channel's history, summaries and settings
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+async fn export(
+  ctx: Context<'_>,
+  #[description = "Export the whole server or just this channel"] scope: ExportChoice,
+  #[description = "JSONL to import elsewhere or Markdown to read, defaults to JSONL"] format: Option<TransferFormat>,
+) -> CommandResult{
+  ctx.defer_ephemeral().await?;
+  let (export_scope, name) = match scope{
+    ExportChoice::Server => (ExportScope::Guild(ctx.guild_id().unwrap().get()), format!("server-{}", ctx.guild_id().unwrap())),
+    ExportChoice::Channel => (ExportScope::Channel(ctx.channel_id().get()), format!("channel-{}", ctx.channel_id())),
+  };
+  let (format, extension) = match format{
+    Some(TransferFormat::Markdown) => (ExportFormat::Markdown, "md"),
+    _ => (ExportFormat::Jsonl, "jsonl"),
+  };
+  let (reply, exported) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::Export{
+    guild: ctx.guild_id().unwrap().get(),
+    scope: export_scope,
+    format,
+    reply,
+  })?;
+  let exported = match exported.await?{
+    Ok(r) => r,
+    Err(err) => {
+      ctx.say(format!("Export failed: {}", err)).await?;
+      return Ok(());
+    }
+  };
+  if exported.len() > UPLOAD_LIMIT{
+    ctx.say("The export is too big to upload to discord, run `lily export` on the bot's host instead").await?;
+    return Ok(());
+  }
+  ctx.send(poise::CreateReply::default()
+    .content("Here's the export")
+    .attachment(serenity::CreateAttachment::bytes(exported.into_bytes(), format!("memory-{}.{}", name, extension)))
+  ).await?;
+  Ok(())
+}
+
+/// Load a JSONL export into this server, anything from other servers in it is skipped
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+async fn import(
+  ctx: Context<'_>,
+  #[description = "A JSONL file made by /export or lily export"] file: serenity::Attachment,
+) -> CommandResult{
+  ctx.defer_ephemeral().await?;
+  let data = match String::from_utf8(file.download().await?){
+    Ok(r) => r,
+    Err(_) => {
+      ctx.say("That file isn't text, only JSONL exports can be imported").await?;
+      return Ok(());
+    }
+  };
+  //threads count as channels of their own, their messages are stored under the thread's id
+  let target = ctx.guild().map(|guild| ImportTarget{
+    guild: guild.id.get(),
+    channels: guild.channels.keys().chain(guild.threads.iter().map(|thread| &thread.id)).map(|channel| channel.get()).collect(),
+  });
+  let target = match target{
+    Some(r) => r,
+    None => {
+      ctx.say("This server isn't loaded yet, try again in a moment").await?;
+      return Ok(());
+    }
+  };
+  let (reply, report) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::Import{
+    data,
+    target: Some(target),
+    reply,
+  })?;
+  match report.await?{
+    Ok(report) => ctx.say(format!(
+      "Imported {} messages, {} summaries and {} settings, skipped {} records that were already here or belong to another server or channel",
+      report.messages, report.summaries, report.settings, report.skipped,
+    )).await?,
+    Err(err) => ctx.say(err).await?,
+  };
+  Ok(())
+}
+
+/// See or clear what the bot has picked up about you
+#[poise::command(slash_command, guild_only, subcommands("memory_view", "memory_clear"))]
+async fn memory(_ctx: Context<'_>) -> CommandResult{
+  Ok(())
+}
+
+/// See what the bot remembers about you in this server
+#[poise::command(slash_command, guild_only, rename = "view")]
+async fn memory_view(ctx: Context<'_>) -> CommandResult{
+  let (reply, facts) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::ListFacts{
+    guild: ctx.guild_id().unwrap().get(),
+    user_id: ctx.author().id.get(),
+    reply,
+  })?;
+  let facts = facts.await?;
+  let response = if facts.is_empty(){
+    "The bot hasn't noted anything about you here".to_string()
+  }else{
+    facts.iter().map(|fact| format!("- {}", fact)).collect::<Vec<String>>().join("\n")
+  };
+  ctx.send(poise::CreateReply::default().ephemeral(true).content(response)).await?;
+  Ok(())
+}
+
+/// Make the bot forget what it noted about you in this server, your messages stay
+#[poise::command(slash_command, guild_only, rename = "clear")]
+async fn memory_clear(ctx: Context<'_>) -> CommandResult{
+  ctx.data().storage_tx.send(StorageRequest::SetFacts{
+    guild: ctx.guild_id().unwrap().get(),
+    user_id: ctx.author().id.get(),
+    facts: Vec::new(),
+    pass: None,
+  })?;
+  ctx.send(poise::CreateReply::default().ephemeral(true).content("Forgot everything noted about you here")).await?;
+  Ok(())
+}
+
+#[derive(poise::ChoiceParameter)]
+enum ForgetMode{
+  #[name = "Delete the messages"]
+  Delete,
+  #[name = "Keep the messages but remove the name"]
+  Anonymize,
+}
+
+async fn forget(ctx: Context<'_>, user_id: u64, guild: Option<u64>, mode: Option<ForgetMode>) -> Result<usize, Error>{
+  let (reply, forgotten) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::ForgetUser{
+    user_id,
+    guild,
+    anonymize: matches!(mode, Some(ForgetMode::Anonymize)),
+    reply,
+  })?;
+  let forgotten = forgotten.await?;
+  ctx.data().swipes.forget(guild);
+  Ok(forgotten)
+}
+
+/// Make the bot forget everything you said or spoke, in every server
+#[poise::command(slash_command, prefix_command)]
+async fn forgetme(
+  ctx: Context<'_>,
+  #[description = "Delete your messages or only take your name off them, defaults to delete"] mode: Option<ForgetMode>,
+) -> CommandResult{
+  let forgotten = forget(ctx, ctx.author().id.get(), None, mode).await?;
+  ctx.send(poise::CreateReply::default().ephemeral(true).content(format!(
+    "Forgot {} of your messages. Anything stored before the bot recorded who sent what can't be traced back to you",
+    forgotten,
+  ))).await?;
+  Ok(())
+}
+
+/// Make the bot forget everything someone said or spoke in this server, for removal requests
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+async fn forgetuser(
+  ctx: Context<'_>,
+  #[description = "Whose messages to forget"] user: serenity::User,
+  #[description = "Delete their messages or only take their name off them, defaults to delete"] mode: Option<ForgetMode>,
+) -> CommandResult{
+  let forgotten = forget(ctx, user.id.get(), Some(ctx.guild_id().unwrap().get()), mode).await?;
+  ctx.send(poise::CreateReply::default().ephemeral(true).content(format!(
+    "Forgot {} messages from {} in this server",
+    forgotten,
+    user.name,
+  ))).await?;
+  Ok(())
+}
+
+#[derive(poise::ChoiceParameter)]
+enum SearchSource{
+  Text,
+  Voice,
+}
+
+const SEARCH_PAGE_SIZE: usize = 5;
+const SEARCH_SNIPPET_LENGTH: usize = 300;
+
+//YYYY-MM-DD as the unix time the day starts
+fn parse_search_date(date: &str) -> Result<i64, String>{
+  NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+    .map(|day| day.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())
+    .map_err(|_| format!("{} is not a date, use YYYY-MM-DD", date))
+}
+
+fn describe_search_hit(hit: &SearchHit, guild: u64) -> String{
+  let mut snippet: String = hit.message.chars().take(SEARCH_SNIPPET_LENGTH).collect();
+  if snippet.len() < hit.message.len(){
+    snippet.push('…');
+  }
+  let when = hit.created_at.map(|created| format!(" <t:{}:d>", created)).unwrap_or_default();
+  //transcribed speech never had a discord message to jump to
+  let link = match hit.discord_id{
+    Some(id) => format!(" [jump](https://discord.com/channels/{}/{}/{})", guild, hit.channel, id),
+    None if hit.source == MessageSource::Voice.as_str() => " (voice)".to_string(),
+    None => String::new(),
+  };
+  format!("**{}** in <#{}>{}{}\n{}", hit.author, hit.channel, when, link, snippet)
+}
+
+/// Search everything the bot has heard in this server
+#[poise::command(slash_command, guild_only)]
+async fn search(
+  ctx: Context<'_>,
+  #[description = "Words the message has to contain"] query: String,
+  #[description = "Only messages from this name"] author: Option<String>,
+  #[description = "Only messages in this channel"] channel: Option<serenity::GuildChannel>,
+  #[description = "Only messages from this day on, YYYY-MM-DD"] after: Option<String>,
+  #[description = "Only messages up to and including this day, YYYY-MM-DD"] before: Option<String>,
+  #[description = "Only typed or only spoken messages"] source: Option<SearchSource>,
+) -> CommandResult{
+  let guild = ctx.guild_id().unwrap().get();
+  let dates = (
+    after.as_deref().map(parse_search_date).transpose(),
+    before.as_deref().map(parse_search_date).transpose(),
+  );
+  let (after, before) = match dates{
+    (Ok(after), Ok(before)) => (after, before.map(|day| day + 24*60*60)),
+    (Err(err), _) | (_, Err(err)) => {
+      ctx.send(poise::CreateReply::default().content(err).ephemeral(true)).await?;
+      return Ok(());
+    }
+  };
+  let (reply, hits) = oneshot::channel();
+  ctx.data().storage_tx.send(StorageRequest::Search{
+    filter: SearchFilter{
+      query,
+      guild,
+      author,
+      channel: channel.map(|channel| channel.id.get()),
+      after,
+      before,
+      source: source.map(|source| match source{
+        SearchSource::Text => MessageSource::Text,
+        SearchSource::Voice => MessageSource::Voice,
+      }),
+    },
+    reply,
+  })?;
+  let hits = hits.await?;
+  if hits.is_empty(){
+    ctx.say("Nothing found").await?;
+    return Ok(());
+  }
+  let pages: Vec<String> = hits.chunks(SEARCH_PAGE_SIZE)
+    .map(|page| page.iter().map(|hit| describe_search_hit(hit, guild)).collect::<Vec<String>>().join("\n\n"))
+    .collect();
+  let pages: Vec<&str> = pages.iter().map(|page| page.as_str()).collect();
+  poise::builtins::paginate(ctx, &pages).await?;
+  Ok(())
+}
+
 async fn poise_event_handler(
-  _ctx: &serenity::Context,
+  ctx: &serenity::Context,
   event: &serenity::FullEvent,
   _framework: poise::FrameworkContext<'_, Data, Error>,
   data: &Data,
 ) -> Result<(), Error>{
-  match event {
-    serenity::FullEvent::Message{new_message} =>{
+  match event{
+    serenity::FullEvent::Message{new_message} => {
       if new_message.author.bot{
         return Ok(());
       }
-      data.storage_tx.send(StorageMessage{
+      data.storage_tx.send(StorageRequest::Store(StorageMessage{
         channel: new_message.channel_id.get(),
+        guild: new_message.guild_id.map(|guild| guild.get()),
         message: new_message.content.clone(),
         author: new_message.author.global_name.as_ref().unwrap().clone(),
+        discord_id: Some(new_message.id.get()),
+        parts: Vec::new(),
+        user_id: Some(new_message.author.id.get()),
+        source: MessageSource::Text,
+        created_at: new_message.timestamp.unix_timestamp(),
+      }))?;
+    },
+    serenity::FullEvent::MessageUpdate{event, ..} => {
+      if event.author.as_ref().is_some_and(|author| author.bot){
+        return Ok(());
+      }
+      //embeds loading in also count as updates, only a changed text matters here
+      if let Some(content) = &event.content{
+        data.storage_tx.send(StorageRequest::EditMessage{
+          guild: event.guild_id.map(|guild| guild.get()),
+          discord_id: event.id.get(),
+          message: content.clone(),
+          edited_at: event.edited_timestamp.map(|edited| edited.unix_timestamp()).unwrap_or_else(unix_now),
+        })?;
+      }
+    },
+    serenity::FullEvent::MessageDelete{deleted_message_id, guild_id, ..} => {
+      data.storage_tx.send(StorageRequest::DeleteMessages{
+        guild: guild_id.map(|guild| guild.get()),
+        discord_ids: vec![deleted_message_id.get()],
+        deleted_at: unix_now(),
+      })?;
+    },
+    serenity::FullEvent::MessageDeleteBulk{multiple_deleted_messages_ids, guild_id, ..} => {
+      data.storage_tx.send(StorageRequest::DeleteMessages{
+        guild: guild_id.map(|guild| guild.get()),
+        discord_ids: multiple_deleted_messages_ids.iter().map(|id| id.get()).collect(),
+        deleted_at: unix_now(),
       })?;
     },
-    _ => {}
+    //someone hit the stop reaction on one of our replies
+    serenity::FullEvent::ReactionAdd{add_reaction} => {
+      let bot_id = ctx.cache.current_user().id;
+      if add_reaction.user_id != Some(bot_id)
+      && add_reaction.message_author_id == Some(bot_id)
+      && add_reaction.emoji.unicode_eq(STOP_EMOJI){
+        data.generations.stop_message(add_reaction.channel_id.get(), add_reaction.message_id.get());
+      }
+    },
+    serenity::FullEvent::InteractionCreate{interaction} => {
+      if let Some(component) = interaction.as_message_component(){
+        handle_swipe_button(ctx, data, component).await?;
+      }
+    },
+    _ => {},
+  }
+  Ok(())
+}
+
+async fn handle_swipe_button(ctx: &serenity::Context, data: &Data, component: &serenity::ComponentInteraction) -> Result<(), Error>{
+  let mut parts = component.data.custom_id.split(':');
+  let (action, anchor) = match (parts.next(), parts.next(), parts.next().and_then(|anchor| anchor.parse::<u64>().ok())){
+    (Some("swipe"), Some(action), Some(anchor)) => (action, anchor),
+    _ => return Ok(()),
+  };
+  let known = match action{
+    "prev" | "next" => data.swipes.step(anchor, action == "next"),
+    "regen" => data.swipes.current(anchor),
+    _ => None,
+  };
+  let view = match known{
+    Some(r) => r,
+    None => {
+      component.create_response(&ctx.http, serenity::CreateInteractionResponse::Message(
+        serenity::CreateInteractionResponseMessage::new()
+          .content("This reply is too old to be changed anymore")
+          .ephemeral(true)
+      )).await?;
+      return Ok(());
+    }
+  };
+  component.create_response(&ctx.http, serenity::CreateInteractionResponse::Acknowledge).await?;
+  if action == "regen"{
+    if let Some(request) = data.swipes.regenerate_request(anchor){
+      data.kobold_tx.send(request)?;
+    }
+    return Ok(());
+  }
+  if view.previous == view.text{
+    return Ok(());
   }
+  let messages = show_swipe(&view, anchor, true).await;
+  data.swipes.set_messages(anchor, messages);
+  data.storage_tx.send(StorageRequest::ReplaceBotMessage{
+    guild: view.guild,
+    discord_id: anchor,
+    message: view.text,
+    parts: view.messages.iter().skip(1).copied().collect(),
+  })?;
   Ok(())
 }
 
 fn get_framework_options() -> poise::FrameworkOptions<Data, Error>{
   poise::FrameworkOptions{
-    commands: vec![age(), mere()],
+    commands: vec![age(), mere(), stop(), persona(), preset(), search(), retention(), forgetme(), forgetuser(), export(), import(), memory()],
     prefix_options: poise::PrefixFrameworkOptions{
       prefix: Some("~".to_string()),
       additional_prefixes: vec![
@@ -329,16 +1261,28 @@ fn get_framework_options() -> poise::FrameworkOptions<Data, Error>{
   }
 }
 
-pub fn get_framework(songbird: Arc<Songbird>) -> poise::Framework<Data, Error>{
+//the databases and the backend are set up by main so a bad configuration stops the bot before it logs in
+pub fn get_framework(songbird: Arc<Songbird>, databases: Databases, generator: Arc<Generator>) -> poise::Framework<Data, Error>{
+  //setup has to be Sync and the connections inside aren't
+  let databases = std::sync::Mutex::new(databases);
   poise::Framework::builder()
     .options(get_framework_options())
     .setup(|ctx, _ready, framework|{
         Box::pin(async move{
             println!("Logged in as {}", _ready.user.name);
             poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+            let personas = Arc::new(PersonaLibrary::load());
+            let generations = Arc::new(ActiveGenerations::default());
+            let swipes = Arc::new(Swipes::default());
+            let databases = databases.into_inner().unwrap();
+            let (storage_tx, kobold_tx) = create_storage_thread(databases, generator, personas.clone(), generations.clone(), swipes.clone());
             Ok(Data {
               songbird,
-              storage_tx: create_storage_thread(),
+              storage_tx,
+              personas,
+              generations,
+              kobold_tx,
+              swipes,
             })
         })
     })
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use serde::{
+  Serialize,
+  Deserialize,
+};
+use tokio::sync::mpsc::{
+  UnboundedSender,
+  unbounded_channel as tokio_channel,
+};
+
+use crate::{
+  backend::endpoint,
+  storage::StorageRequest,
+};
+
+//how many texts go to the server in one call while catching up
+const EMBEDDING_BATCH: usize = 32;
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a>{
+  model: &'a str,
+  input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse{
+  data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData{
+  embedding: Vec<f32>,
+  #[serde(default)]
+  index: usize,
+}
+
+//anything that serves /v1/embeddings, koboldcpp and llama.cpp server both do when started with an embedding model
+pub struct EmbeddingClient{
+  client: reqwest::Client,
+  model: String,
+  api_key: Option<String>,
+}
+
+impl EmbeddingClient{
+  //None when EMBEDDINGS_URL isn't set, which turns retrieval off
+  pub fn from_env() -> Option<Self>{
+    std::env::var("EMBEDDINGS_URL").ok().filter(|url| !url.trim().is_empty())?;
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(30)).build(){
+      Ok(r) => r,
+      Err(err) => {
+        println!("Reqwest client can't be built for embeddings, retrieval is turned off: {}", err);
+        return None;
+      }
+    };
+    Some(Self{
+      client,
+      model: std::env::var("EMBEDDINGS_MODEL").unwrap_or_default(),
+      api_key: std::env::var("EMBEDDINGS_API_KEY").ok().filter(|key| !key.is_empty()),
+    })
+  }
+
+  //vectors from different models can't be compared, so every stored vector is tagged with this
+  pub fn model(&self) -> &str{
+    &self.model
+  }
+
+  pub async fn embed(&self, input: &[String]) -> Result<Vec<Vec<f32>>, String>{
+    let mut req = self.client.post(endpoint("EMBEDDINGS_URL", "v1/embeddings"))
+      .json(&EmbeddingRequest{
+        model: &self.model,
+        input,
+      });
+    if let Some(key) = &self.api_key{
+      req = req.bearer_auth(key);
+    }
+    let res = req.send().await.map_err(|err| err.to_string())?;
+    if !res.status().is_success(){
+      return Err(format!("Embeddings server answered {}", res.status()));
+    }
+    let mut response: EmbeddingResponse = res.json().await.map_err(|err| err.to_string())?;
+    if response.data.len() != input.len(){
+      return Err(format!("Embeddings server returned {} vectors for {} texts", response.data.len(), input.len()));
+    }
+    response.data.sort_by_key(|data| data.index);
+    Ok(response.data.into_iter().map(|data| data.embedding).collect())
+  }
+}
+
+pub fn vector_to_blob(vector: &[f32]) -> Vec<u8>{
+  vector.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+pub fn blob_to_vector(blob: &[u8]) -> Vec<f32>{
+  blob.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32{
+  if a.len() != b.len(){
+    return 0.0;
+  }
+  let mut dot = 0.0;
+  let mut norm_a = 0.0;
+  let mut norm_b = 0.0;
+  for (x, y) in a.iter().zip(b){
+    dot += x * y;
+    norm_a += x * x;
+    norm_b += y * y;
+  }
+  if norm_a == 0.0 || norm_b == 0.0{
+    return 0.0;
+  }
+  dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+//a stored row waiting for its vector
+pub struct EmbeddingJob{
+  pub message: i64,
+  pub guild: u64,
+  pub text: String,
+}
+
+//embeds new rows in the background and hands the vectors back to storage, whatever piled up while
+//the server was busy goes out in batches
+pub fn spawn_embedding_thread(storage_tx: UnboundedSender<StorageRequest>, client: EmbeddingClient) -> UnboundedSender<EmbeddingJob>{
+  let (embedding_tx, mut embedding_rx) = tokio_channel::<EmbeddingJob>();
+  tokio::spawn(async move{
+    while let Some(job) = embedding_rx.recv().await{
+      let mut jobs = vec![job];
+      while jobs.len() < EMBEDDING_BATCH{
+        match embedding_rx.try_recv(){
+          Ok(r) => jobs.push(r),
+          Err(_) => break,
+        }
+      }
+      let texts: Vec<String> = jobs.iter().map(|job| job.text.clone()).collect();
+      let vectors = match client.embed(&texts).await{
+        Ok(r) => r,
+        Err(err) => {
+          println!("Unable to embed {} messages, they won't be recalled: {}", jobs.len(), err);
+          continue;
+        }
+      };
+      for (job, vector) in jobs.into_iter().zip(vectors){
+        if let Err(err) = storage_tx.send(StorageRequest::StoreEmbedding{
+          message: job.message,
+          guild: job.guild,
+          model: client.model().to_string(),
+          vector,
+        }){
+          println!("Unable to send embedding to storage thread: {}", err);
+        }
+      }
+    }
+  });
+  embedding_tx
+}
@@ -0,0 +1,564 @@
+use std::collections::{
+  BTreeSet,
+  HashSet,
+};
+
+use base64::{
+  engine::general_purpose::STANDARD,
+  Engine,
+};
+use chacha20poly1305::{
+  aead::{
+    Aead,
+    KeyInit,
+  },
+  ChaCha20Poly1305,
+  Nonce,
+};
+use sha2::{
+  Digest,
+  Sha256,
+};
+
+use crate::{
+  database::{
+    Database,
+    DatabaseError,
+    DatabaseResult,
+    TextColumn,
+    TEXT_COLUMNS,
+  },
+  embeddings::EmbeddingJob,
+  kobold::StoredMessage,
+  retention::{
+    PruneReport,
+    RetentionPolicy,
+  },
+  storage::{
+    RecalledMessage,
+    SearchFilter,
+    SearchHit,
+    StorageMessage,
+  },
+  transfer::{
+    ExportRecord,
+    ExportScope,
+    ImportedMessage,
+  },
+};
+
+//marks a stored value as encrypted, anything without it is plain text from before encryption was turned on
+const PREFIX: &str = "enc:";
+const NONCE_SIZE: usize = 12;
+//how many rows an encrypted search decrypts at a time
+const SEARCH_BATCH: u32 = 500;
+
+fn derive(label: &[u8], secret: &[u8]) -> [u8; 32]{
+  let mut hasher = Sha256::new();
+  hasher.update(label);
+  hasher.update(secret);
+  hasher.finalize().into()
+}
+
+fn invalid(what: &str) -> DatabaseError{
+  DatabaseError::Encryption(format!("{}, is the right key configured?", what))
+}
+
+#[derive(Clone)]
+pub struct MessageCipher{
+  cipher: ChaCha20Poly1305,
+  nonce_key: [u8; 32],
+}
+
+impl MessageCipher{
+  pub fn new(secret: &[u8]) -> Self{
+    Self{
+      cipher: ChaCha20Poly1305::new(&derive(b"lily message key", secret).into()),
+      nonce_key: derive(b"lily nonce key", secret),
+    }
+  }
+
+  //ENCRYPTION_KEY_FILE points at a file holding the secret and ENCRYPTION_KEY is the secret itself,
+  //the file wins when both are set. None when neither is, which leaves everything in plain text.
+  //read once at startup and handed to whatever opens a database
+  pub fn from_env() -> Result<Option<Self>, String>{
+    if let Some(path) = std::env::var("ENCRYPTION_KEY_FILE").ok().filter(|path| !path.trim().is_empty()){
+      return Self::from_file(path.trim()).map(Some);
+    }
+    Ok(std::env::var("ENCRYPTION_KEY").ok()
+      .filter(|secret| !secret.trim().is_empty())
+      .map(|secret| Self::new(secret.trim().as_bytes())))
+  }
+
+  pub fn from_file(path: &str) -> Result<Self, String>{
+    let secret = std::fs::read(path).map_err(|err| format!("Unable to read the key file {}: {}", path, err))?;
+    let secret = secret.trim_ascii();
+    if secret.is_empty(){
+      return Err(format!("The key file {} is empty", path));
+    }
+    Ok(Self::new(secret))
+  }
+
+  //the nonce is derived from the text, so the same text always encrypts the same way and imports
+  //can still match rows up. that shows which stored texts are identical and nothing more
+  pub fn encrypt(&self, text: &str) -> String{
+    if text.is_empty(){
+      return String::new();
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(self.nonce_key);
+    hasher.update(text.as_bytes());
+    let digest = hasher.finalize();
+    let nonce = Nonce::from_slice(&digest[..NONCE_SIZE]);
+    //only fails for texts far beyond anything discord allows
+    let mut blob = nonce.to_vec();
+    blob.extend(self.cipher.encrypt(nonce, text.as_bytes()).expect("Text too long to encrypt"));
+    format!("{}{}", PREFIX, STANDARD.encode(blob))
+  }
+
+  pub fn decrypt(&self, text: String) -> DatabaseResult<String>{
+    let encoded = match text.strip_prefix(PREFIX){
+      Some(r) => r,
+      None => return Ok(text),
+    };
+    let blob = STANDARD.decode(encoded).map_err(|_| invalid("A stored text isn't valid ciphertext"))?;
+    if blob.len() < NONCE_SIZE{
+      return Err(invalid("A stored text isn't valid ciphertext"));
+    }
+    let (nonce, sealed) = blob.split_at(NONCE_SIZE);
+    let plain = self.cipher.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| invalid("A stored text can't be decrypted"))?;
+    String::from_utf8(plain).map_err(|_| invalid("A stored text decrypted to garbage"))
+  }
+}
+
+//the words of a text the way the full text index splits them
+fn words(text: &str) -> HashSet<String>{
+  text.split(|c: char| !c.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(|word| word.to_lowercase())
+    .collect()
+}
+
+//encrypts message bodies, summaries and member facts on the way in and decrypts them on the way out,
+//everything else passes straight through to the backend
+pub struct EncryptedDatabase{
+  inner: Box<dyn Database>,
+  cipher: MessageCipher,
+}
+
+impl EncryptedDatabase{
+  pub fn new(inner: Box<dyn Database>, cipher: MessageCipher) -> Self{
+    Self{inner, cipher}
+  }
+
+  //a text that won't decrypt is left out and logged instead of failing everything that came with it
+  fn readable(&self, text: String) -> Option<String>{
+    match self.cipher.decrypt(text){
+      Ok(r) => Some(r),
+      Err(err) => {
+        println!("Skipping a stored text: {}", err);
+        None
+      }
+    }
+  }
+
+  fn decrypt_messages(&self, messages: Vec<StoredMessage>) -> Vec<StoredMessage>{
+    messages.into_iter().filter_map(|message| Some(StoredMessage{
+      message: self.readable(message.message)?,
+      author: message.author,
+    })).collect()
+  }
+}
+
+impl Database for EncryptedDatabase{
+  fn insert_messages(&mut self, messages: &[StorageMessage]) -> DatabaseResult<Vec<i64>>{
+    let encrypted: Vec<StorageMessage> = messages.iter().map(|message| StorageMessage{
+      message: self.cipher.encrypt(&message.message),
+      ..message.clone()
+    }).collect();
+    self.inner.insert_messages(&encrypted)
+  }
+
+  fn context(&mut self, channel: u64, after: i64, until: i64, limit: u32) -> DatabaseResult<Vec<StoredMessage>>{
+    let messages = self.inner.context(channel, after, until, limit)?;
+    Ok(self.decrypt_messages(messages))
+  }
+
+  fn unsummarized(&mut self, channel: u64, after: i64, limit: u32) -> DatabaseResult<Vec<(i64, StoredMessage)>>{
+    Ok(self.inner.unsummarized(channel, after, limit)?.into_iter().filter_map(|(id, message)| Some((id, StoredMessage{
+      message: self.readable(message.message)?,
+      author: message.author,
+    }))).collect())
+  }
+
+  fn member_messages(&mut self, guild: u64, user_id: u64, limit: usize) -> DatabaseResult<Vec<String>>{
+    Ok(self.inner.member_messages(guild, user_id, limit)?.into_iter().filter_map(|message| self.readable(message)).collect())
+  }
+
+  fn replace_bot_message(&mut self, discord_id: u64, message: &str, parts: &[u64]) -> DatabaseResult<()>{
+    self.inner.replace_bot_message(discord_id, &self.cipher.encrypt(message), parts)
+  }
+
+  fn edit_message(&mut self, discord_id: u64, message: &str, edited_at: i64) -> DatabaseResult<()>{
+    self.inner.edit_message(discord_id, &self.cipher.encrypt(message), edited_at)
+  }
+
+  fn delete_messages(&mut self, discord_ids: &[u64], deleted_at: i64) -> DatabaseResult<()>{
+    self.inner.delete_messages(discord_ids, deleted_at)
+  }
+
+  fn embedding_job(&mut self, discord_id: u64) -> DatabaseResult<Option<EmbeddingJob>>{
+    let job = match self.inner.embedding_job(discord_id)?{
+      Some(r) => r,
+      None => return Ok(None),
+    };
+    let job = EmbeddingJob{
+      text: self.cipher.decrypt(job.text)?,
+      ..job
+    };
+    Ok(Some(job).filter(|job| !job.text.trim().is_empty()))
+  }
+
+  fn store_embedding(&mut self, message: i64, guild: u64, model: &str, vector: &[f32]) -> DatabaseResult<()>{
+    self.inner.store_embedding(message, guild, model, vector)
+  }
+
+  fn recall(&mut self, guild: u64, model: &str, vector: &[f32], limit: usize, window: u32) -> DatabaseResult<Vec<RecalledMessage>>{
+    Ok(self.inner.recall(guild, model, vector, limit, window)?.into_iter().filter_map(|recalled| Some(RecalledMessage{
+      message: self.readable(recalled.message)?,
+      ..recalled
+    })).collect())
+  }
+
+  //ciphertext is kept out of the index, so the rows the filter allows are decrypted a batch at a time and
+  //checked for the words here instead. newest matches come first since there's no index to rank them
+  fn search(&mut self, filter: &SearchFilter, limit: u32) -> DatabaseResult<Vec<SearchHit>>{
+    let query = words(&filter.query);
+    if query.is_empty(){
+      return Ok(Vec::new());
+    }
+    let mut hits = Vec::new();
+    let mut before = None;
+    loop{
+      let batch = self.inner.search_candidates(filter, before, SEARCH_BATCH)?;
+      let last = match batch.last(){
+        Some((id, _)) => *id,
+        None => return Ok(hits),
+      };
+      for (_, hit) in batch{
+        let message = match self.readable(hit.message){
+          Some(r) => r,
+          None => continue,
+        };
+        let hit = SearchHit{message, ..hit};
+        let found = words(&format!("{} {}", hit.author, hit.message));
+        if query.is_subset(&found){
+          hits.push(hit);
+          if hits.len() >= limit as usize{
+            return Ok(hits);
+          }
+        }
+      }
+      before = Some(last);
+    }
+  }
+
+  fn search_candidates(&mut self, filter: &SearchFilter, before: Option<i64>, limit: u32) -> DatabaseResult<Vec<(i64, SearchHit)>>{
+    Ok(self.inner.search_candidates(filter, before, limit)?.into_iter().filter_map(|(id, hit)| Some((id, SearchHit{
+      message: self.readable(hit.message)?,
+      ..hit
+    }))).collect())
+  }
+
+  fn latest_summary(&mut self, channel: u64) -> DatabaseResult<Option<(String, i64)>>{
+    Ok(self.inner.latest_summary(channel)?.and_then(|(summary, last)| Some((self.readable(summary)?, last))))
+  }
+
+  fn store_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<()>{
+    self.inner.store_summary(channel, last_message, &self.cipher.encrypt(summary), created_at)
+  }
+
+  fn guild_persona(&mut self, guild: u64) -> DatabaseResult<Option<String>>{
+    self.inner.guild_persona(guild)
+  }
+
+  fn set_persona(&mut self, guild: u64, persona: &str) -> DatabaseResult<()>{
+    self.inner.set_persona(guild, persona)
+  }
+
+  fn active_presets(&mut self, guild: u64, channel: u64) -> DatabaseResult<Vec<String>>{
+    self.inner.active_presets(guild, channel)
+  }
+
+  fn presets(&mut self, guild: u64) -> DatabaseResult<Vec<(String, String)>>{
+    self.inner.presets(guild)
+  }
+
+  fn save_preset(&mut self, guild: u64, name: &str, preset: &str) -> DatabaseResult<()>{
+    self.inner.save_preset(guild, name, preset)
+  }
+
+  fn delete_preset(&mut self, guild: u64, name: &str) -> DatabaseResult<bool>{
+    self.inner.delete_preset(guild, name)
+  }
+
+  fn assigned_preset(&mut self, guild: u64, channel: u64) -> DatabaseResult<Option<String>>{
+    self.inner.assigned_preset(guild, channel)
+  }
+
+  fn assign_preset(&mut self, guild: u64, channel: u64, preset: Option<&str>) -> DatabaseResult<()>{
+    self.inner.assign_preset(guild, channel, preset)
+  }
+
+  fn preset_assignments(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(u64, u64, String)>>{
+    self.inner.preset_assignments(scope)
+  }
+
+  fn set_facts(&mut self, guild: u64, user_id: u64, facts: &[String]) -> DatabaseResult<()>{
+    let encrypted: Vec<String> = facts.iter().map(|fact| self.cipher.encrypt(fact)).collect();
+    self.inner.set_facts(guild, user_id, &encrypted)
+  }
+
+  fn load_facts(&mut self, guild: u64, user_id: u64) -> DatabaseResult<Vec<String>>{
+    Ok(self.inner.load_facts(guild, user_id)?.into_iter().filter_map(|fact| self.readable(fact)).collect())
+  }
+
+  fn load_policy(&mut self, guild: u64) -> DatabaseResult<RetentionPolicy>{
+    self.inner.load_policy(guild)
+  }
+
+  fn save_policy(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<()>{
+    self.inner.save_policy(guild, policy)
+  }
+
+  fn retention_guilds(&mut self) -> DatabaseResult<Vec<u64>>{
+    self.inner.retention_guilds()
+  }
+
+  fn prune_candidates(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<(PruneReport, BTreeSet<i64>)>{
+    self.inner.prune_candidates(guild, policy)
+  }
+
+  fn delete_rows(&mut self, ids: &BTreeSet<i64>) -> DatabaseResult<()>{
+    self.inner.delete_rows(ids)
+  }
+
+  fn reclaim_space(&mut self) -> DatabaseResult<()>{
+    self.inner.reclaim_space()
+  }
+
+  fn scrub(&mut self) -> DatabaseResult<()>{
+    self.inner.scrub()
+  }
+
+  fn forget_user(&mut self, user_id: u64, guild: Option<u64>, anonymize: bool) -> DatabaseResult<usize>{
+    self.inner.forget_user(user_id, guild, anonymize)
+  }
+
+  fn export_messages(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>{
+    let mut messages = self.inner.export_messages(scope)?;
+    for (_, record) in messages.iter_mut(){
+      if let ExportRecord::Message{message, ..} = record{
+        *message = self.cipher.decrypt(std::mem::take(message))?;
+      }
+    }
+    Ok(messages)
+  }
+
+  fn export_summaries(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>{
+    let mut summaries = self.inner.export_summaries(scope)?;
+    for (_, record) in summaries.iter_mut(){
+      if let ExportRecord::Summary{summary, ..} = record{
+        *summary = self.cipher.decrypt(std::mem::take(summary))?;
+      }
+    }
+    Ok(summaries)
+  }
+
+  fn import_message(&mut self, message: &ImportedMessage) -> DatabaseResult<(i64, bool)>{
+    let encrypted = self.cipher.encrypt(message.message);
+    self.inner.import_message(&ImportedMessage{message: &encrypted, ..*message})
+  }
+
+  fn import_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<bool>{
+    self.inner.import_summary(channel, last_message, &self.cipher.encrypt(summary), created_at)
+  }
+
+  fn stored_texts(&mut self, column: TextColumn) -> DatabaseResult<Vec<(i64, String)>>{
+    self.inner.stored_texts(column)
+  }
+
+  fn rewrite_text(&mut self, column: TextColumn, id: i64, text: &str) -> DatabaseResult<()>{
+    self.inner.rewrite_text(column, id, text)
+  }
+
+  fn lock_maintenance(&mut self) -> DatabaseResult<bool>{
+    self.inner.lock_maintenance()
+  }
+
+  fn unlock_maintenance(&mut self) -> DatabaseResult<()>{
+    self.inner.unlock_maintenance()
+  }
+
+  fn begin(&mut self) -> DatabaseResult<()>{
+    self.inner.begin()
+  }
+
+  fn commit(&mut self) -> DatabaseResult<()>{
+    self.inner.commit()
+  }
+
+  fn rollback(&mut self) -> DatabaseResult<()>{
+    self.inner.rollback()
+  }
+}
+
+fn rewrite_texts(db: &mut dyn Database, current: Option<&MessageCipher>, new: Option<&MessageCipher>) -> DatabaseResult<usize>{
+  let mut rewritten = 0;
+  for column in TEXT_COLUMNS{
+    for (id, stored) in db.stored_texts(column)?{
+      //texts an interrupted run already moved to the new key stay as they are, so it can simply be run again
+      if new.is_some_and(|cipher| stored.starts_with(PREFIX) && cipher.decrypt(stored.clone()).is_ok()){
+        continue;
+      }
+      let text = match current{
+        Some(cipher) => cipher.decrypt(stored.clone())?,
+        //encrypting it again would bury the text under two keys
+        None if stored.starts_with(PREFIX) => {
+          return Err(DatabaseError::Encryption("stored texts are encrypted but no current key is configured".to_string()));
+        },
+        None => stored.clone(),
+      };
+      let text = match new{
+        Some(cipher) => cipher.encrypt(&text),
+        None => text,
+      };
+      if text != stored{
+        db.rewrite_text(column, id, &text)?;
+        rewritten += 1;
+      }
+    }
+  }
+  Ok(rewritten)
+}
+
+//moves every stored text from the current key to the new one in one transaction, rows still in plain text
+//get encrypted along the way and a new key of None writes everything back as plain text. texts already on
+//the new key are skipped. returns how many texts changed
+pub fn rotate_key(db: &mut dyn Database, current: Option<&MessageCipher>, new: Option<&MessageCipher>) -> DatabaseResult<usize>{
+  db.begin()?;
+  let rewritten = match rewrite_texts(db, current, new){
+    Ok(r) => r,
+    Err(err) => {
+      if let Err(err) = db.rollback(){
+        println!("Can't roll back failed key rotation: {}", err);
+      }
+      return Err(err);
+    }
+  };
+  db.commit()?;
+  //nothing of the old texts may be left readable in the index, the log or free pages
+  db.scrub()?;
+  Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests{
+  use std::path::{
+    Path,
+    PathBuf,
+  };
+
+  use super::*;
+  use crate::sqlite::SqliteDatabase;
+
+  fn scratch_file(name: &str) -> PathBuf{
+    let path = std::env::temp_dir().join(format!("lily-{}-{}.db", name, std::process::id()));
+    for suffix in ["", "-wal", "-shm"]{
+      let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+    path
+  }
+
+  fn on_disk(path: &Path) -> Vec<u8>{
+    ["", "-wal"].iter()
+      .flat_map(|suffix| std::fs::read(format!("{}{}", path.display(), suffix)).unwrap_or_default())
+      .collect()
+  }
+
+  #[test]
+  fn encrypted_text_round_trips(){
+    let cipher = MessageCipher::new(b"secret");
+    let encrypted = cipher.encrypt("hello there");
+    assert!(encrypted.starts_with(PREFIX));
+    assert!(!encrypted.contains("hello"));
+    assert_eq!(cipher.decrypt(encrypted.clone()).unwrap(), "hello there");
+    //imports match rows up by their stored text
+    assert_eq!(cipher.encrypt("hello there"), encrypted);
+    assert_eq!(cipher.encrypt(""), "");
+  }
+
+  #[test]
+  fn wrong_key_fails_to_decrypt(){
+    let encrypted = MessageCipher::new(b"secret").encrypt("hello there");
+    assert!(MessageCipher::new(b"other").decrypt(encrypted).is_err());
+    assert!(MessageCipher::new(b"secret").decrypt("enc:not base64!".to_string()).is_err());
+    assert!(MessageCipher::new(b"secret").decrypt("enc:AAAA".to_string()).is_err());
+  }
+
+  #[test]
+  fn plain_text_passes_through(){
+    let cipher = MessageCipher::new(b"secret");
+    assert_eq!(cipher.decrypt("from before encryption".to_string()).unwrap(), "from before encryption");
+  }
+
+  #[test]
+  fn rotation_encrypts_plain_rows_and_decrypts_back(){
+    let mut db = SqliteDatabase::open(Path::new(":memory:"), false).unwrap();
+    let cipher = MessageCipher::new(b"secret");
+    db.insert_messages(&[StorageMessage::sample("plain"), StorageMessage::sample(&cipher.encrypt("sealed"))]).unwrap();
+    assert_eq!(rotate_key(&mut db, Some(&cipher), Some(&cipher)).unwrap(), 1);
+    assert!(db.stored_texts(TextColumn::Messages).unwrap().iter().all(|(_, text)| text.starts_with(PREFIX)));
+    assert_eq!(rotate_key(&mut db, Some(&cipher), None).unwrap(), 2);
+    let texts: Vec<String> = db.stored_texts(TextColumn::Messages).unwrap().into_iter().map(|(_, text)| text).collect();
+    assert_eq!(texts, vec!["plain", "sealed"]);
+  }
+
+  #[test]
+  fn rotation_leaves_no_plain_text_on_disk(){
+    let path = scratch_file("rotation");
+    let mut db = SqliteDatabase::open(&path, false).unwrap();
+    db.insert_messages(&[StorageMessage::sample("zanzibarquokka marmalade")]).unwrap();
+    assert!(on_disk(&path).windows(14).any(|window| window == b"zanzibarquokka"));
+    let cipher = MessageCipher::new(b"secret");
+    assert_eq!(rotate_key(&mut db, None, Some(&cipher)).unwrap(), 1);
+    assert!(!on_disk(&path).windows(14).any(|window| window == b"zanzibarquokka"));
+    let mut db = EncryptedDatabase::new(Box::new(db), cipher);
+    assert_eq!(db.context(1, 0, i64::MAX, 10).unwrap()[0].message, "zanzibarquokka marmalade");
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn interrupted_rotation_can_run_again(){
+    let mut db = SqliteDatabase::open(Path::new(":memory:"), false).unwrap();
+    let (old, new) = (MessageCipher::new(b"old"), MessageCipher::new(b"new"));
+    db.insert_messages(&[StorageMessage::sample(&old.encrypt("first")), StorageMessage::sample(&new.encrypt("second")), StorageMessage::sample("third")]).unwrap();
+    assert_eq!(rotate_key(&mut db, Some(&old), Some(&new)).unwrap(), 2);
+    assert_eq!(rotate_key(&mut db, Some(&old), Some(&new)).unwrap(), 0);
+    let mut db = EncryptedDatabase::new(Box::new(db), new);
+    let texts: Vec<String> = db.context(1, 0, i64::MAX, 10).unwrap().into_iter().map(|message| message.message).collect();
+    assert_eq!(texts, vec!["first", "second", "third"]);
+  }
+
+  #[test]
+  fn rotation_without_the_current_key_leaves_ciphertext_alone(){
+    let mut db = SqliteDatabase::open(Path::new(":memory:"), false).unwrap();
+    let (old, new) = (MessageCipher::new(b"old"), MessageCipher::new(b"new"));
+    let sealed = old.encrypt("sealed");
+    db.insert_messages(&[StorageMessage::sample("plain"), StorageMessage::sample(&sealed)]).unwrap();
+    assert!(matches!(rotate_key(&mut db, None, Some(&new)), Err(DatabaseError::Encryption(_))));
+    let texts: Vec<String> = db.stored_texts(TextColumn::Messages).unwrap().into_iter().map(|(_, text)| text).collect();
+    assert_eq!(texts, vec!["plain".to_string(), sealed]);
+  }
+}
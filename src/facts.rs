@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{
+  UnboundedSender,
+  unbounded_channel as tokio_channel,
+};
+
+use crate::{
+  backend::{
+    GenerationRequest,
+    SamplerSettings,
+  },
+  kobold::{
+    Generator,
+    StoredMessage,
+  },
+  storage::StorageRequest,
+};
+
+//a profile is kept short enough to sit in every prompt the user shows up in
+const MAX_FACTS: usize = 15;
+const MAX_FACT_LENGTH: usize = 200;
+
+//a user's recent messages, to be folded into what the bot knows about them
+pub struct FactRequest{
+  pub guild: u64,
+  pub user_id: u64,
+  //handed back with the facts so a pass that raced a clear can be told apart
+  pub pass: u64,
+  pub name: String,
+  pub known: Vec<String>,
+  pub messages: Vec<String>,
+  pub settings: SamplerSettings,
+}
+
+fn fact_prompt(request: &FactRequest) -> GenerationRequest{
+  let mut system = format!(
+    "You keep notes about {}, a member of a discord server, so a chat bot remembers them. \
+    From their messages, write down lasting facts they share about themselves: preferences, the pronouns they state, \
+    what they do, recurring topics they bring up. Ignore anything temporary and anything said about other people. \
+    Write the full updated list, one short fact per line starting with \"- \", at most {} lines. \
+    If there is nothing worth keeping, write NONE.",
+    request.name,
+    MAX_FACTS,
+  );
+  if !request.known.is_empty(){
+    system.push_str("\n\nWhat is already known, keep what still holds:\n");
+    system.push_str(&request.known.iter().map(|fact| format!("- {}", fact)).collect::<Vec<String>>().join("\n"));
+  }
+  GenerationRequest{
+    system,
+    messages: vec![StoredMessage{
+      author: request.name.clone(),
+      message: request.messages.join("\n"),
+    }],
+    bot_name: "Notes".to_string(),
+    summary: None,
+    recalled: Vec::new(),
+    speaker: String::new(),
+    facts: Vec::new(),
+    settings: request.settings.clone(),
+    genkey: format!("facts-{}-{}", request.guild, request.user_id),
+  }
+}
+
+//one fact per list item, anything that isn't a list item is the model thinking out loud
+fn parse_facts(generation: &str) -> Vec<String>{
+  generation.lines()
+    .filter_map(|line| line.trim().strip_prefix("- ").or(line.trim().strip_prefix("* ")))
+    .map(|fact| fact.trim())
+    .filter(|fact| !fact.is_empty() && !fact.eq_ignore_ascii_case("none"))
+    .map(|fact| fact.chars().take(MAX_FACT_LENGTH).collect())
+    .take(MAX_FACTS)
+    .collect()
+}
+
+//facts are extracted one user at a time in the background, a failed pass keeps the old facts
+pub fn spawn_fact_thread(storage_tx: UnboundedSender<StorageRequest>, generator: Arc<Generator>) -> UnboundedSender<FactRequest>{
+  let (fact_tx, mut fact_rx) = tokio_channel::<FactRequest>();
+  tokio::spawn(async move{
+    while let Some(request) = fact_rx.recv().await{
+      let generation = match generator.background(&fact_prompt(&request)).await{
+        Ok(r) => r,
+        Err(err) => {
+          println!("Unable to extract user facts with {} server: {}", generator.name(), err);
+          continue;
+        }
+      };
+      let facts = parse_facts(&generation);
+      //an empty answer next to known facts is far more likely a bad generation than everything becoming untrue
+      if facts.is_empty() && !request.known.is_empty() && !generation.to_lowercase().contains("none"){
+        continue;
+      }
+      if let Err(err) = storage_tx.send(StorageRequest::SetFacts{
+        guild: request.guild,
+        user_id: request.user_id,
+        facts,
+        pass: Some(request.pass),
+      }){
+        println!("Unable to send user facts to storage thread: {}", err);
+      }
+    }
+  });
+  fact_tx
+}
@@ -1,20 +1,69 @@
-use std::{time::Duration, vec};
+use std::{
+  collections::{
+    HashMap,
+    VecDeque,
+  },
+  sync::{
+    atomic::{
+      AtomicU64,
+      AtomicUsize,
+      Ordering,
+    },
+    Arc,
+    Mutex,
+  },
+  time::Duration,
+};
 
-use reqwest::{header, StatusCode};
+use async_trait::async_trait;
+use chrono::DateTime;
+use dashmap::DashMap;
+use reqwest::StatusCode;
 use serde::{
   Serialize,
   Deserialize,
 };
-use tokio::sync::mpsc::{
-  UnboundedSender,
-  unbounded_channel as tokio_channel,
+use tokio::sync::{
+  mpsc::{
+    UnboundedSender,
+    unbounded_channel as tokio_channel,
+  },
+  oneshot,
+  Notify,
+  Semaphore,
 };
 use crate::{
+  backend::{
+    backend_from_env,
+    drain_sse_events,
+    DiscardTokens,
+    endpoint,
+    streaming_client,
+    GenerationBackend,
+    GenerationError,
+    GenerationRequest,
+    SamplerSettings,
+    TokenSink,
+  },
+  embeddings::EmbeddingClient,
   discord::{
-    send_discord_message, 
-    start_typing,
+    show_swipe,
+    StreamingReply,
   },
-  storage::StorageMessage,
+  prompt::{
+    build_prompt,
+    system_with_memory,
+    TokenCounter,
+  },
+  persona::Persona,
+  storage::{
+    unix_now,
+    MessageSource,
+    StorageMessage,
+    StorageRequest,
+  },
+  swipe::Swipes,
+  template::ChatTemplate,
 };
 
 #[derive(Serialize)]
@@ -78,30 +127,26 @@ struct KoboldData{
   ignore_eos: bool,
   stream: bool,
   max_length: u64,
+  genkey: String,
 }
 
 impl KoboldData{
-  fn new(prompt: String) -> Self{
+  fn new(prompt: String, breakers: Vec<String>, settings: &SamplerSettings, genkey: String) -> Self{
     let dry_sequence_breakers = vec!["\n".to_string(), ":".to_string(), "\"".to_string(), "*".to_string()]; 
-    let breakers = vec![
-      "<|eot_id|><|end_of_text|>".to_string(),
-      "<|start_header_id|>user<|end_header_id|>".to_string(),
-      "<|start_header_id|>assistant<|end_header_id|>".to_string(),
-      "<|begin_of_text|><|start_header_id|>system<|end_header_id|>".to_string()];
     Self{
       prompt,
-      max_new_tokens: 512,
-      new_tokens: 512,
-      temperature: 1.0,
-      top_p: 1.0,
-      typical_p: 1.0,
-      typical: 1.0,
-      sampler_seed: -1,
-      min_p: 0.0,
-      repetition_penalty: 1.0,
-      frequency_penalty: 0.0,
-      presence_penalty: 0.0,
-      top_k: 0,
+      max_new_tokens: settings.max_length,
+      new_tokens: settings.max_length,
+      temperature: settings.temperature,
+      top_p: settings.top_p,
+      typical_p: settings.typical_p,
+      typical: settings.typical_p,
+      sampler_seed: settings.seed as i64,
+      min_p: settings.min_p,
+      repetition_penalty: settings.repetition_penalty,
+      frequency_penalty: settings.frequency_penalty,
+      presence_penalty: settings.presence_penalty,
+      top_k: settings.top_k,
       skew: 0.0,
       min_tokens: 0,
       length_penalty: 0.0,
@@ -114,15 +159,15 @@ impl KoboldData{
       dry_base: 1.75,
       dry_sequence_breakers,
       stopping_strings: breakers.clone(),
-      stop: breakers.clone(),
-      truncation_length: 16384,
+      stop: breakers,
+      truncation_length: settings.context_length,
       ban_eos_token: false,
       skip_special_tokens: true,
       top_a: 0.0, //maybe this one
       tfs: 1.0, //maybe this one
-      mirostat_mode: 0,
-      mirostat_tau: 5.0,
-      mirostat_eta: 0.1,
+      mirostat_mode: settings.mirostat_mode,
+      mirostat_tau: settings.mirostat_tau,
+      mirostat_eta: settings.mirostat_eta,
       custom_token_bans: String::new(),
       banned_strings: Vec::new(),
       api_type: "koboldcpp".to_string(),
@@ -130,24 +175,25 @@ impl KoboldData{
       legecy_api: false,
       sampler_order: vec![6,0,1,3,4,2,5],
       grammer: String::new(),
-      rep_pen: 1.0,
-      rep_pen_range: 0,
+      rep_pen: settings.repetition_penalty,
+      rep_pen_range: settings.repetition_penalty_range,
       rep_pen_slope: 1.0,
-      repetition_penalty_range: 0,
-      seed: -1,
+      repetition_penalty_range: settings.repetition_penalty_range,
+      seed: settings.seed,
       guidance_scale: 1.0,
       negative_prompt: String::new(),
       grammer_string: String::new(),
-      repeat_penalty: 1.0,
+      repeat_penalty: settings.repetition_penalty,
       tfs_z: 1.0,
-      repeat_last_n: 0.0,
-      n_predict: 512,
-      num_predict: 512,
-      num_ctx: 16384,
-      mirostat: 0.0, //Maybe this one
+      repeat_last_n: settings.repetition_penalty_range as f32,
+      n_predict: settings.max_length,
+      num_predict: settings.max_length,
+      num_ctx: settings.context_length,
+      mirostat: settings.mirostat_mode as f32, //Maybe this one
       ignore_eos: false,
       stream: true,
-      max_length: 512,
+      max_length: settings.max_length as u64,
+      genkey,
     }
   }
 }
@@ -158,111 +204,586 @@ struct KoboldError{
   r#type: String
 }
 
-#[derive(Deserialize)]
-struct KoboldResponse{
-  results: Vec<KoboldGeneration>,
+#[derive(Serialize)]
+struct KoboldAbort<'a>{
+  genkey: &'a str,
 }
 
 #[derive(Deserialize)]
-struct KoboldGeneration{
-  text: String,
+struct KoboldStreamToken{
+  token: String,
 }
 
+#[derive(Clone)]
 pub struct KoboldRequest{
   pub origin_channel: u64,
+  pub guild: Option<u64>,
+  pub persona: Persona,
+  pub settings: SamplerSettings,
   pub messages: Vec<StoredMessage>,
+  //covers everything older than messages
+  pub summary: Option<String>,
+  //the user who asked for the reply and what's known about them, the newest message
+  //in the history can be someone else's once a batch of voice messages went in together
+  pub speaker: String,
+  pub facts: Vec<String>,
+  //the reply being rewritten, keyed like swipes are
+  pub regenerate: Option<u64>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct StoredMessage{
   pub message: String,
   pub author: String,
 }
 
-const TEXT_START: &str = "<|begin_of_text|>";
-const TEXT_END: &str = "<|eot_id|>";
-const HEADER_START: &str = "<|start_header_id|>";
-const HEADER_END: &str = "<|end_header_id|>";
-const AI_DESC: &str = "You are a discord bot named Lily on a server called Big Gay Rock. You are speaking to the members of the server and will help them with whatever they ask.";
+pub struct KoboldBackend{
+  template: ChatTemplate,
+  token_counter: TokenCounter,
+}
 
-pub fn spawn_kobold_thread(message_storage_channel: UnboundedSender<StorageMessage>) -> UnboundedSender<KoboldRequest>{
-  let (kobold_tx, mut kobold_rx) = tokio_channel::<KoboldRequest>();
-  tokio::spawn(async move{
-    while let Some(kobold_req) = kobold_rx.recv().await{
-      let origin_channel = kobold_req.origin_channel;
-      let messages = kobold_req.messages;
-      let typing_state = match start_typing(origin_channel).await{
-        Some(r) => r,
-        None => {
-          println!("Unable to start typing in the channel, perhaps the discord server has died.");
-          continue;
+impl KoboldBackend{
+  pub fn from_env() -> Self{
+    let template = ChatTemplate::from_env().unwrap_or_else(|err| {
+      println!("{}, falling back to the llama3 chat template", err);
+      ChatTemplate::llama3()
+    });
+    Self{
+      template,
+      token_counter: TokenCounter::from_env(),
+    }
+  }
+}
+
+#[async_trait]
+impl GenerationBackend for KoboldBackend{
+  fn name(&self) -> &'static str{
+    "KoboldCPP"
+  }
+
+  async fn generate(&self, request: &GenerationRequest, sink: &mut dyn TokenSink) -> Result<String, GenerationError>{
+    let prompt = build_prompt(
+      &self.template,
+      &system_with_memory(request),
+      &request.messages,
+      &request.bot_name,
+      request.settings.prompt_budget(),
+      &self.token_counter,
+    ).await;
+    let data = KoboldData::new(prompt, self.template.stop_sequences.clone(), &request.settings, request.genkey.clone());
+    let client = streaming_client("text/event-stream")?;
+    let mut res = client.post(endpoint("KOBOLD_URL", "api/extra/generate/stream"))
+      .json(&data)
+      .send()
+      .await
+      .map_err(|err| GenerationError::Unreachable(err.to_string()))?;
+
+    if res.status() == StatusCode::SERVICE_UNAVAILABLE{
+      return match res.json::<KoboldError>().await{
+        Ok(r) => Err(GenerationError::Busy(format!("{} ({})", r.msg, r.r#type))),
+        Err(err) => Err(GenerationError::Busy(err.to_string())),
+      };
+    }else if res.status() != StatusCode::OK{
+      return Err(GenerationError::Failed(format!("Something weird happened: {:?}", res)));
+    }
+
+    let mut generation = String::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    loop{
+      let chunk = match res.chunk().await{
+        Ok(Some(r)) => r,
+        Ok(None) => break,
+        Err(err) => {
+          println!("Kobold stream was interrupted: {}", err);
+          break;
         }
       };
-      let bot_name = std::env::var("BOT_NAME").unwrap();
-      let new_prompt = format!(
-        "{TEXT_START}{HEADER_START}system{HEADER_END}\n\n{AI_DESC}{TEXT_END}"
-      );
-      let kobold_messages: Vec<String> = messages.iter().map(|msg| {
-        format!("{HEADER_START}user{HEADER_END}\n\n{}: {}{TEXT_END}", msg.author, msg.message)
-      }).collect();
-      let context_messages = kobold_messages.join("");
-
-      let ai_start = format!("{HEADER_START}assistant{HEADER_END}\n\n{bot_name}:");
-      let prompt = new_prompt + &context_messages + &ai_start;
-      
-      let mut headers = header::HeaderMap::new();
-      headers.insert("accept", header::HeaderValue::from_static("application/json"));
-      headers.insert("Content-Type", header::HeaderValue::from_static("application/json"));
-      let data = KoboldData::new(prompt);
-      let client = match reqwest::Client::builder()
-        .default_headers(headers)
-        .timeout(Duration::from_secs(60))
-        .build(){
+      buffer.extend_from_slice(&chunk);
+      for payload in drain_sse_events(&mut buffer){
+        let token: KoboldStreamToken = match serde_json::from_str(&payload){
           Ok(r) => r,
-          Err(err) =>{
-            println!("Reqwest client can't be built: {}", err);
-            return;
+          Err(err) => {
+            println!("Unable to parse token from kobold stream: {}", err);
+            continue;
           }
+        };
+        sink.push(&token.token).await;
+        generation.push_str(&token.token);
+      }
+    }
+    Ok(generation)
+  }
+
+  async fn abort(&self, genkey: &str){
+    let client = reqwest::Client::new();
+    if let Err(err) = client.post(endpoint("KOBOLD_URL", "api/extra/abort"))
+      .json(&KoboldAbort{genkey})
+      .send()
+      .await{
+        println!("Unable to abort generation on Kobold Server: {}", err);
+      }
+  }
+}
+
+struct ActiveGeneration{
+  cancel: Arc<Notify>,
+  //the discord messages the reply is being written into, a stop reaction only counts on these
+  messages: Vec<u64>,
+}
+
+//generations that are running right now, one per channel since channels generate in order
+#[derive(Default)]
+pub struct ActiveGenerations{
+  by_channel: DashMap<u64, ActiveGeneration>,
+  next_genkey: AtomicU64,
+}
+
+impl ActiveGenerations{
+  fn start(&self, channel: u64) -> (String, Arc<Notify>){
+    //koboldcpp only needs the key to be unique among running generations
+    let genkey = format!("KCPP{}{:04}", std::process::id(), self.next_genkey.fetch_add(1, Ordering::SeqCst));
+    let cancel = Arc::new(Notify::new());
+    self.by_channel.insert(channel, ActiveGeneration{
+      cancel: cancel.clone(),
+      messages: Vec::new(),
+    });
+    (genkey, cancel)
+  }
+
+  pub fn add_message(&self, channel: u64, message: u64){
+    if let Some(mut generation) = self.by_channel.get_mut(&channel){
+      generation.messages.push(message);
+    }
+  }
+
+  //a stop reaction on an older reply in the channel leaves the running one alone
+  pub fn stop_message(&self, channel: u64, message: u64) -> bool{
+    let owns = self.by_channel.get(&channel).is_some_and(|generation| generation.messages.contains(&message));
+    owns && self.stop(channel)
+  }
+
+  fn finish(&self, channel: u64){
+    self.by_channel.remove(&channel);
+  }
+
+  //returns whether there was anything to stop
+  pub fn stop(&self, channel: u64) -> bool{
+    match self.by_channel.get(&channel){
+      Some(generation) => {
+        generation.cancel.notify_one();
+        true
+      },
+      None => false,
+    }
+  }
+}
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+struct RetryPolicy{
+  attempts: u32,
+  base_delay: Duration,
+}
+
+impl RetryPolicy{
+  //GENERATION_RETRIES and GENERATION_RETRY_DELAY (seconds) tune how long a busy server is waited on
+  fn from_env() -> Self{
+    let attempts = std::env::var("GENERATION_RETRIES").ok()
+      .and_then(|r| r.trim().parse().ok())
+      .unwrap_or(5);
+    let base_delay = std::env::var("GENERATION_RETRY_DELAY").ok()
+      .and_then(|r| r.trim().parse().ok())
+      .unwrap_or(2);
+    Self{
+      attempts,
+      base_delay: Duration::from_secs(base_delay),
+    }
+  }
+
+  //doubles every attempt but never waits longer than MAX_RETRY_DELAY at a time
+  fn delay(&self, attempt: u32) -> Duration{
+    (self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1))).min(MAX_RETRY_DELAY)
+  }
+}
+
+//requests waiting on a busy server, oldest first, so users can be told where they stand
+#[derive(Default)]
+struct BusyQueue{
+  waiting: Mutex<VecDeque<u64>>,
+  next_ticket: AtomicU64,
+}
+
+impl BusyQueue{
+  fn join(&self) -> BusyTicket<'_>{
+    let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+    self.waiting.lock().unwrap().push_back(ticket);
+    BusyTicket{
+      queue: self,
+      ticket,
+    }
+  }
+
+  fn position(&self, ticket: u64) -> usize{
+    let waiting = self.waiting.lock().unwrap();
+    waiting.iter().position(|t| *t == ticket).map(|i| i+1).unwrap_or(waiting.len()+1)
+  }
+
+  fn leave(&self, ticket: u64){
+    self.waiting.lock().unwrap().retain(|t| *t != ticket);
+  }
+}
+
+//a place in the busy queue, given back when dropped so a stopped generation doesn't keep it
+struct BusyTicket<'a>{
+  queue: &'a BusyQueue,
+  ticket: u64,
+}
+
+impl BusyTicket<'_>{
+  fn position(&self) -> usize{
+    self.queue.position(self.ticket)
+  }
+}
+
+impl Drop for BusyTicket<'_>{
+  fn drop(&mut self){
+    self.queue.leave(self.ticket);
+  }
+}
+
+//GENERATION_CONCURRENCY is how many generations the backend is trusted to run at once
+fn generation_concurrency() -> usize{
+  std::env::var("GENERATION_CONCURRENCY").ok()
+    .and_then(|r| r.trim().parse().ok())
+    .filter(|r| *r > 0)
+    .unwrap_or(1)
+}
+
+//the backend and everything that keeps it from being swamped, replies, summaries and fact
+//extraction all take their turn through the same one
+pub struct Generator{
+  backend: Arc<dyn GenerationBackend>,
+  retry: RetryPolicy,
+  busy_queue: BusyQueue,
+  slots: Semaphore,
+}
+
+impl Generator{
+  pub fn from_env() -> Result<Self, String>{
+    Ok(Self{
+      backend: backend_from_env()?,
+      retry: RetryPolicy::from_env(),
+      busy_queue: BusyQueue::default(),
+      slots: Semaphore::new(generation_concurrency()),
+    })
+  }
+
+  pub fn name(&self) -> &'static str{
+    self.backend.name()
+  }
+
+  //runs the generation, waiting out a busy server with backoff and keeping the channel posted on it,
+  //the slot is only held while generating so other channels aren't stuck behind the backoff
+  async fn reply(&self, request: &GenerationRequest, reply: &mut StreamingReply) -> Option<String>{
+    let mut ticket = None;
+    let mut attempt = 0;
+    let generation = loop{
+      let generation = {
+        let _slot = self.slots.acquire().await.unwrap();
+        self.backend.generate(request, reply).await
+      };
+      match generation{
+        Ok(r) => break Some(r),
+        Err(GenerationError::Busy(err)) => {
+          attempt += 1;
+          println!("{} server is busy: {}", self.name(), err);
+          if attempt > self.retry.attempts{
+            reply.status(&format!("The {} server is still busy, giving up after {} retries. Please try again later", self.name(), self.retry.attempts)).await;
+            break None;
+          }
+          let ticket = ticket.get_or_insert_with(|| self.busy_queue.join());
+          let delay = self.retry.delay(attempt);
+          reply.status(&format!(
+            "The {} server is busy, queued at position {}. Retrying in {}s ({}/{})",
+            self.name(),
+            ticket.position(),
+            delay.as_secs(),
+            attempt,
+            self.retry.attempts,
+          )).await;
+          tokio::time::sleep(delay).await;
+        },
+        Err(GenerationError::Unreachable(err)) => {
+          println!("Unable to request from {} Server: {}", self.name(), err);
+          reply.status(&format!("The {} server is down", self.name())).await;
+          break None;
+        },
+        Err(err) => {
+          println!("{} {}", self.name(), err);
+          break None;
+        },
+      }
+    };
+    generation
+  }
+
+  //background work with nobody to keep posted, the slot is given back while waiting out a busy
+  //server so replies people are waiting on can go first
+  pub async fn background(&self, request: &GenerationRequest) -> Result<String, GenerationError>{
+    let mut attempt = 0;
+    loop{
+      let generation = {
+        let _slot = self.slots.acquire().await.unwrap();
+        self.backend.generate(request, &mut DiscardTokens).await
       };
-      //add the / at the end of the server url if it's not there
-      let mut kobold_server = std::env::var("KOBOLD_URL").unwrap();
-      if kobold_server.chars().last().unwrap() != '/'{
-        kobold_server.push('/');
+      match generation{
+        Err(GenerationError::Busy(err)) if attempt < self.retry.attempts => {
+          attempt += 1;
+          println!("{} server is busy: {}", self.name(), err);
+          tokio::time::sleep(self.retry.delay(attempt)).await;
+        },
+        other => return other,
+      }
+    }
+  }
+
+  async fn abort(&self, genkey: &str){
+    self.backend.abort(genkey).await;
+  }
+}
+
+//everything a channel worker needs, shared between all of them
+struct WorkerContext{
+  generator: Arc<Generator>,
+  storage_tx: UnboundedSender<StorageRequest>,
+  generations: Arc<ActiveGenerations>,
+  swipes: Arc<Swipes>,
+  embeddings: Option<EmbeddingClient>,
+  recall_limit: usize,
+  recall_window: u32,
+}
+
+//RECALL_LIMIT is how many related older messages are put in the prompt when embeddings are set up
+fn recall_limit() -> usize{
+  std::env::var("RECALL_LIMIT").ok()
+    .and_then(|r| r.trim().parse().ok())
+    .unwrap_or(4)
+}
+
+//RECALL_WINDOW is how many of the guild's newest embedded messages recall compares against, so the
+//lookup stays quick however long the history gets
+fn recall_window() -> u32{
+  std::env::var("RECALL_WINDOW").ok()
+    .and_then(|r| r.trim().parse().ok())
+    .filter(|r| *r > 0)
+    .unwrap_or(5000)
+}
+
+//looks up older messages from the guild that are close in meaning to the message being replied to,
+//anything already in the verbatim history is left out
+async fn recall_related(context: &WorkerContext, kobold_req: &KoboldRequest) -> Vec<String>{
+  let (embeddings, guild, query) = match (&context.embeddings, kobold_req.guild, kobold_req.messages.last()){
+    (Some(embeddings), Some(guild), Some(query)) if context.recall_limit > 0 => (embeddings, guild, query),
+    _ => return Vec::new(),
+  };
+  let vector = match embeddings.embed(std::slice::from_ref(&query.message)).await{
+    Ok(mut r) => r.remove(0),
+    Err(err) => {
+      println!("Unable to embed message for recall: {}", err);
+      return Vec::new();
+    }
+  };
+  let (reply_tx, reply_rx) = oneshot::channel();
+  //asks for extra so there are still enough left once the ones already in the prompt are dropped
+  if let Err(err) = context.storage_tx.send(StorageRequest::Recall{
+    guild,
+    model: embeddings.model().to_string(),
+    vector,
+    limit: context.recall_limit + kobold_req.messages.len().min(context.recall_limit * 4),
+    window: context.recall_window,
+    reply: reply_tx,
+  }){
+    println!("Unable to ask storage thread for related messages: {}", err);
+    return Vec::new();
+  }
+  let recalled = reply_rx.await.unwrap_or_default();
+  recalled.into_iter()
+    .filter(|recalled| !kobold_req.messages.iter().any(|msg| msg.author == recalled.author && msg.message == recalled.message))
+    .take(context.recall_limit)
+    .map(|recalled| {
+      let date = recalled.created_at
+        .and_then(|created| DateTime::from_timestamp(created, 0))
+        .map(|created| format!("[{}] ", created.format("%Y-%m-%d")))
+        .unwrap_or_default();
+      format!("{}{}: {}", date, recalled.author, recalled.message)
+    })
+    .collect()
+}
+
+async fn handle_request(context: &WorkerContext, kobold_req: KoboldRequest){
+  let origin_channel = kobold_req.origin_channel;
+  //a regenerated reply is written over the alternative currently on show
+  let shown = kobold_req.regenerate.and_then(|anchor| context.swipes.current(anchor));
+  let started = match &shown{
+    Some(view) => StreamingReply::resume(origin_channel, &view.messages).await,
+    None => StreamingReply::start(origin_channel).await,
+  };
+  let mut reply = match started{
+    Some(r) => r,
+    None => {
+      println!("Unable to start typing in the channel, perhaps the discord server has died.");
+      return;
+    }
+  };
+  let recalled = recall_related(context, &kobold_req).await;
+  let (genkey, cancel) = context.generations.start(origin_channel);
+  reply.watch_stops(context.generations.clone());
+  let request = GenerationRequest{
+    system: kobold_req.persona.system_prompt(),
+    messages: kobold_req.messages.clone(),
+    bot_name: kobold_req.persona.name.clone(),
+    summary: kobold_req.summary.clone(),
+    recalled,
+    speaker: kobold_req.speaker.clone(),
+    facts: kobold_req.facts.clone(),
+    settings: kobold_req.settings.clone(),
+    genkey,
+  };
+  let outcome = tokio::select!{
+    generation = context.generator.reply(&request, &mut reply) => Some(generation),
+    _ = cancel.notified() => None,
+  };
+  context.generations.finish(origin_channel);
+  let generation = match outcome{
+    Some(r) => r,
+    None => {
+      //a stopped reply stays visible but never makes it into the history
+      context.generator.abort(&request.genkey).await;
+      reply.mark_stopped().await;
+      let messages = reply.finish().await;
+      restore_swipe(context, &kobold_req, messages).await;
+      return;
+    }
+  };
+  let messages = reply.finish().await;
+  let generation = match generation{
+    Some(r) if !r.trim().is_empty() => r,
+    _ => {
+      restore_swipe(context, &kobold_req, messages).await;
+      return;
+    }
+  };
+  if let Some(anchor) = kobold_req.regenerate{
+    if let Some(view) = context.swipes.add_alternative(anchor, generation.clone(), messages){
+      show_swipe(&view, anchor, false).await;
+      if let Err(err) = context.storage_tx.send(StorageRequest::ReplaceBotMessage{
+        guild: view.guild,
+        discord_id: anchor,
+        message: view.text,
+        parts: view.messages.iter().skip(1).copied().collect(),
+      }){
+        println!("Unable to send regenerated reply to storage thread: {}", err);
       }
-      kobold_server.push_str("api/v1/generate");
-      let res = match client.post(kobold_server)
-          .json(&data)
-          .send()
-          .await{
-            Ok(r) => r,
-            Err(err) => {
-              println!("Unable to request from Kobold Server: {}", err);
-              send_discord_message("The KoboldCPP server is down".to_string(), origin_channel, typing_state).await;
-              continue;
-            },
-          };
-      
-      if res.status() == StatusCode::OK{
-        let res_json: KoboldResponse = res.json().await.unwrap();
-        if let Some(x) = res_json.results.iter().next(){
-          send_discord_message(x.text.clone(), origin_channel, typing_state).await;
-          if let Err(err) = message_storage_channel.send(StorageMessage{
-            message: x.text.clone(),
-            author: bot_name.to_string(),
-            channel: origin_channel,
-          }){
-            println!("Unable to send kobold generation to storage thread: {}", err);
+    }
+    return;
+  }
+  let messages_anchor = messages.first().copied();
+  let parts = messages.iter().skip(1).copied().collect();
+  if let Some(anchor) = messages_anchor{
+    let view = context.swipes.record(anchor, kobold_req.clone(), generation.clone(), messages);
+    show_swipe(&view, anchor, false).await;
+  }
+  if let Err(err) = context.storage_tx.send(StorageRequest::Store(StorageMessage{
+    message: generation,
+    author: kobold_req.persona.name,
+    channel: origin_channel,
+    guild: kobold_req.guild,
+    discord_id: messages_anchor,
+    parts,
+    user_id: None,
+    source: MessageSource::Text,
+    created_at: unix_now(),
+  })){
+    println!("Unable to send generation to storage thread: {}", err);
+  }
+}
+
+//a regeneration that didn't produce anything puts back the alternative it was writing over
+async fn restore_swipe(context: &WorkerContext, kobold_req: &KoboldRequest, messages: Vec<u64>){
+  let anchor = match kobold_req.regenerate{
+    Some(r) => r,
+    None => return,
+  };
+  context.swipes.set_messages(anchor, messages);
+  if let Some(view) = context.swipes.current(anchor){
+    let messages = show_swipe(&view, anchor, true).await;
+    context.swipes.set_messages(anchor, messages);
+  }
+}
+
+struct ChannelWorker{
+  tx: UnboundedSender<KoboldRequest>,
+  pending: Arc<AtomicUsize>,
+}
+
+//one worker per channel keeps each channel's replies in order, the generation slots keep the
+//backend from being swamped when several channels want a reply at the same time
+pub fn spawn_kobold_thread(message_storage_channel: UnboundedSender<StorageRequest>, generator: Arc<Generator>, generations: Arc<ActiveGenerations>, swipes: Arc<Swipes>) -> UnboundedSender<KoboldRequest>{
+  let (kobold_tx, mut kobold_rx) = tokio_channel::<KoboldRequest>();
+  let context = Arc::new(WorkerContext{
+    generator,
+    storage_tx: message_storage_channel,
+    generations,
+    swipes,
+    embeddings: EmbeddingClient::from_env(),
+    recall_limit: recall_limit(),
+    recall_window: recall_window(),
+  });
+  tokio::spawn(async move{
+    let mut workers: HashMap<u64, ChannelWorker> = HashMap::new();
+    while let Some(kobold_req) = kobold_rx.recv().await{
+      //this loop is the only sender, so a worker with nothing pending can't be handed anything
+      //mid shutdown and dropping its sender lets it finish
+      workers.retain(|_, worker| worker.pending.load(Ordering::SeqCst) > 0);
+      let worker = workers.entry(kobold_req.origin_channel).or_insert_with(|| {
+        let (tx, mut rx) = tokio_channel::<KoboldRequest>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let worker_pending = pending.clone();
+        let context = context.clone();
+        tokio::spawn(async move{
+          while let Some(kobold_req) = rx.recv().await{
+            handle_request(&context, kobold_req).await;
+            worker_pending.fetch_sub(1, Ordering::SeqCst);
           }
+        });
+        ChannelWorker{
+          tx,
+          pending,
         }
-      }else if res.status() == StatusCode::SERVICE_UNAVAILABLE{
-        let res_json: KoboldError = res.json().await.unwrap();
-        println!("KoboldCPP Server is busy");
-        println!("\tmsg: {}", res_json.msg);
-        println!("\ttype: {}", res_json.r#type);
-      }else{
-        println!("Something weird happened: {:?}", res);
+      });
+      worker.pending.fetch_add(1, Ordering::SeqCst);
+      if let Err(err) = worker.tx.send(kobold_req){
+        println!("Unable to hand request to channel worker: {}", err);
+        worker.pending.fetch_sub(1, Ordering::SeqCst);
       }
     }
   });
   kobold_tx
 }
+
+#[cfg(test)]
+mod tests{
+  use super::*;
+
+  #[test]
+  fn dropped_busy_ticket_leaves_the_queue(){
+    let queue = BusyQueue::default();
+    let first = queue.join();
+    let second = queue.join();
+    assert_eq!(second.position(), 2);
+    drop(first);
+    assert_eq!(second.position(), 1);
+    drop(second);
+    assert!(queue.waiting.lock().unwrap().is_empty());
+  }
+}
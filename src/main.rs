@@ -1,7 +1,25 @@
+pub mod backend;
+pub mod database;
 pub mod kobold;
+pub mod migrations;
+pub mod ollama;
+pub mod openai;
+pub mod postgres;
 pub mod discord;
+pub mod embeddings;
+pub mod encryption;
+pub mod facts;
 pub mod whisper;
 pub mod storage;
+pub mod prompt;
+pub mod retention;
+pub mod sqlite;
+pub mod persona;
+pub mod summary;
+pub mod swipe;
+pub mod tavern;
+pub mod template;
+pub mod transfer;
 
 use songbird::{driver::DecodeMode, Songbird};
 use poise::serenity_prelude as serenity;
@@ -11,15 +29,26 @@ use std::sync::Arc;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>>{
     dotenv::dotenv().ok();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = transfer::run_cli(&args){
+        return result.map_err(|err| err.into());
+    }
     std::env::var("DISCORD_TOKEN").expect("Expected DISCORD_TOKEN in the environment variables");
-    std::env::var("KOBOLD_URL").expect("Expected KOBOLD_URL in the environment variables");
+    backend::BackendKind::from_env()?.check_env()?;
+    database::DatabaseKind::from_env()?.check_env()?;
     std::env::var("WHISPER_URL").expect("Expected WHISPER_URL in the environment variables");
     std::env::var("ACTIVATION_PHRASE").expect("Expected ACTIVATION_PHRASE in the environment variables");
     std::env::var("BOT_NAME").expect("Expected BOT_NAME in the environment variables");
+    let generator = Arc::new(kobold::Generator::from_env()?);
+    let cipher = encryption::MessageCipher::from_env()?;
+    //the postgres client runs a runtime of its own, so it can't be connected from inside this one
+    let databases = tokio::task::spawn_blocking(move || database::Databases::open(cipher)).await?
+        .map_err(|err| format!("Not able to open the database: {}", err))?;
     tokio::spawn(async move {
         let token = std::env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
 
         let intents = GatewayIntents::GUILD_MESSAGES
+            | GatewayIntents::GUILD_MESSAGE_REACTIONS
             | GatewayIntents::DIRECT_MESSAGES
             | GatewayIntents::MESSAGE_CONTENT
             | GatewayIntents::GUILDS
@@ -32,7 +61,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
         let manager = Songbird::serenity_from_config(songbird_config);
         let clone_manager = Arc::clone(&manager);
         
-        let framework = discord::get_framework(clone_manager);
+        let framework = discord::get_framework(clone_manager, databases, generator);
 
         let mut client = serenity::Client::builder(&token, intents)
             .framework(framework)
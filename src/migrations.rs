@@ -0,0 +1,277 @@
+use rusqlite::Connection;
+
+struct Migration{
+  sqlite: &'static str,
+  postgres: &'static str,
+}
+
+//every entry upgrades the database by one version, written once for each server it can live in.
+//sqlite keeps the version reached in PRAGMA user_version, postgres in the schema_version table.
+//never edit an entry that has shipped, add a new one instead
+const MIGRATIONS: &[Migration] = &[
+  //1: the tables as they were before migrations existed, IF NOT EXISTS lets older databases pass through
+  Migration{
+    sqlite: "CREATE TABLE IF NOT EXISTS messages(
+      id INTEGER PRIMARY KEY,
+      author TEXT NOT NULL,
+      message TEXT NOT NULL,
+      channel INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS guild_personas(
+      guild INTEGER PRIMARY KEY,
+      persona TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sampler_presets(
+      guild INTEGER NOT NULL,
+      name TEXT NOT NULL,
+      settings TEXT NOT NULL,
+      PRIMARY KEY (guild, name)
+    );
+    CREATE TABLE IF NOT EXISTS sampler_assignments(
+      guild INTEGER NOT NULL,
+      channel INTEGER NOT NULL,
+      preset TEXT NOT NULL,
+      PRIMARY KEY (guild, channel)
+    );",
+    postgres: "CREATE TABLE IF NOT EXISTS messages(
+      id BIGSERIAL PRIMARY KEY,
+      author TEXT NOT NULL,
+      message TEXT NOT NULL,
+      channel BIGINT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS guild_personas(
+      guild BIGINT PRIMARY KEY,
+      persona TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sampler_presets(
+      guild BIGINT NOT NULL,
+      name TEXT NOT NULL,
+      settings TEXT NOT NULL,
+      PRIMARY KEY (guild, name)
+    );
+    CREATE TABLE IF NOT EXISTS sampler_assignments(
+      guild BIGINT NOT NULL,
+      channel BIGINT NOT NULL,
+      preset TEXT NOT NULL,
+      PRIMARY KEY (guild, channel)
+    );",
+  },
+  //2: where a message came from and when, rows from before this are left NULL since it was never recorded
+  Migration{
+    sqlite: "ALTER TABLE messages ADD COLUMN guild INTEGER;
+    ALTER TABLE messages ADD COLUMN discord_id INTEGER;
+    ALTER TABLE messages ADD COLUMN user_id INTEGER;
+    ALTER TABLE messages ADD COLUMN source TEXT NOT NULL DEFAULT 'text';
+    ALTER TABLE messages ADD COLUMN created_at INTEGER;
+    CREATE INDEX IF NOT EXISTS messages_channel ON messages(channel, id);
+    CREATE INDEX IF NOT EXISTS messages_discord_id ON messages(discord_id);",
+    postgres: "ALTER TABLE messages ADD COLUMN guild BIGINT;
+    ALTER TABLE messages ADD COLUMN discord_id BIGINT;
+    ALTER TABLE messages ADD COLUMN user_id BIGINT;
+    ALTER TABLE messages ADD COLUMN source TEXT NOT NULL DEFAULT 'text';
+    ALTER TABLE messages ADD COLUMN created_at BIGINT;
+    CREATE INDEX IF NOT EXISTS messages_channel ON messages(channel, id);
+    CREATE INDEX IF NOT EXISTS messages_discord_id ON messages(discord_id);",
+  },
+  //3: edits and deletions made on discord, a deleted row keeps its place but loses its text
+  Migration{
+    sqlite: "ALTER TABLE messages ADD COLUMN edited_at INTEGER;
+    ALTER TABLE messages ADD COLUMN deleted_at INTEGER;",
+    postgres: "ALTER TABLE messages ADD COLUMN edited_at BIGINT;
+    ALTER TABLE messages ADD COLUMN deleted_at BIGINT;",
+  },
+  //4: rolling summaries, the newest one per channel covers every row up to last_message
+  Migration{
+    sqlite: "CREATE TABLE IF NOT EXISTS summaries(
+      id INTEGER PRIMARY KEY,
+      channel INTEGER NOT NULL,
+      last_message INTEGER NOT NULL,
+      summary TEXT NOT NULL,
+      created_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS summaries_channel ON summaries(channel, last_message);",
+    postgres: "CREATE TABLE IF NOT EXISTS summaries(
+      id BIGSERIAL PRIMARY KEY,
+      channel BIGINT NOT NULL,
+      last_message BIGINT NOT NULL,
+      summary TEXT NOT NULL,
+      created_at BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS summaries_channel ON summaries(channel, last_message);",
+  },
+  //5: one embedding per message for semantic recall, vectors are little endian f32s
+  Migration{
+    sqlite: "CREATE TABLE IF NOT EXISTS embeddings(
+      message INTEGER PRIMARY KEY,
+      guild INTEGER NOT NULL,
+      model TEXT NOT NULL,
+      vector BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS embeddings_guild ON embeddings(guild, model);",
+    postgres: "CREATE TABLE IF NOT EXISTS embeddings(
+      message BIGINT PRIMARY KEY,
+      guild BIGINT NOT NULL,
+      model TEXT NOT NULL,
+      vector BYTEA NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS embeddings_guild ON embeddings(guild, model);",
+  },
+  //6: full text index over messages, kept in step by triggers so every writer stays oblivious to it
+  Migration{
+    sqlite: "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(author, message, content='messages', content_rowid='id');
+    CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+      INSERT INTO messages_fts(rowid, author, message) VALUES (new.id, new.author, new.message);
+    END;
+    CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+      INSERT INTO messages_fts(messages_fts, rowid, author, message) VALUES ('delete', old.id, old.author, old.message);
+    END;
+    CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF author, message ON messages BEGIN
+      INSERT INTO messages_fts(messages_fts, rowid, author, message) VALUES ('delete', old.id, old.author, old.message);
+      INSERT INTO messages_fts(rowid, author, message) VALUES (new.id, new.author, new.message);
+    END;
+    INSERT INTO messages_fts(messages_fts) VALUES ('rebuild');",
+    postgres: "ALTER TABLE messages ADD COLUMN search TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', author || ' ' || message)) STORED;
+    CREATE INDEX IF NOT EXISTS messages_search ON messages USING GIN(search);",
+  },
+  //7: how long each guild keeps its history, guilds without a row keep everything
+  Migration{
+    sqlite: "CREATE TABLE IF NOT EXISTS retention_policies(
+      guild INTEGER PRIMARY KEY,
+      max_age_days INTEGER,
+      max_rows_per_channel INTEGER,
+      keep_summaries_only INTEGER NOT NULL DEFAULT 0
+    );",
+    postgres: "CREATE TABLE IF NOT EXISTS retention_policies(
+      guild BIGINT PRIMARY KEY,
+      max_age_days INTEGER,
+      max_rows_per_channel INTEGER,
+      keep_summaries_only BOOLEAN NOT NULL DEFAULT FALSE
+    );",
+  },
+  //8: what the bot has picked up about each member, per guild so nothing carries over between servers
+  Migration{
+    sqlite: "CREATE TABLE IF NOT EXISTS user_facts(
+      id INTEGER PRIMARY KEY,
+      guild INTEGER NOT NULL,
+      user_id INTEGER NOT NULL,
+      fact TEXT NOT NULL,
+      created_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS user_facts_user ON user_facts(guild, user_id);",
+    postgres: "CREATE TABLE IF NOT EXISTS user_facts(
+      id BIGSERIAL PRIMARY KEY,
+      guild BIGINT NOT NULL,
+      user_id BIGINT NOT NULL,
+      fact TEXT NOT NULL,
+      created_at BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS user_facts_user ON user_facts(guild, user_id);",
+  },
+  //9: the later discord messages a long reply spilled into, so deleting any of them reaches the reply's row
+  Migration{
+    sqlite: "CREATE TABLE IF NOT EXISTS message_parts(
+      discord_id INTEGER PRIMARY KEY,
+      message INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS message_parts_message ON message_parts(message);",
+    postgres: "CREATE TABLE IF NOT EXISTS message_parts(
+      discord_id BIGINT PRIMARY KEY,
+      message BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS message_parts_message ON message_parts(message);",
+  },
+  //10: recall only looks at a guild's newest embeddings. sqlite's embeddings_guild already ends in the
+  //rowid, which is the message
+  Migration{
+    sqlite: "",
+    postgres: "CREATE INDEX IF NOT EXISTS embeddings_recent ON embeddings(guild, model, message);",
+  },
+  //11: encrypted texts (the ones starting with enc:) can't be matched, so they're kept out of the full
+  //text index instead of bloating it. the index is filled again from the plain text rows only
+  Migration{
+    sqlite: "DROP TRIGGER IF EXISTS messages_fts_insert;
+    DROP TRIGGER IF EXISTS messages_fts_delete;
+    DROP TRIGGER IF EXISTS messages_fts_update;
+    CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+      INSERT INTO messages_fts(rowid, author, message) SELECT new.id, new.author, new.message WHERE new.message NOT GLOB 'enc:*';
+    END;
+    CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+      INSERT INTO messages_fts(messages_fts, rowid, author, message) SELECT 'delete', old.id, old.author, old.message WHERE old.message NOT GLOB 'enc:*';
+    END;
+    CREATE TRIGGER messages_fts_update AFTER UPDATE OF author, message ON messages BEGIN
+      INSERT INTO messages_fts(messages_fts, rowid, author, message) SELECT 'delete', old.id, old.author, old.message WHERE old.message NOT GLOB 'enc:*';
+      INSERT INTO messages_fts(rowid, author, message) SELECT new.id, new.author, new.message WHERE new.message NOT GLOB 'enc:*';
+    END;
+    INSERT INTO messages_fts(messages_fts) VALUES ('delete-all');
+    INSERT INTO messages_fts(rowid, author, message) SELECT id, author, message FROM messages WHERE message NOT GLOB 'enc:*';",
+    postgres: "ALTER TABLE messages DROP COLUMN search;
+    ALTER TABLE messages ADD COLUMN search TSVECTOR GENERATED ALWAYS AS (
+      CASE WHEN message LIKE 'enc:%' THEN NULL ELSE to_tsvector('simple', author || ' ' || message) END
+    ) STORED;
+    CREATE INDEX IF NOT EXISTS messages_search ON messages USING GIN(search);",
+  },
+];
+
+//brings a sqlite file up to the newest schema, each step commits on its own so a failure
+//leaves the database at the last version that fully applied
+pub fn migrate_sqlite(conn: &mut Connection) -> rusqlite::Result<()>{
+  let name = conn.path().unwrap_or("memory.db").to_string();
+  let current: usize = conn.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+  //a brand new database picks up the vacuum mode straight away, before any table exists
+  if current == 0{
+    conn.execute_batch("PRAGMA auto_vacuum=INCREMENTAL;")?;
+  }
+  if current > MIGRATIONS.len(){
+    println!("{} is at schema version {} but this build only knows up to {}, continuing anyway", name, current, MIGRATIONS.len());
+    return Ok(());
+  }
+  for (version, migration) in MIGRATIONS.iter().enumerate().skip(current){
+    let tx = conn.transaction()?;
+    tx.execute_batch(migration.sqlite)?;
+    tx.pragma_update(None, "user_version", version + 1)?;
+    tx.commit()?;
+    println!("Upgraded {} to schema version {}", name, version + 1);
+  }
+  enable_incremental_vacuum(conn, &name)
+}
+
+//the same steps for postgres. shards can start at the same time, so the whole upgrade runs under
+//an advisory lock and the version is read again once it's held
+pub fn migrate_postgres(client: &mut postgres::Client) -> Result<(), postgres::Error>{
+  client.batch_execute(
+    "SELECT pg_advisory_lock(hashtext('lily_migrations'));
+    CREATE TABLE IF NOT EXISTS schema_version(version INTEGER NOT NULL);
+    INSERT INTO schema_version (version) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM schema_version);"
+  )?;
+  let result = apply_postgres_migrations(client);
+  client.batch_execute("SELECT pg_advisory_unlock(hashtext('lily_migrations'));")?;
+  result
+}
+
+fn apply_postgres_migrations(client: &mut postgres::Client) -> Result<(), postgres::Error>{
+  let current: i32 = client.query_one("SELECT version FROM schema_version", &[])?.get(0);
+  let current = current as usize;
+  if current > MIGRATIONS.len(){
+    println!("The postgres database is at schema version {} but this build only knows up to {}, continuing anyway", current, MIGRATIONS.len());
+    return Ok(());
+  }
+  for (version, migration) in MIGRATIONS.iter().enumerate().skip(current){
+    let mut tx = client.transaction()?;
+    tx.batch_execute(migration.postgres)?;
+    tx.execute("UPDATE schema_version SET version=$1", &[&(version as i32 + 1)])?;
+    tx.commit()?;
+    println!("Upgraded the postgres database to schema version {}", version + 1);
+  }
+  Ok(())
+}
+
+//pruned pages are only handed back to the filesystem in incremental mode, which an existing database
+//only switches to after one full VACUUM, and that can't run inside a transaction
+fn enable_incremental_vacuum(conn: &Connection, name: &str) -> rusqlite::Result<()>{
+  let mode: i64 = conn.query_row("PRAGMA auto_vacuum", (), |row| row.get(0))?;
+  if mode != 2{
+    println!("Switching {} to incremental vacuum, this rewrites the database once", name);
+    conn.execute_batch("PRAGMA auto_vacuum=INCREMENTAL; VACUUM;")?;
+  }
+  Ok(())
+}
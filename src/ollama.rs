@@ -0,0 +1,161 @@
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::{
+  Serialize,
+  Deserialize,
+};
+
+use crate::backend::{
+  build_chat_messages,
+  drain_json_lines,
+  endpoint,
+  streaming_client,
+  ChatMessage,
+  GenerationBackend,
+  GenerationError,
+  GenerationRequest,
+  SamplerSettings,
+  TokenSink,
+};
+
+#[derive(Serialize)]
+struct OllamaChatRequest{
+  model: String,
+  messages: Vec<ChatMessage>,
+  stream: bool,
+  options: OllamaOptions,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions{
+  temperature: f32,
+  top_p: f32,
+  top_k: i32,
+  min_p: f32,
+  typical_p: f32,
+  repeat_penalty: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  repeat_last_n: Option<i32>,
+  frequency_penalty: f32,
+  presence_penalty: f32,
+  mirostat: u32,
+  mirostat_tau: f32,
+  mirostat_eta: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  seed: Option<i32>,
+  num_predict: i32,
+  num_ctx: u32,
+}
+
+impl OllamaOptions{
+  fn new(settings: &SamplerSettings) -> Self{
+    Self{
+      temperature: settings.temperature,
+      top_p: settings.top_p,
+      top_k: settings.top_k,
+      min_p: settings.min_p,
+      typical_p: settings.typical_p,
+      repeat_penalty: settings.repetition_penalty,
+      //without a range ollama keeps its own default window, 0 would turn the penalty off
+      repeat_last_n: Some(settings.repetition_penalty_range as i32).filter(|range| *range > 0),
+      frequency_penalty: settings.frequency_penalty,
+      presence_penalty: settings.presence_penalty,
+      mirostat: settings.mirostat_mode,
+      mirostat_tau: settings.mirostat_tau,
+      mirostat_eta: settings.mirostat_eta,
+      //ollama picks a random seed on its own when none is given
+      seed: Some(settings.seed).filter(|seed| *seed >= 0),
+      num_predict: settings.max_length as i32,
+      num_ctx: settings.context_length,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct OllamaChatChunk{
+  message: Option<OllamaChunkMessage>,
+  #[serde(default)]
+  done: bool,
+  error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OllamaChunkMessage{
+  content: String,
+}
+
+pub struct OllamaBackend{
+  model: String,
+}
+
+impl OllamaBackend{
+  //OLLAMA_MODEL is checked at startup by BackendKind::check_env
+  pub fn from_env() -> Self{
+    Self{
+      model: std::env::var("OLLAMA_MODEL").unwrap_or_default(),
+    }
+  }
+}
+
+#[async_trait]
+impl GenerationBackend for OllamaBackend{
+  fn name(&self) -> &'static str{
+    "Ollama"
+  }
+
+  async fn generate(&self, request: &GenerationRequest, sink: &mut dyn TokenSink) -> Result<String, GenerationError>{
+    let data = OllamaChatRequest{
+      model: self.model.clone(),
+      messages: build_chat_messages(request).await,
+      stream: true,
+      options: OllamaOptions::new(&request.settings),
+    };
+    let client = streaming_client("application/x-ndjson")?;
+    let mut res = client.post(endpoint("OLLAMA_URL", "api/chat"))
+      .json(&data)
+      .send()
+      .await
+      .map_err(|err| GenerationError::Unreachable(err.to_string()))?;
+
+    //ollama answers 503 once OLLAMA_MAX_QUEUE is full
+    if res.status() == StatusCode::SERVICE_UNAVAILABLE{
+      return Err(GenerationError::Busy(res.text().await.unwrap_or_default()));
+    }else if res.status() != StatusCode::OK{
+      return Err(GenerationError::Failed(format!("Something weird happened: {:?}", res)));
+    }
+
+    let mut generation = String::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    loop{
+      let chunk = match res.chunk().await{
+        Ok(Some(r)) => r,
+        Ok(None) => break,
+        Err(err) => {
+          println!("Ollama stream was interrupted: {}", err);
+          break;
+        }
+      };
+      buffer.extend_from_slice(&chunk);
+      for line in drain_json_lines(&mut buffer){
+        let chunk: OllamaChatChunk = match serde_json::from_str(&line){
+          Ok(r) => r,
+          Err(err) => {
+            println!("Unable to parse chunk from ollama stream: {}", err);
+            continue;
+          }
+        };
+        if let Some(err) = chunk.error{
+          return Err(GenerationError::Failed(err));
+        }
+        if let Some(message) = chunk.message{
+          sink.push(&message.content).await;
+          generation.push_str(&message.content);
+        }
+        if chunk.done{
+          return Ok(generation);
+        }
+      }
+    }
+    Ok(generation)
+  }
+}
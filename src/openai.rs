@@ -0,0 +1,128 @@
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::{
+  Serialize,
+  Deserialize,
+};
+
+use crate::backend::{
+  build_chat_messages,
+  drain_sse_events,
+  endpoint,
+  streaming_client,
+  ChatMessage,
+  GenerationBackend,
+  GenerationError,
+  GenerationRequest,
+  TokenSink,
+};
+
+#[derive(Serialize)]
+struct ChatCompletionRequest{
+  model: String,
+  messages: Vec<ChatMessage>,
+  stream: bool,
+  max_tokens: u32,
+  temperature: f32,
+  top_p: f32,
+  frequency_penalty: f32,
+  presence_penalty: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  seed: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionChunk{
+  choices: Vec<ChatCompletionChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionChoice{
+  delta: ChatCompletionDelta,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionDelta{
+  content: Option<String>,
+}
+
+//anything that speaks /v1/chat/completions, llama.cpp server, vllm, ollama and lm studio all do
+pub struct OpenAiBackend{
+  model: String,
+  api_key: Option<String>,
+}
+
+impl OpenAiBackend{
+  pub fn from_env() -> Self{
+    Self{
+      model: std::env::var("OPENAI_MODEL").unwrap_or_default(),
+      api_key: std::env::var("OPENAI_API_KEY").ok().filter(|key| !key.is_empty()),
+    }
+  }
+}
+
+#[async_trait]
+impl GenerationBackend for OpenAiBackend{
+  fn name(&self) -> &'static str{
+    "OpenAI compatible"
+  }
+
+  async fn generate(&self, request: &GenerationRequest, sink: &mut dyn TokenSink) -> Result<String, GenerationError>{
+    let data = ChatCompletionRequest{
+      model: self.model.clone(),
+      messages: build_chat_messages(request).await,
+      stream: true,
+      max_tokens: request.settings.max_length,
+      temperature: request.settings.temperature,
+      top_p: request.settings.top_p,
+      frequency_penalty: request.settings.frequency_penalty,
+      presence_penalty: request.settings.presence_penalty,
+      seed: Some(request.settings.seed).filter(|seed| *seed >= 0),
+    };
+    let client = streaming_client("text/event-stream")?;
+    let mut req = client.post(endpoint("OPENAI_URL", "v1/chat/completions")).json(&data);
+    if let Some(key) = &self.api_key{
+      req = req.bearer_auth(key);
+    }
+    let mut res = req.send()
+      .await
+      .map_err(|err| GenerationError::Unreachable(err.to_string()))?;
+
+    if res.status() == StatusCode::SERVICE_UNAVAILABLE || res.status() == StatusCode::TOO_MANY_REQUESTS{
+      return Err(GenerationError::Busy(res.text().await.unwrap_or_default()));
+    }else if res.status() != StatusCode::OK{
+      return Err(GenerationError::Failed(format!("Something weird happened: {:?}", res)));
+    }
+
+    let mut generation = String::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    loop{
+      let chunk = match res.chunk().await{
+        Ok(Some(r)) => r,
+        Ok(None) => break,
+        Err(err) => {
+          println!("Chat completion stream was interrupted: {}", err);
+          break;
+        }
+      };
+      buffer.extend_from_slice(&chunk);
+      for payload in drain_sse_events(&mut buffer){
+        if payload == "[DONE]"{
+          return Ok(generation);
+        }
+        let chunk: ChatCompletionChunk = match serde_json::from_str(&payload){
+          Ok(r) => r,
+          Err(err) => {
+            println!("Unable to parse chunk from chat completion stream: {}", err);
+            continue;
+          }
+        };
+        for token in chunk.choices.into_iter().filter_map(|choice| choice.delta.content){
+          sink.push(&token).await;
+          generation.push_str(&token);
+        }
+      }
+    }
+    Ok(generation)
+  }
+}
@@ -0,0 +1,137 @@
+use std::{
+  collections::HashMap,
+  path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::tavern::{
+  is_card_json,
+  parse_card_json,
+  parse_card_png,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Persona{
+  pub name: String,
+  pub description: String,
+  #[serde(default)]
+  pub personality: String,
+  #[serde(default)]
+  pub scenario: String,
+  //replaces the generated system prompt, {{original}} inside it stands for what would have been generated
+  #[serde(default)]
+  pub system_prompt: String,
+  #[serde(default)]
+  pub example_dialogue: String,
+  #[serde(default)]
+  pub greeting: String,
+}
+
+impl Persona{
+  //what the bot is when a guild hasn't picked anything, named after BOT_NAME
+  pub fn default_from_env() -> Self{
+    let name = std::env::var("BOT_NAME").unwrap();
+    Self{
+      description: format!("You are a discord bot named {}. You are speaking to the members of the server and will help them with whatever they ask.", name),
+      name,
+      personality: String::new(),
+      scenario: String::new(),
+      system_prompt: String::new(),
+      example_dialogue: String::new(),
+      greeting: String::new(),
+    }
+  }
+
+  pub fn system_prompt(&self) -> String{
+    let mut prompt = self.description.clone();
+    if !self.personality.trim().is_empty(){
+      prompt.push_str(&format!("\n\n{}'s personality: {}", self.name, self.personality));
+    }
+    if !self.scenario.trim().is_empty(){
+      prompt.push_str(&format!("\n\nScenario: {}", self.scenario));
+    }
+    if !self.system_prompt.trim().is_empty(){
+      prompt = self.system_prompt.replace("{{original}}", &prompt);
+    }
+    if !self.example_dialogue.trim().is_empty(){
+      prompt.push_str("\n\nExample dialogue:\n");
+      prompt.push_str(&self.example_dialogue);
+    }
+    prompt
+  }
+}
+
+pub struct PersonaLibrary{
+  personas: HashMap<String, Persona>,
+  default: Persona,
+}
+
+impl PersonaLibrary{
+  //every .json file in PERSONA_DIR (./personas when unset) is one persona, looked up by its name,
+  //tavern character cards can sit next to them as either .json or .png
+  pub fn load() -> Self{
+    let dir = std::env::var("PERSONA_DIR")
+      .ok()
+      .filter(|dir| !dir.trim().is_empty())
+      .unwrap_or("./personas".to_string());
+    let mut personas = HashMap::new();
+    match std::fs::read_dir(&dir){
+      Ok(entries) => {
+        for entry in entries.flatten(){
+          let path = entry.path();
+          match Self::load_file(&path){
+            Some(Ok(persona)) => {
+              personas.insert(persona.name.to_lowercase(), persona);
+            },
+            Some(Err(err)) => println!("{}", err),
+            None => {},
+          }
+        }
+      },
+      Err(err) => println!("Unable to read persona directory {}, only the default persona is available: {}", dir, err),
+    }
+    Self{
+      personas,
+      default: Persona::default_from_env(),
+    }
+  }
+
+  //None for files that aren't personas at all
+  fn load_file(path: &Path) -> Option<Result<Persona, String>>{
+    let extension = path.extension().and_then(|ext| ext.to_str())?.to_lowercase();
+    if extension != "json" && extension != "png"{
+      return None;
+    }
+    let contents = match std::fs::read(path){
+      Ok(r) => r,
+      Err(err) => return Some(Err(format!("Unable to read persona file {}: {}", path.display(), err))),
+    };
+    let persona = if extension == "png"{
+      parse_card_png(&contents)
+    }else{
+      let json = String::from_utf8_lossy(&contents);
+      if is_card_json(&json){
+        parse_card_json(&json)
+      }else{
+        serde_json::from_str(&json).map_err(|err| err.to_string())
+      }
+    };
+    Some(persona.map_err(|err| format!("Persona file {} is not valid: {}", path.display(), err)))
+  }
+
+  pub fn get(&self, name: &str) -> Option<&Persona>{
+    self.personas.get(&name.to_lowercase())
+  }
+
+  //falls back to the default persona when nothing was picked or the pick no longer exists
+  pub fn resolve(&self, name: Option<&str>) -> &Persona{
+    name.and_then(|name| self.get(name)).unwrap_or(&self.default)
+  }
+
+  pub fn names(&self) -> Vec<String>{
+    let mut names: Vec<String> = self.personas.values().map(|persona| persona.name.clone()).collect();
+    names.sort();
+    names
+  }
+}
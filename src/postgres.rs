@@ -0,0 +1,752 @@
+use std::collections::{
+  BTreeSet,
+  HashMap,
+};
+
+use ::postgres::{
+  types::ToSql,
+  Client,
+  Row,
+  Statement,
+};
+use native_tls::{
+  Certificate,
+  TlsConnector,
+};
+use postgres_native_tls::MakeTlsConnector;
+
+use crate::{
+  database::{
+    database_url,
+    Database,
+    DatabaseError,
+    DatabaseResult,
+    TextColumn,
+  },
+  embeddings::{
+    blob_to_vector,
+    cosine_similarity,
+    vector_to_blob,
+    EmbeddingJob,
+  },
+  kobold::StoredMessage,
+  migrations::migrate_postgres,
+  retention::{
+    PruneReport,
+    RetentionPolicy,
+    ANONYMOUS_AUTHOR,
+    DAY_SECONDS,
+  },
+  storage::{
+    unix_now,
+    RecalledMessage,
+    SearchFilter,
+    SearchHit,
+    StorageMessage,
+    GUILD_WIDE,
+  },
+  transfer::{
+    ExportRecord,
+    ExportScope,
+    ImportedMessage,
+  },
+};
+
+//advisory lock key the shards agree on for pruning, any number works as long as it's the same everywhere
+const MAINTENANCE_LOCK: i64 = 0x6c696c79;
+
+//whether TLS is used follows sslmode in DATABASE_URL, prefer when it isn't given. DATABASE_CA_FILE trusts the
+//pem certificate of a private CA on top of the system's
+fn tls_connector() -> DatabaseResult<MakeTlsConnector>{
+  let mut builder = TlsConnector::builder();
+  if let Some(path) = std::env::var("DATABASE_CA_FILE").ok().filter(|path| !path.trim().is_empty()){
+    let certificate = Certificate::from_pem(&std::fs::read(&path)?)
+      .map_err(|err| DatabaseError::Config(format!("DATABASE_CA_FILE {} is not a pem certificate: {}", path, err)))?;
+    builder.add_root_certificate(certificate);
+  }
+  let connector = builder.build().map_err(|err| DatabaseError::Config(format!("Can't set up TLS for postgres: {}", err)))?;
+  Ok(MakeTlsConnector::new(connector))
+}
+
+//postgres has no unsigned BIGINT, so discord ids are stored bit for bit as i64 and unsigned_id turns them back.
+//snowflakes pass i64::MAX around 2084 and are stored negative from then on, ids are only ever compared for
+//equality so the one thing that changes is the order channels are listed in an export
+fn id(value: u64) -> i64{
+  value as i64
+}
+
+fn unsigned_id(value: i64) -> u64{
+  value as u64
+}
+
+fn optional_id(value: Option<u64>) -> Option<i64>{
+  value.map(id)
+}
+
+fn get_id(row: &Row, index: usize) -> u64{
+  unsigned_id(row.get(index))
+}
+
+fn get_optional_id(row: &Row, index: usize) -> Option<u64>{
+  row.get::<_, Option<i64>>(index).map(unsigned_id)
+}
+
+fn stored_message(row: &Row, first: usize) -> StoredMessage{
+  StoredMessage{
+    author: row.get(first),
+    message: row.get(first + 1),
+  }
+}
+
+fn delete_rows_in(tx: &mut ::postgres::Transaction, ids: &BTreeSet<i64>) -> Result<(), ::postgres::Error>{
+  let ids: Vec<i64> = ids.iter().copied().collect();
+  tx.execute("DELETE FROM embeddings WHERE message=ANY($1)", &[&ids])?;
+  tx.execute("DELETE FROM message_parts WHERE message=ANY($1)", &[&ids])?;
+  tx.execute("DELETE FROM messages WHERE id=ANY($1)", &[&ids])?;
+  Ok(())
+}
+
+fn message_filter(scope: ExportScope) -> (&'static str, i64){
+  match scope{
+    ExportScope::Channel(channel) => ("channel=$1", id(channel)),
+    ExportScope::Guild(guild) => ("guild=$1", id(guild)),
+  }
+}
+
+//rows keyed by channel only belong to a guild when its messages are there and no other guild's are,
+//an imported file could otherwise claim a channel of another guild
+fn channel_filter(scope: ExportScope) -> (&'static str, i64){
+  match scope{
+    ExportScope::Channel(channel) => ("channel=$1", id(channel)),
+    ExportScope::Guild(guild) => ("channel IN (SELECT channel FROM messages WHERE guild=$1 EXCEPT SELECT channel FROM messages WHERE guild<>$1)", id(guild)),
+  }
+}
+
+//shared memory for several bot shards, every shard points DATABASE_URL at the same server
+pub struct PostgresDatabase{
+  client: Client,
+  statements: HashMap<&'static str, Statement>,
+  //a transaction opened with begin doesn't survive a reconnect, so nothing in one is retried
+  in_transaction: bool,
+}
+
+impl PostgresDatabase{
+  //the connection that storage writes through also brings the schema up to date
+  pub fn connect(migrate: bool) -> DatabaseResult<Self>{
+    let mut client = Client::connect(&database_url()?, tls_connector()?)?;
+    if migrate{
+      migrate_postgres(&mut client)?;
+    }
+    Ok(Self{
+      client,
+      statements: HashMap::new(),
+      in_transaction: false,
+    })
+  }
+
+  //a restarted server or a network blip closes the connection for good, statements prepared on it go with it
+  fn reconnect(&mut self) -> DatabaseResult<()>{
+    self.client = Client::connect(&database_url()?, tls_connector()?)?;
+    self.statements.clear();
+    Ok(())
+  }
+
+  //runs the call and runs it once more on a new connection when the old one turns out to be gone
+  fn retrying<T>(&mut self, mut call: impl FnMut(&mut Self) -> Result<T, ::postgres::Error>) -> DatabaseResult<T>{
+    if self.client.is_closed() && !self.in_transaction{
+      self.reconnect()?;
+    }
+    match call(self){
+      Err(err) if err.is_closed() && !self.in_transaction => {
+        println!("Lost the connection to postgres, reconnecting: {}", err);
+        self.reconnect()?;
+        Ok(call(self)?)
+      },
+      other => Ok(other?),
+    }
+  }
+
+  //the whole transaction is what gets retried, a connection that dies before the commit rolls it back
+  fn transaction<T>(&mut self, mut work: impl FnMut(&mut ::postgres::Transaction) -> Result<T, ::postgres::Error>) -> DatabaseResult<T>{
+    self.retrying(|db|{
+      let mut tx = db.client.transaction()?;
+      let result = work(&mut tx)?;
+      tx.commit()?;
+      Ok(result)
+    })
+  }
+
+  //prepared once per connection, like sqlite's statement cache
+  fn statement(&mut self, sql: &'static str) -> Result<Statement, ::postgres::Error>{
+    if let Some(statement) = self.statements.get(sql){
+      return Ok(statement.clone());
+    }
+    let statement = self.client.prepare(sql)?;
+    self.statements.insert(sql, statement.clone());
+    Ok(statement)
+  }
+
+  fn query(&mut self, sql: &'static str, params: &[&(dyn ToSql + Sync)]) -> DatabaseResult<Vec<Row>>{
+    self.retrying(|db|{
+      let statement = db.statement(sql)?;
+      db.client.query(&statement, params)
+    })
+  }
+
+  fn query_opt(&mut self, sql: &'static str, params: &[&(dyn ToSql + Sync)]) -> DatabaseResult<Option<Row>>{
+    self.retrying(|db|{
+      let statement = db.statement(sql)?;
+      db.client.query_opt(&statement, params)
+    })
+  }
+
+  fn query_one(&mut self, sql: &'static str, params: &[&(dyn ToSql + Sync)]) -> DatabaseResult<Row>{
+    self.retrying(|db|{
+      let statement = db.statement(sql)?;
+      db.client.query_one(&statement, params)
+    })
+  }
+
+  fn execute(&mut self, sql: &'static str, params: &[&(dyn ToSql + Sync)]) -> DatabaseResult<u64>{
+    self.retrying(|db|{
+      let statement = db.statement(sql)?;
+      db.client.execute(&statement, params)
+    })
+  }
+
+  fn matching_ids(&mut self, sql: &'static str, params: &[&(dyn ToSql + Sync)]) -> DatabaseResult<Vec<i64>>{
+    Ok(self.query(sql, params)?.iter().map(|row| row.get(0)).collect())
+  }
+}
+
+impl Database for PostgresDatabase{
+  fn insert_messages(&mut self, messages: &[StorageMessage]) -> DatabaseResult<Vec<i64>>{
+    self.retrying(|db|{
+      let statement = db.statement(
+        "INSERT INTO messages (author, message, channel, guild, discord_id, user_id, source, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id"
+      )?;
+      let part_statement = db.statement(
+        "INSERT INTO message_parts (discord_id, message) VALUES ($1, $2) ON CONFLICT (discord_id) DO UPDATE SET message=excluded.message"
+      )?;
+      let mut tx = db.client.transaction()?;
+      let mut ids = Vec::with_capacity(messages.len());
+      for message in messages{
+        let row: i64 = tx.query_one(&statement, &[
+          &message.author,
+          &message.message,
+          &id(message.channel),
+          &optional_id(message.guild),
+          &optional_id(message.discord_id),
+          &optional_id(message.user_id),
+          &message.source.as_str(),
+          &message.created_at,
+        ])?.get(0);
+        for part in &message.parts{
+          tx.execute(&part_statement, &[&id(*part), &row])?;
+        }
+        ids.push(row);
+      }
+      tx.commit()?;
+      Ok(ids)
+    })
+  }
+
+  fn context(&mut self, channel: u64, after: i64, until: i64, limit: u32) -> DatabaseResult<Vec<StoredMessage>>{
+    //newest rows first so the cap drops the oldest history
+    let rows = self.query(
+      "SELECT author, message FROM messages WHERE channel=$1 AND id>$2 AND id<=$4 AND deleted_at IS NULL ORDER BY id DESC LIMIT $3",
+      &[&id(channel), &after, &(limit as i64), &until],
+    )?;
+    Ok(rows.iter().rev().map(|row| stored_message(row, 0)).collect())
+  }
+
+  fn unsummarized(&mut self, channel: u64, after: i64, limit: u32) -> DatabaseResult<Vec<(i64, StoredMessage)>>{
+    let rows = self.query(
+      "SELECT id, author, message FROM messages WHERE channel=$1 AND id>$2 AND deleted_at IS NULL ORDER BY id ASC LIMIT $3",
+      &[&id(channel), &after, &(limit as i64)],
+    )?;
+    Ok(rows.iter().map(|row| (row.get(0), stored_message(row, 1))).collect())
+  }
+
+  fn member_messages(&mut self, guild: u64, user_id: u64, limit: usize) -> DatabaseResult<Vec<String>>{
+    let rows = self.query(
+      "SELECT message FROM messages WHERE guild=$1 AND user_id=$2 AND deleted_at IS NULL ORDER BY id DESC LIMIT $3",
+      &[&id(guild), &id(user_id), &(limit as i64)],
+    )?;
+    Ok(rows.iter().rev().map(|row| row.get(0)).collect())
+  }
+
+  fn replace_bot_message(&mut self, discord_id: u64, message: &str, parts: &[u64]) -> DatabaseResult<()>{
+    let parts: Vec<i64> = parts.iter().map(|part| id(*part)).collect();
+    self.transaction(|tx|{
+      let row = tx.query_opt("SELECT id FROM messages WHERE discord_id=$1 AND deleted_at IS NULL LIMIT 1", &[&id(discord_id)])?;
+      if let Some(row) = row{
+        let row: i64 = row.get(0);
+        tx.execute("UPDATE messages SET message=$2 WHERE id=$1", &[&row, &message])?;
+        tx.execute("DELETE FROM message_parts WHERE message=$1", &[&row])?;
+        tx.execute(
+          "INSERT INTO message_parts (discord_id, message) SELECT part, $2 FROM unnest($1::BIGINT[]) part
+          ON CONFLICT (discord_id) DO UPDATE SET message=excluded.message",
+          &[&parts, &row],
+        )?;
+      }
+      Ok(())
+    })
+  }
+
+  fn edit_message(&mut self, discord_id: u64, message: &str, edited_at: i64) -> DatabaseResult<()>{
+    self.execute(
+      "UPDATE messages SET message=$2, edited_at=$3 WHERE discord_id=$1 AND user_id IS NOT NULL AND deleted_at IS NULL",
+      &[&id(discord_id), &message, &edited_at],
+    )?;
+    Ok(())
+  }
+
+  fn delete_messages(&mut self, discord_ids: &[u64], deleted_at: i64) -> DatabaseResult<()>{
+    let discord_ids: Vec<i64> = discord_ids.iter().map(|discord_id| id(*discord_id)).collect();
+    //a bulk purge lands all at once or not at all
+    self.transaction(|tx|{
+      let ids: Vec<i64> = tx.query(
+        "SELECT id FROM messages WHERE discord_id=ANY($1) UNION SELECT message FROM message_parts WHERE discord_id=ANY($1)",
+        &[&discord_ids],
+      )?.iter().map(|row| row.get(0)).collect();
+      tx.execute("DELETE FROM embeddings WHERE message=ANY($1)", &[&ids])?;
+      tx.execute(
+        "UPDATE messages SET message='', deleted_at=$2 WHERE id=ANY($1) AND deleted_at IS NULL",
+        &[&ids, &deleted_at],
+      )?;
+      Ok(())
+    })
+  }
+
+  fn embedding_job(&mut self, discord_id: u64) -> DatabaseResult<Option<EmbeddingJob>>{
+    let row = self.query_opt(
+      "SELECT id, guild, message FROM messages WHERE discord_id=$1 AND guild IS NOT NULL AND deleted_at IS NULL LIMIT 1",
+      &[&id(discord_id)],
+    )?;
+    Ok(row.map(|row| EmbeddingJob{
+      message: row.get(0),
+      guild: get_id(&row, 1),
+      text: row.get(2),
+    }).filter(|job| !job.text.trim().is_empty()))
+  }
+
+  fn store_embedding(&mut self, message: i64, guild: u64, model: &str, vector: &[f32]) -> DatabaseResult<()>{
+    self.execute(
+      "INSERT INTO embeddings (message, guild, model, vector) VALUES ($1, $2, $3, $4)
+      ON CONFLICT (message) DO UPDATE SET guild=EXCLUDED.guild, model=EXCLUDED.model, vector=EXCLUDED.vector",
+      &[&message, &id(guild), &model, &vector_to_blob(vector)],
+    )?;
+    Ok(())
+  }
+
+  //brute force like sqlite, the vectors are scored here rather than on the server
+  fn recall(&mut self, guild: u64, model: &str, vector: &[f32], limit: usize, window: u32) -> DatabaseResult<Vec<RecalledMessage>>{
+    let rows = self.query(
+      "SELECT e.vector, m.author, m.message, m.created_at FROM embeddings e JOIN messages m ON m.id=e.message
+      WHERE e.guild=$1 AND e.model=$2 AND m.deleted_at IS NULL ORDER BY e.message DESC LIMIT $3",
+      &[&id(guild), &model, &(window as i64)],
+    )?;
+    let mut scored: Vec<(f32, RecalledMessage)> = rows.iter().map(|row|{
+      let blob: Vec<u8> = row.get(0);
+      (cosine_similarity(vector, &blob_to_vector(&blob)), RecalledMessage{
+        author: row.get(1),
+        message: row.get(2),
+        created_at: row.get(3),
+      })
+    }).collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    Ok(scored.into_iter().take(limit).map(|(_, message)| message).collect())
+  }
+
+  //plainto_tsquery treats the query as plain words that all have to appear, never as syntax
+  fn search(&mut self, filter: &SearchFilter, limit: u32) -> DatabaseResult<Vec<SearchHit>>{
+    if filter.query.trim().is_empty(){
+      return Ok(Vec::new());
+    }
+    let rows = self.query(
+      "SELECT m.author, m.message, m.channel, m.discord_id, m.created_at, m.source
+      FROM messages m, plainto_tsquery('simple', $1) query
+      WHERE m.search @@ query AND m.guild=$2 AND m.deleted_at IS NULL
+      AND ($3::TEXT IS NULL OR lower(m.author)=lower($3))
+      AND ($4::BIGINT IS NULL OR m.channel=$4)
+      AND ($5::BIGINT IS NULL OR m.created_at>=$5)
+      AND ($6::BIGINT IS NULL OR m.created_at<$6)
+      AND ($7::TEXT IS NULL OR m.source=$7)
+      ORDER BY ts_rank(m.search, query) DESC LIMIT $8",
+      &[
+        &filter.query,
+        &id(filter.guild),
+        &filter.author,
+        &optional_id(filter.channel),
+        &filter.after,
+        &filter.before,
+        &filter.source.map(|source| source.as_str()),
+        &(limit as i64),
+      ],
+    )?;
+    Ok(rows.iter().map(|row| SearchHit{
+      author: row.get(0),
+      message: row.get(1),
+      channel: get_id(row, 2),
+      discord_id: get_optional_id(row, 3),
+      created_at: row.get(4),
+      source: row.get(5),
+    }).collect())
+  }
+
+  fn search_candidates(&mut self, filter: &SearchFilter, before: Option<i64>, limit: u32) -> DatabaseResult<Vec<(i64, SearchHit)>>{
+    let rows = self.query(
+      "SELECT id, author, message, channel, discord_id, created_at, source FROM messages
+      WHERE guild=$1 AND deleted_at IS NULL
+      AND ($2::TEXT IS NULL OR lower(author)=lower($2))
+      AND ($3::BIGINT IS NULL OR channel=$3)
+      AND ($4::BIGINT IS NULL OR created_at>=$4)
+      AND ($5::BIGINT IS NULL OR created_at<$5)
+      AND ($6::TEXT IS NULL OR source=$6)
+      AND ($7::BIGINT IS NULL OR id<$7)
+      ORDER BY id DESC LIMIT $8",
+      &[
+        &id(filter.guild),
+        &filter.author,
+        &optional_id(filter.channel),
+        &filter.after,
+        &filter.before,
+        &filter.source.map(|source| source.as_str()),
+        &before,
+        &(limit as i64),
+      ],
+    )?;
+    Ok(rows.iter().map(|row| (row.get(0), SearchHit{
+      author: row.get(1),
+      message: row.get(2),
+      channel: get_id(row, 3),
+      discord_id: get_optional_id(row, 4),
+      created_at: row.get(5),
+      source: row.get(6),
+    })).collect())
+  }
+
+  fn latest_summary(&mut self, channel: u64) -> DatabaseResult<Option<(String, i64)>>{
+    let row = self.query_opt(
+      "SELECT summary, last_message FROM summaries WHERE channel=$1 ORDER BY last_message DESC LIMIT 1",
+      &[&id(channel)],
+    )?;
+    Ok(row.map(|row| (row.get(0), row.get(1))))
+  }
+
+  fn store_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<()>{
+    self.execute(
+      "INSERT INTO summaries (channel, last_message, summary, created_at) VALUES ($1, $2, $3, $4)",
+      &[&id(channel), &last_message, &summary, &created_at],
+    )?;
+    Ok(())
+  }
+
+  fn guild_persona(&mut self, guild: u64) -> DatabaseResult<Option<String>>{
+    Ok(self.query_opt("SELECT persona FROM guild_personas WHERE guild=$1", &[&id(guild)])?.map(|row| row.get(0)))
+  }
+
+  fn set_persona(&mut self, guild: u64, persona: &str) -> DatabaseResult<()>{
+    self.execute(
+      "INSERT INTO guild_personas (guild, persona) VALUES ($1, $2) ON CONFLICT (guild) DO UPDATE SET persona=EXCLUDED.persona",
+      &[&id(guild), &persona],
+    )?;
+    Ok(())
+  }
+
+  fn active_presets(&mut self, guild: u64, channel: u64) -> DatabaseResult<Vec<String>>{
+    let rows = self.query(
+      "SELECT p.settings FROM sampler_assignments a
+      JOIN sampler_presets p ON p.guild = a.guild AND p.name = a.preset
+      WHERE a.guild=$1 AND a.channel IN ($2, $3)
+      ORDER BY a.channel=$3",
+      &[&id(guild), &id(GUILD_WIDE), &id(channel)],
+    )?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+  }
+
+  fn presets(&mut self, guild: u64) -> DatabaseResult<Vec<(String, String)>>{
+    let rows = self.query("SELECT name, settings FROM sampler_presets WHERE guild=$1 ORDER BY name", &[&id(guild)])?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+  }
+
+  fn save_preset(&mut self, guild: u64, name: &str, preset: &str) -> DatabaseResult<()>{
+    self.execute(
+      "INSERT INTO sampler_presets (guild, name, settings) VALUES ($1, $2, $3) ON CONFLICT (guild, name) DO UPDATE SET settings=EXCLUDED.settings",
+      &[&id(guild), &name, &preset],
+    )?;
+    Ok(())
+  }
+
+  fn delete_preset(&mut self, guild: u64, name: &str) -> DatabaseResult<bool>{
+    let deleted = self.execute("DELETE FROM sampler_presets WHERE guild=$1 AND name=$2", &[&id(guild), &name])?;
+    self.execute("DELETE FROM sampler_assignments WHERE guild=$1 AND preset=$2", &[&id(guild), &name])?;
+    Ok(deleted > 0)
+  }
+
+  fn assigned_preset(&mut self, guild: u64, channel: u64) -> DatabaseResult<Option<String>>{
+    let row = self.query_opt("SELECT preset FROM sampler_assignments WHERE guild=$1 AND channel=$2", &[&id(guild), &id(channel)])?;
+    Ok(row.map(|row| row.get(0)))
+  }
+
+  fn assign_preset(&mut self, guild: u64, channel: u64, preset: Option<&str>) -> DatabaseResult<()>{
+    match preset{
+      Some(preset) => self.execute(
+        "INSERT INTO sampler_assignments (guild, channel, preset) VALUES ($1, $2, $3) ON CONFLICT (guild, channel) DO UPDATE SET preset=EXCLUDED.preset",
+        &[&id(guild), &id(channel), &preset],
+      )?,
+      None => self.execute("DELETE FROM sampler_assignments WHERE guild=$1 AND channel=$2", &[&id(guild), &id(channel)])?,
+    };
+    Ok(())
+  }
+
+  fn preset_assignments(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(u64, u64, String)>>{
+    let rows = match scope{
+      ExportScope::Channel(channel) => self.query("SELECT guild, channel, preset FROM sampler_assignments WHERE channel=$1", &[&id(channel)])?,
+      ExportScope::Guild(guild) => self.query(
+        "SELECT guild, channel, preset FROM sampler_assignments
+        WHERE guild=$1 AND channel NOT IN (SELECT channel FROM messages WHERE guild<>$1) ORDER BY channel",
+        &[&id(guild)],
+      )?,
+    };
+    Ok(rows.iter().map(|row| (get_id(row, 0), get_id(row, 1), row.get(2))).collect())
+  }
+
+  fn set_facts(&mut self, guild: u64, user_id: u64, facts: &[String]) -> DatabaseResult<()>{
+    let now = unix_now();
+    self.transaction(|tx|{
+      tx.execute("DELETE FROM user_facts WHERE guild=$1 AND user_id=$2", &[&id(guild), &id(user_id)])?;
+      for fact in facts{
+        tx.execute(
+          "INSERT INTO user_facts (guild, user_id, fact, created_at) VALUES ($1, $2, $3, $4)",
+          &[&id(guild), &id(user_id), fact, &now],
+        )?;
+      }
+      Ok(())
+    })
+  }
+
+  fn load_facts(&mut self, guild: u64, user_id: u64) -> DatabaseResult<Vec<String>>{
+    let rows = self.query("SELECT fact FROM user_facts WHERE guild=$1 AND user_id=$2 ORDER BY id", &[&id(guild), &id(user_id)])?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+  }
+
+  fn load_policy(&mut self, guild: u64) -> DatabaseResult<RetentionPolicy>{
+    let row = self.query_opt(
+      "SELECT max_age_days, max_rows_per_channel, keep_summaries_only FROM retention_policies WHERE guild=$1",
+      &[&id(guild)],
+    )?;
+    Ok(row.map(|row| RetentionPolicy{
+      max_age_days: row.get::<_, Option<i32>>(0).map(|days| days as u32),
+      max_rows_per_channel: row.get::<_, Option<i32>>(1).map(|rows| rows as u32),
+      keep_summaries_only: row.get(2),
+    }).unwrap_or_default())
+  }
+
+  fn save_policy(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<()>{
+    if policy.is_empty(){
+      self.execute("DELETE FROM retention_policies WHERE guild=$1", &[&id(guild)])?;
+    }else{
+      self.execute(
+        "INSERT INTO retention_policies (guild, max_age_days, max_rows_per_channel, keep_summaries_only) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (guild) DO UPDATE SET max_age_days=EXCLUDED.max_age_days, max_rows_per_channel=EXCLUDED.max_rows_per_channel,
+        keep_summaries_only=EXCLUDED.keep_summaries_only",
+        &[
+          &id(guild),
+          &policy.max_age_days.map(|days| days as i32),
+          &policy.max_rows_per_channel.map(|rows| rows as i32),
+          &policy.keep_summaries_only,
+        ],
+      )?;
+    }
+    Ok(())
+  }
+
+  fn retention_guilds(&mut self) -> DatabaseResult<Vec<u64>>{
+    Ok(self.matching_ids("SELECT guild FROM retention_policies", &[])?.into_iter().map(unsigned_id).collect())
+  }
+
+  //rows from before guilds were recorded can't be attributed to one and are never pruned
+  fn prune_candidates(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<(PruneReport, BTreeSet<i64>)>{
+    let mut report = PruneReport::default();
+    let mut doomed = BTreeSet::new();
+    if let Some(days) = policy.max_age_days{
+      let ids = self.matching_ids(
+        "SELECT id FROM messages WHERE guild=$1 AND deleted_at IS NULL AND created_at<$2",
+        &[&id(guild), &(unix_now() - days as i64 * DAY_SECONDS)],
+      )?;
+      report.by_age = ids.len();
+      doomed.extend(ids);
+    }
+    if let Some(rows) = policy.max_rows_per_channel{
+      let ids = self.matching_ids(
+        "SELECT id FROM (SELECT id, ROW_NUMBER() OVER (PARTITION BY channel ORDER BY id DESC) AS newest FROM messages WHERE guild=$1 AND deleted_at IS NULL) ranked WHERE newest>$2",
+        &[&id(guild), &(rows as i64)],
+      )?;
+      report.by_rows = ids.len();
+      doomed.extend(ids);
+    }
+    if policy.keep_summaries_only{
+      let ids = self.matching_ids(
+        "SELECT id FROM messages m WHERE guild=$1 AND deleted_at IS NULL AND id<=(SELECT MAX(last_message) FROM summaries s WHERE s.channel=m.channel)",
+        &[&id(guild)],
+      )?;
+      report.by_summaries = ids.len();
+      doomed.extend(ids);
+    }
+    //tombstones keep no text, any policy clears them out on the next prune
+    if !policy.is_empty(){
+      let ids = self.matching_ids(
+        "SELECT id FROM messages WHERE guild=$1 AND deleted_at IS NOT NULL",
+        &[&id(guild)],
+      )?;
+      report.tombstones = ids.len();
+      doomed.extend(ids);
+    }
+    report.total = doomed.len();
+    Ok((report, doomed))
+  }
+
+  //the search column is generated, so it goes with the row
+  fn delete_rows(&mut self, ids: &BTreeSet<i64>) -> DatabaseResult<()>{
+    self.transaction(|tx| delete_rows_in(tx, ids))
+  }
+
+  //summaries that were written from the user's messages go too, the rest of the history gets summarized again without them
+  //all or nothing, a forget that stops halfway would leave summaries gone but the messages still there
+  fn forget_user(&mut self, user_id: u64, guild: Option<u64>, anonymize: bool) -> DatabaseResult<usize>{
+    let (user_id, guild) = (id(user_id), optional_id(guild));
+    self.transaction(|tx|{
+      let ids: BTreeSet<i64> = tx.query(
+        "SELECT id FROM messages WHERE user_id=$1 AND ($2::BIGINT IS NULL OR guild=$2)",
+        &[&user_id, &guild],
+      )?.iter().map(|row| row.get(0)).collect();
+      tx.execute("DELETE FROM user_facts WHERE user_id=$1 AND ($2::BIGINT IS NULL OR guild=$2)", &[&user_id, &guild])?;
+      if !ids.is_empty(){
+        tx.execute(
+          "DELETE FROM summaries WHERE EXISTS (SELECT 1 FROM messages m WHERE m.user_id=$1 AND ($2::BIGINT IS NULL OR m.guild=$2) AND m.channel=summaries.channel AND m.id<=summaries.last_message)",
+          &[&user_id, &guild],
+        )?;
+        if anonymize{
+          tx.execute(
+            "UPDATE messages SET author=$3, user_id=NULL WHERE user_id=$1 AND ($2::BIGINT IS NULL OR guild=$2)",
+            &[&user_id, &guild, &ANONYMOUS_AUTHOR],
+          )?;
+        }else{
+          delete_rows_in(tx, &ids)?;
+        }
+      }
+      Ok(ids.len())
+    })
+  }
+
+  fn export_messages(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>{
+    let (filter, scope_id) = message_filter(scope);
+    let sql = format!(
+      "SELECT id, channel, guild, author, message, discord_id, user_id, source, created_at, edited_at FROM messages
+      WHERE {} AND deleted_at IS NULL ORDER BY id",
+      filter,
+    );
+    let rows = self.retrying(|db| db.client.query(&sql, &[&scope_id]))?;
+    Ok(rows.iter().map(|row| (row.get(0), ExportRecord::Message{
+      channel: get_id(row, 1),
+      guild: get_optional_id(row, 2),
+      author: row.get(3),
+      message: row.get(4),
+      discord_id: get_optional_id(row, 5),
+      user_id: get_optional_id(row, 6),
+      source: row.get(7),
+      created_at: row.get(8),
+      edited_at: row.get(9),
+    })).collect())
+  }
+
+  fn export_summaries(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>{
+    let (filter, scope_id) = channel_filter(scope);
+    let sql = format!("SELECT last_message, channel, summary, created_at FROM summaries WHERE {} ORDER BY last_message", filter);
+    let rows = self.retrying(|db| db.client.query(&sql, &[&scope_id]))?;
+    Ok(rows.iter().map(|row| (row.get(0), ExportRecord::Summary{
+      channel: get_id(row, 1),
+      summary: row.get(2),
+      created_at: row.get(3),
+    })).collect())
+  }
+
+  fn import_message(&mut self, imported: &ImportedMessage) -> DatabaseResult<(i64, bool)>{
+    let ImportedMessage{channel, guild, author, message, discord_id, user_id, source, created_at, edited_at} = *imported;
+    let existing = self.query_opt(
+      "SELECT id FROM messages WHERE channel=$1 AND author=$2 AND message=$3 AND created_at IS NOT DISTINCT FROM $4 AND deleted_at IS NULL LIMIT 1",
+      &[&id(channel), &author, &message, &created_at],
+    )?;
+    if let Some(row) = existing{
+      return Ok((row.get(0), false));
+    }
+    let row = self.query_one(
+      "INSERT INTO messages (author, message, channel, guild, discord_id, user_id, source, created_at, edited_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id",
+      &[&author, &message, &id(channel), &optional_id(guild), &optional_id(discord_id), &optional_id(user_id), &source, &created_at, &edited_at],
+    )?;
+    Ok((row.get(0), true))
+  }
+
+  fn import_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<bool>{
+    let inserted = self.execute(
+      "INSERT INTO summaries (channel, last_message, summary, created_at) SELECT $1::BIGINT, $2::BIGINT, $3::TEXT, $4::BIGINT
+      WHERE NOT EXISTS (SELECT 1 FROM summaries WHERE channel=$1 AND summary=$3)",
+      &[&id(channel), &last_message, &summary, &created_at],
+    )?;
+    Ok(inserted > 0)
+  }
+
+  fn stored_texts(&mut self, column: TextColumn) -> DatabaseResult<Vec<(i64, String)>>{
+    let rows = self.query(match column{
+      TextColumn::Messages => "SELECT id, message FROM messages WHERE message<>''",
+      TextColumn::Summaries => "SELECT id, summary FROM summaries WHERE summary<>''",
+      TextColumn::Facts => "SELECT id, fact FROM user_facts WHERE fact<>''",
+    }, &[])?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+  }
+
+  fn rewrite_text(&mut self, column: TextColumn, id: i64, text: &str) -> DatabaseResult<()>{
+    self.execute(match column{
+      TextColumn::Messages => "UPDATE messages SET message=$2 WHERE id=$1",
+      TextColumn::Summaries => "UPDATE summaries SET summary=$2 WHERE id=$1",
+      TextColumn::Facts => "UPDATE user_facts SET fact=$2 WHERE id=$1",
+    }, &[&id, &text])?;
+    Ok(())
+  }
+
+  //rewrites the tables so the old row versions are gone from them, the server's own write ahead log and
+  //backups are out of reach from here
+  fn scrub(&mut self) -> DatabaseResult<()>{
+    self.retrying(|db| db.client.batch_execute("VACUUM FULL messages, summaries, user_facts"))
+  }
+
+  //session level, so a shard that dies mid prune gives it up with its connection
+  fn lock_maintenance(&mut self) -> DatabaseResult<bool>{
+    Ok(self.query_one("SELECT pg_try_advisory_lock($1)", &[&MAINTENANCE_LOCK])?.get(0))
+  }
+
+  fn unlock_maintenance(&mut self) -> DatabaseResult<()>{
+    self.query_one("SELECT pg_advisory_unlock($1)", &[&MAINTENANCE_LOCK])?;
+    Ok(())
+  }
+
+  fn begin(&mut self) -> DatabaseResult<()>{
+    self.retrying(|db| db.client.batch_execute("BEGIN"))?;
+    self.in_transaction = true;
+    Ok(())
+  }
+
+  fn commit(&mut self) -> DatabaseResult<()>{
+    self.in_transaction = false;
+    self.client.batch_execute("COMMIT")?;
+    Ok(())
+  }
+
+  fn rollback(&mut self) -> DatabaseResult<()>{
+    self.in_transaction = false;
+    self.client.batch_execute("ROLLBACK")?;
+    Ok(())
+  }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use serde::{
+  Serialize,
+  Deserialize,
+};
+
+use crate::{
+  backend::{
+    endpoint,
+    GenerationRequest,
+  },
+  kobold::StoredMessage,
+  template::ChatTemplate,
+};
+
+#[derive(Serialize)]
+struct TokenCountRequest<'a>{
+  prompt: &'a str,
+}
+
+#[derive(Deserialize)]
+struct TokenCountResponse{
+  value: usize,
+}
+
+pub enum TokenCounter{
+  Kobold(reqwest::Client),
+  Estimate,
+}
+
+//roughly four characters per token for english text, only used when the server can't tell us
+pub fn estimate_tokens(text: &str) -> usize{
+  text.chars().count().div_ceil(4)
+}
+
+impl TokenCounter{
+  //TOKEN_COUNTER=estimate skips the round trip to koboldcpp's tokenizer
+  pub fn from_env() -> Self{
+    if let Ok(setting) = std::env::var("TOKEN_COUNTER"){
+      if setting.trim().eq_ignore_ascii_case("estimate"){
+        return Self::Estimate;
+      }
+    }
+    match reqwest::Client::builder().timeout(Duration::from_secs(10)).build(){
+      Ok(r) => Self::Kobold(r),
+      Err(err) => {
+        println!("Reqwest client can't be built for token counting, estimating instead: {}", err);
+        Self::Estimate
+      }
+    }
+  }
+
+  pub async fn count(&self, text: &str) -> usize{
+    let client = match self{
+      Self::Kobold(r) => r,
+      Self::Estimate => return estimate_tokens(text),
+    };
+    let res = match client.post(endpoint("KOBOLD_URL", "api/extra/tokencount"))
+      .json(&TokenCountRequest{prompt: text})
+      .send()
+      .await{
+        Ok(r) => r,
+        Err(err) => {
+          println!("Unable to count tokens with Kobold Server, estimating instead: {}", err);
+          return estimate_tokens(text);
+        }
+      };
+    match res.json::<TokenCountResponse>().await{
+      Ok(r) => r.value,
+      Err(err) => {
+        println!("Kobold Server returned an unreadable token count, estimating instead: {}", err);
+        estimate_tokens(text)
+      }
+    }
+  }
+}
+
+//works out how many of the oldest rendered messages have to go for the rest to fit in the budget,
+//the header, footer and newest message are always kept
+pub async fn fit_history(
+  header: &str,
+  footer: &str,
+  rendered: &[String],
+  budget: usize,
+  counter: &TokenCounter,
+) -> usize{
+  //pick a starting point with the cheap estimate so the real count only runs a handful of times
+  let mut used = estimate_tokens(header) + estimate_tokens(footer);
+  let mut first_kept = rendered.len();
+  while first_kept > 0{
+    let cost = estimate_tokens(&rendered[first_kept-1]);
+    if used + cost > budget && first_kept < rendered.len(){
+      break;
+    }
+    used += cost;
+    first_kept -= 1;
+  }
+
+  loop{
+    if first_kept + 1 >= rendered.len(){
+      return first_kept;
+    }
+    let prompt = header.to_string() + &rendered[first_kept..].concat() + footer;
+    let tokens = counter.count(&prompt).await;
+    if tokens <= budget{
+      if first_kept > 0{
+        println!("Dropped {} old messages to fit the prompt into {} tokens", first_kept, budget);
+      }
+      return first_kept;
+    }
+    //drop at least one message, or enough estimated tokens to cover the overflow
+    let mut overflow = tokens - budget;
+    loop{
+      overflow = overflow.saturating_sub(estimate_tokens(&rendered[first_kept]));
+      first_kept += 1;
+      if overflow == 0 || first_kept + 1 >= rendered.len(){
+        break;
+      }
+    }
+  }
+}
+
+//long term memory goes between the system prompt and the verbatim history: what's known about whoever
+//asked, recalled messages, and then the summary that leads straight into the recent messages
+pub fn system_with_memory(request: &GenerationRequest) -> String{
+  let mut prompt = request.system.clone();
+  if !request.facts.is_empty(){
+    prompt.push_str(&format!("\n\nWhat you know about {}:\n", request.speaker));
+    prompt.push_str(&request.facts.iter().map(|fact| format!("- {}", fact)).collect::<Vec<String>>().join("\n"));
+  }
+  if !request.recalled.is_empty(){
+    prompt.push_str("\n\nRelevant messages from earlier conversations:\n");
+    prompt.push_str(&request.recalled.join("\n"));
+  }
+  if let Some(summary) = request.summary.as_deref().filter(|summary| !summary.trim().is_empty()){
+    prompt.push_str("\n\nSummary of the conversation so far:\n");
+    prompt.push_str(summary.trim());
+  }
+  prompt
+}
+
+//renders the prompt with as much recent history as fits in the budget
+pub async fn build_prompt(
+  template: &ChatTemplate,
+  system: &str,
+  messages: &[StoredMessage],
+  bot_name: &str,
+  budget: usize,
+  counter: &TokenCounter,
+) -> String{
+  let header = template.render_system(system);
+  let footer = template.render_reply_start(bot_name);
+  let rendered: Vec<String> = messages.iter().map(|msg| template.render_message(msg, bot_name)).collect();
+  let first_kept = fit_history(&header, &footer, &rendered, budget, counter).await;
+  header + &rendered[first_kept..].concat() + &footer
+}
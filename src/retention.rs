@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+  database::Database,
+  storage::StorageRequest,
+};
+
+pub const DAY_SECONDS: i64 = 24*60*60;
+//who anonymized messages are attributed to
+pub const ANONYMOUS_AUTHOR: &str = "Someone";
+
+//what a guild keeps, None means no limit of that kind
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy{
+  pub max_age_days: Option<u32>,
+  pub max_rows_per_channel: Option<u32>,
+  //drop every message a summary already covers
+  pub keep_summaries_only: bool,
+}
+
+impl RetentionPolicy{
+  pub fn is_empty(&self) -> bool{
+    self.max_age_days.is_none() && self.max_rows_per_channel.is_none() && !self.keep_summaries_only
+  }
+}
+
+//how many messages each rule catches, a message caught by several rules is only counted once in total
+//the rules only look at live messages, tombstones of deleted ones are counted on their own
+#[derive(Debug, Default)]
+pub struct PruneReport{
+  pub by_age: usize,
+  pub by_rows: usize,
+  pub by_summaries: usize,
+  pub tombstones: usize,
+  pub total: usize,
+}
+
+//what the guild's policy would remove right now, without removing it
+pub fn prune_report(db: &mut dyn Database, guild: u64) -> PruneReport{
+  let policy = match db.load_policy(guild){
+    Ok(r) => r,
+    Err(err) => {
+      println!("Couldn't retrieve retention policy from the database: {}", err);
+      return PruneReport::default();
+    }
+  };
+  match db.prune_candidates(guild, &policy){
+    Ok((report, _)) => report,
+    Err(err) => {
+      println!("Couldn't find rows to prune in the database: {}", err);
+      PruneReport::default()
+    }
+  }
+}
+
+pub fn prune_guild(db: &mut dyn Database, guild: u64, policy: &RetentionPolicy) -> PruneReport{
+  let (report, doomed) = match db.prune_candidates(guild, policy){
+    Ok(r) => r,
+    Err(err) => {
+      println!("Couldn't find rows to prune in the database: {}", err);
+      return PruneReport::default();
+    }
+  };
+  if doomed.is_empty(){
+    return report;
+  }
+  if let Err(err) = db.delete_rows(&doomed){
+    println!("Can't prune messages from the database: {}", err);
+  }
+  report
+}
+
+//applies every guild's policy, then hands the freed pages back to the filesystem
+//shards sharing a postgres database take turns, whoever finds the lock taken leaves this round to the other
+pub fn prune_all(db: &mut dyn Database){
+  match db.lock_maintenance(){
+    Ok(true) => {},
+    Ok(false) => return,
+    Err(err) => {
+      println!("Couldn't take the maintenance lock of the database: {}", err);
+      return;
+    }
+  }
+  prune_locked(db);
+  if let Err(err) = db.unlock_maintenance(){
+    println!("Couldn't release the maintenance lock of the database: {}", err);
+  }
+}
+
+fn prune_locked(db: &mut dyn Database){
+  let guilds = match db.retention_guilds(){
+    Ok(r) => r,
+    Err(err) => {
+      println!("Couldn't retrieve retention policies from the database: {}", err);
+      return;
+    }
+  };
+  let mut pruned = 0;
+  for guild in guilds{
+    match db.load_policy(guild){
+      Ok(policy) => pruned += prune_guild(db, guild, &policy).total,
+      Err(err) => println!("Couldn't retrieve retention policy from the database: {}", err),
+    }
+  }
+  if pruned > 0{
+    println!("Pruned {} messages past their server's retention policy", pruned);
+  }
+  if let Err(err) = db.reclaim_space(){
+    println!("Can't reclaim the space freed by pruning: {}", err);
+  }
+}
+
+//RETENTION_INTERVAL is how many hours pass between prunes, 0 turns the background prune off
+pub fn spawn_retention_timer(storage_tx: UnboundedSender<StorageRequest>){
+  let hours: u64 = std::env::var("RETENTION_INTERVAL").ok()
+    .and_then(|r| r.trim().parse().ok())
+    .unwrap_or(6);
+  if hours == 0{
+    return;
+  }
+  tokio::spawn(async move{
+    let mut interval = tokio::time::interval(Duration::from_secs(hours*60*60));
+    loop{
+      interval.tick().await;
+      if storage_tx.send(StorageRequest::PruneAll).is_err(){
+        break;
+      }
+    }
+  });
+}
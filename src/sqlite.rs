@@ -0,0 +1,719 @@
+use std::{
+  collections::BTreeSet,
+  path::Path,
+  time::Duration,
+};
+
+use rusqlite::{
+  params,
+  Connection,
+  OpenFlags,
+  OptionalExtension,
+};
+
+use crate::{
+  database::{
+    Database,
+    DatabaseResult,
+    TextColumn,
+  },
+  embeddings::{
+    blob_to_vector,
+    cosine_similarity,
+    vector_to_blob,
+    EmbeddingJob,
+  },
+  kobold::StoredMessage,
+  migrations::migrate_sqlite,
+  retention::{
+    PruneReport,
+    RetentionPolicy,
+    ANONYMOUS_AUTHOR,
+    DAY_SECONDS,
+  },
+  storage::{
+    unix_now,
+    RecalledMessage,
+    SearchFilter,
+    SearchHit,
+    StorageMessage,
+    GUILD_WIDE,
+  },
+  transfer::{
+    ExportRecord,
+    ExportScope,
+    ImportedMessage,
+  },
+};
+
+const STATEMENT_CACHE_SIZE: usize = 64;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct SqliteDatabase{
+  conn: Connection,
+}
+
+impl SqliteDatabase{
+  //opens the file and brings it up to the current schema. secure_delete zeroes what deletes and updates
+  //free, for databases holding encrypted texts that shouldn't leave older versions behind in free pages
+  pub fn open(path: &Path, secure_delete: bool) -> rusqlite::Result<Self>{
+    let mut conn = Connection::open(path)?;
+    migrate_sqlite(&mut conn)?;
+    //wal lets the read pool keep querying while the writer commits, and NORMAL is still crash safe under wal
+    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL;")?;
+    if secure_delete{
+      conn.execute_batch("PRAGMA secure_delete=ON;")?;
+    }
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+    Ok(Self{conn})
+  }
+
+  pub fn open_read_only(path: &Path) -> rusqlite::Result<Self>{
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+    Ok(Self{conn})
+  }
+
+  fn matching_ids(&self, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<Vec<i64>>{
+    let mut stmt = self.conn.prepare_cached(sql)?;
+    let ids = stmt.query_map(params, |row| row.get(0))?.collect();
+    ids
+  }
+}
+
+fn delete_rows_in(tx: &rusqlite::Transaction, ids: &BTreeSet<i64>) -> rusqlite::Result<()>{
+  let mut delete_embedding = tx.prepare("DELETE FROM embeddings WHERE message=?1")?;
+  let mut delete_parts = tx.prepare("DELETE FROM message_parts WHERE message=?1")?;
+  let mut delete_message = tx.prepare("DELETE FROM messages WHERE id=?1")?;
+  for id in ids{
+    delete_embedding.execute(params![id])?;
+    delete_parts.execute(params![id])?;
+    delete_message.execute(params![id])?;
+  }
+  Ok(())
+}
+
+//every word of the query has to appear, each one quoted so fts5 never sees user input as syntax
+fn fts_query(query: &str) -> String{
+  query.split_whitespace()
+    .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+    .collect::<Vec<String>>()
+    .join(" ")
+}
+
+fn message_filter(scope: ExportScope) -> (&'static str, u64){
+  match scope{
+    ExportScope::Channel(channel) => ("channel=?1", channel),
+    ExportScope::Guild(guild) => ("guild=?1", guild),
+  }
+}
+
+//rows keyed by channel only belong to a guild when its messages are there and no other guild's are,
+//an imported file could otherwise claim a channel of another guild
+fn channel_filter(scope: ExportScope) -> (&'static str, u64){
+  match scope{
+    ExportScope::Channel(channel) => ("channel=?1", channel),
+    ExportScope::Guild(guild) => ("channel IN (SELECT channel FROM messages WHERE guild=?1 EXCEPT SELECT channel FROM messages WHERE guild<>?1)", guild),
+  }
+}
+
+impl Database for SqliteDatabase{
+  fn insert_messages(&mut self, messages: &[StorageMessage]) -> DatabaseResult<Vec<i64>>{
+    let tx = self.conn.transaction()?;
+    let mut ids = Vec::with_capacity(messages.len());
+    {
+      let mut stmt = tx.prepare_cached(
+        "INSERT INTO messages (author, message, channel, guild, discord_id, user_id, source, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+      )?;
+      let mut part_stmt = tx.prepare_cached("INSERT OR REPLACE INTO message_parts (discord_id, message) VALUES (?1, ?2)")?;
+      for message in messages{
+        let id = stmt.insert(params![
+          &message.author,
+          &message.message,
+          message.channel,
+          message.guild,
+          message.discord_id,
+          message.user_id,
+          message.source.as_str(),
+          message.created_at,
+        ])?;
+        for part in &message.parts{
+          part_stmt.execute(params![part, id])?;
+        }
+        ids.push(id);
+      }
+    }
+    tx.commit()?;
+    Ok(ids)
+  }
+
+  fn context(&mut self, channel: u64, after: i64, until: i64, limit: u32) -> DatabaseResult<Vec<StoredMessage>>{
+    //newest rows first so the cap drops the oldest history
+    let mut stmt = self.conn.prepare_cached("SELECT author, message FROM messages WHERE channel=?1 AND id>?3 AND id<=?4 AND deleted_at IS NULL ORDER BY id DESC LIMIT ?2")?;
+    let mut messages = stmt.query_map(params![channel, limit, after, until], |row|{
+      Ok(StoredMessage{
+        author: row.get(0)?,
+        message: row.get(1)?,
+      })
+    })?.collect::<rusqlite::Result<Vec<StoredMessage>>>()?;
+    messages.reverse();
+    Ok(messages)
+  }
+
+  fn unsummarized(&mut self, channel: u64, after: i64, limit: u32) -> DatabaseResult<Vec<(i64, StoredMessage)>>{
+    let mut stmt = self.conn.prepare_cached("SELECT id, author, message FROM messages WHERE channel=?1 AND id>?2 AND deleted_at IS NULL ORDER BY id ASC LIMIT ?3")?;
+    let rows = stmt.query_map(params![channel, after, limit], |row|{
+      Ok((row.get(0)?, StoredMessage{
+        author: row.get(1)?,
+        message: row.get(2)?,
+      }))
+    })?.collect::<rusqlite::Result<Vec<(i64, StoredMessage)>>>()?;
+    Ok(rows)
+  }
+
+  fn member_messages(&mut self, guild: u64, user_id: u64, limit: usize) -> DatabaseResult<Vec<String>>{
+    let mut stmt = self.conn.prepare_cached("SELECT message FROM messages WHERE guild=?1 AND user_id=?2 AND deleted_at IS NULL ORDER BY id DESC LIMIT ?3")?;
+    let mut messages = stmt.query_map(params![guild, user_id, limit], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+    messages.reverse();
+    Ok(messages)
+  }
+
+  fn replace_bot_message(&mut self, discord_id: u64, message: &str, parts: &[u64]) -> DatabaseResult<()>{
+    let tx = self.conn.transaction()?;
+    let id: Option<i64> = tx.query_row(
+      "SELECT id FROM messages WHERE discord_id=?1 AND deleted_at IS NULL",
+      params![discord_id],
+      |row| row.get(0),
+    ).optional()?;
+    if let Some(id) = id{
+      tx.execute("UPDATE messages SET message=?2 WHERE id=?1", params![id, message])?;
+      tx.execute("DELETE FROM message_parts WHERE message=?1", params![id])?;
+      for part in parts{
+        tx.execute("INSERT OR REPLACE INTO message_parts (discord_id, message) VALUES (?1, ?2)", params![part, id])?;
+      }
+    }
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn edit_message(&mut self, discord_id: u64, message: &str, edited_at: i64) -> DatabaseResult<()>{
+    self.conn.execute(
+      "UPDATE messages SET message=?2, edited_at=?3 WHERE discord_id=?1 AND user_id IS NOT NULL AND deleted_at IS NULL",
+      params![discord_id, message, edited_at],
+    )?;
+    Ok(())
+  }
+
+  fn delete_messages(&mut self, discord_ids: &[u64], deleted_at: i64) -> DatabaseResult<()>{
+    //a bulk purge lands all at once or not at all
+    let tx = self.conn.transaction()?;
+    {
+      let mut matching = tx.prepare_cached("SELECT id FROM messages WHERE discord_id=?1 UNION SELECT message FROM message_parts WHERE discord_id=?1")?;
+      let mut delete_embedding = tx.prepare_cached("DELETE FROM embeddings WHERE message=?1")?;
+      let mut blank_message = tx.prepare_cached("UPDATE messages SET message='', deleted_at=?2 WHERE id=?1 AND deleted_at IS NULL")?;
+      for discord_id in discord_ids{
+        let ids = matching.query_map(params![discord_id], |row| row.get(0))?.collect::<rusqlite::Result<Vec<i64>>>()?;
+        for id in ids{
+          delete_embedding.execute(params![id])?;
+          blank_message.execute(params![id, deleted_at])?;
+        }
+      }
+    }
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn embedding_job(&mut self, discord_id: u64) -> DatabaseResult<Option<EmbeddingJob>>{
+    let job = self.conn.query_row(
+      "SELECT id, guild, message FROM messages WHERE discord_id=?1 AND guild IS NOT NULL AND deleted_at IS NULL",
+      params![discord_id],
+      |row| Ok(EmbeddingJob{
+        message: row.get(0)?,
+        guild: row.get(1)?,
+        text: row.get(2)?,
+      }),
+    ).optional()?;
+    Ok(job.filter(|job| !job.text.trim().is_empty()))
+  }
+
+  fn store_embedding(&mut self, message: i64, guild: u64, model: &str, vector: &[f32]) -> DatabaseResult<()>{
+    let mut stmt = self.conn.prepare_cached("INSERT OR REPLACE INTO embeddings (message, guild, model, vector) VALUES (?1, ?2, ?3, ?4)")?;
+    stmt.execute(params![message, guild, model, vector_to_blob(vector)])?;
+    Ok(())
+  }
+
+  //brute force over every vector in the guild, plenty fast for the size of a discord server's history
+  fn recall(&mut self, guild: u64, model: &str, vector: &[f32], limit: usize, window: u32) -> DatabaseResult<Vec<RecalledMessage>>{
+    let mut stmt = self.conn.prepare_cached(
+      "SELECT e.vector, m.author, m.message, m.created_at FROM embeddings e JOIN messages m ON m.id=e.message
+      WHERE e.guild=?1 AND e.model=?2 AND m.deleted_at IS NULL ORDER BY e.message DESC LIMIT ?3"
+    )?;
+    let mut scored = stmt.query_map(params![guild, model, window], |row|{
+      let blob: Vec<u8> = row.get(0)?;
+      Ok((cosine_similarity(vector, &blob_to_vector(&blob)), RecalledMessage{
+        author: row.get(1)?,
+        message: row.get(2)?,
+        created_at: row.get(3)?,
+      }))
+    })?.collect::<rusqlite::Result<Vec<(f32, RecalledMessage)>>>()?;
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    Ok(scored.into_iter().take(limit).map(|(_, message)| message).collect())
+  }
+
+  fn search(&mut self, filter: &SearchFilter, limit: u32) -> DatabaseResult<Vec<SearchHit>>{
+    let query = fts_query(&filter.query);
+    if query.is_empty(){
+      return Ok(Vec::new());
+    }
+    let mut stmt = self.conn.prepare_cached(
+      "SELECT m.author, m.message, m.channel, m.discord_id, m.created_at, m.source
+      FROM messages_fts JOIN messages m ON m.id=messages_fts.rowid
+      WHERE messages_fts MATCH ?1 AND m.guild=?2 AND m.deleted_at IS NULL
+      AND (?3 IS NULL OR m.author=?3 COLLATE NOCASE)
+      AND (?4 IS NULL OR m.channel=?4)
+      AND (?5 IS NULL OR m.created_at>=?5)
+      AND (?6 IS NULL OR m.created_at<?6)
+      AND (?7 IS NULL OR m.source=?7)
+      ORDER BY messages_fts.rank LIMIT ?8"
+    )?;
+    let hits = stmt.query_map(params![
+      query,
+      filter.guild,
+      filter.author,
+      filter.channel,
+      filter.after,
+      filter.before,
+      filter.source.map(|source| source.as_str()),
+      limit,
+    ], |row|{
+      Ok(SearchHit{
+        author: row.get(0)?,
+        message: row.get(1)?,
+        channel: row.get(2)?,
+        discord_id: row.get(3)?,
+        created_at: row.get(4)?,
+        source: row.get(5)?,
+      })
+    })?.collect::<rusqlite::Result<Vec<SearchHit>>>()?;
+    Ok(hits)
+  }
+
+  fn search_candidates(&mut self, filter: &SearchFilter, before: Option<i64>, limit: u32) -> DatabaseResult<Vec<(i64, SearchHit)>>{
+    let mut stmt = self.conn.prepare_cached(
+      "SELECT id, author, message, channel, discord_id, created_at, source FROM messages
+      WHERE guild=?1 AND deleted_at IS NULL
+      AND (?2 IS NULL OR author=?2 COLLATE NOCASE)
+      AND (?3 IS NULL OR channel=?3)
+      AND (?4 IS NULL OR created_at>=?4)
+      AND (?5 IS NULL OR created_at<?5)
+      AND (?6 IS NULL OR source=?6)
+      AND (?7 IS NULL OR id<?7)
+      ORDER BY id DESC LIMIT ?8"
+    )?;
+    let hits = stmt.query_map(params![
+      filter.guild,
+      filter.author,
+      filter.channel,
+      filter.after,
+      filter.before,
+      filter.source.map(|source| source.as_str()),
+      before,
+      limit,
+    ], |row|{
+      Ok((row.get(0)?, SearchHit{
+        author: row.get(1)?,
+        message: row.get(2)?,
+        channel: row.get(3)?,
+        discord_id: row.get(4)?,
+        created_at: row.get(5)?,
+        source: row.get(6)?,
+      }))
+    })?.collect::<rusqlite::Result<Vec<(i64, SearchHit)>>>()?;
+    Ok(hits)
+  }
+
+  fn latest_summary(&mut self, channel: u64) -> DatabaseResult<Option<(String, i64)>>{
+    Ok(self.conn.query_row(
+      "SELECT summary, last_message FROM summaries WHERE channel=?1 ORDER BY last_message DESC LIMIT 1",
+      params![channel],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?)
+  }
+
+  fn store_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<()>{
+    self.conn.execute(
+      "INSERT INTO summaries (channel, last_message, summary, created_at) VALUES (?1, ?2, ?3, ?4)",
+      params![channel, last_message, summary, created_at],
+    )?;
+    Ok(())
+  }
+
+  fn guild_persona(&mut self, guild: u64) -> DatabaseResult<Option<String>>{
+    Ok(self.conn.query_row("SELECT persona FROM guild_personas WHERE guild=?1", params![guild], |row| row.get(0)).optional()?)
+  }
+
+  fn set_persona(&mut self, guild: u64, persona: &str) -> DatabaseResult<()>{
+    self.conn.execute("INSERT OR REPLACE INTO guild_personas (guild, persona) VALUES (?1, ?2)", params![guild, persona])?;
+    Ok(())
+  }
+
+  fn active_presets(&mut self, guild: u64, channel: u64) -> DatabaseResult<Vec<String>>{
+    let mut stmt = self.conn.prepare_cached(
+      "SELECT p.settings FROM sampler_assignments a
+      JOIN sampler_presets p ON p.guild = a.guild AND p.name = a.preset
+      WHERE a.guild=?1 AND a.channel IN (?2, ?3)
+      ORDER BY a.channel=?3"
+    )?;
+    let presets = stmt.query_map(params![guild, GUILD_WIDE, channel], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(presets)
+  }
+
+  fn presets(&mut self, guild: u64) -> DatabaseResult<Vec<(String, String)>>{
+    let mut stmt = self.conn.prepare_cached("SELECT name, settings FROM sampler_presets WHERE guild=?1 ORDER BY name")?;
+    let presets = stmt.query_map(params![guild], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+    Ok(presets)
+  }
+
+  fn save_preset(&mut self, guild: u64, name: &str, preset: &str) -> DatabaseResult<()>{
+    self.conn.execute("INSERT OR REPLACE INTO sampler_presets (guild, name, settings) VALUES (?1, ?2, ?3)", params![guild, name, preset])?;
+    Ok(())
+  }
+
+  fn delete_preset(&mut self, guild: u64, name: &str) -> DatabaseResult<bool>{
+    let deleted = self.conn.execute("DELETE FROM sampler_presets WHERE guild=?1 AND name=?2", params![guild, name])?;
+    self.conn.execute("DELETE FROM sampler_assignments WHERE guild=?1 AND preset=?2", params![guild, name])?;
+    Ok(deleted > 0)
+  }
+
+  fn assigned_preset(&mut self, guild: u64, channel: u64) -> DatabaseResult<Option<String>>{
+    Ok(self.conn.query_row(
+      "SELECT preset FROM sampler_assignments WHERE guild=?1 AND channel=?2",
+      params![guild, channel],
+      |row| row.get(0),
+    ).optional()?)
+  }
+
+  fn assign_preset(&mut self, guild: u64, channel: u64, preset: Option<&str>) -> DatabaseResult<()>{
+    match preset{
+      Some(preset) => self.conn.execute("INSERT OR REPLACE INTO sampler_assignments (guild, channel, preset) VALUES (?1, ?2, ?3)", params![guild, channel, preset])?,
+      None => self.conn.execute("DELETE FROM sampler_assignments WHERE guild=?1 AND channel=?2", params![guild, channel])?,
+    };
+    Ok(())
+  }
+
+  fn preset_assignments(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(u64, u64, String)>>{
+    let sql = match scope{
+      ExportScope::Channel(_) => "SELECT guild, channel, preset FROM sampler_assignments WHERE channel=?1",
+      ExportScope::Guild(_) => "SELECT guild, channel, preset FROM sampler_assignments
+        WHERE guild=?1 AND channel NOT IN (SELECT channel FROM messages WHERE guild<>?1) ORDER BY channel",
+    };
+    let id = match scope{
+      ExportScope::Channel(id) | ExportScope::Guild(id) => id,
+    };
+    let mut stmt = self.conn.prepare_cached(sql)?;
+    let assignments = stmt.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.collect::<rusqlite::Result<Vec<(u64, u64, String)>>>()?;
+    Ok(assignments)
+  }
+
+  fn set_facts(&mut self, guild: u64, user_id: u64, facts: &[String]) -> DatabaseResult<()>{
+    let tx = self.conn.transaction()?;
+    tx.execute("DELETE FROM user_facts WHERE guild=?1 AND user_id=?2", params![guild, user_id])?;
+    let now = unix_now();
+    for fact in facts{
+      tx.execute("INSERT INTO user_facts (guild, user_id, fact, created_at) VALUES (?1, ?2, ?3, ?4)", params![guild, user_id, fact, now])?;
+    }
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn load_facts(&mut self, guild: u64, user_id: u64) -> DatabaseResult<Vec<String>>{
+    let mut stmt = self.conn.prepare_cached("SELECT fact FROM user_facts WHERE guild=?1 AND user_id=?2 ORDER BY id")?;
+    let facts = stmt.query_map(params![guild, user_id], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(facts)
+  }
+
+  fn load_policy(&mut self, guild: u64) -> DatabaseResult<RetentionPolicy>{
+    let policy = self.conn.query_row(
+      "SELECT max_age_days, max_rows_per_channel, keep_summaries_only FROM retention_policies WHERE guild=?1",
+      params![guild],
+      |row| Ok(RetentionPolicy{
+        max_age_days: row.get(0)?,
+        max_rows_per_channel: row.get(1)?,
+        keep_summaries_only: row.get(2)?,
+      }),
+    ).optional()?;
+    Ok(policy.unwrap_or_default())
+  }
+
+  fn save_policy(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<()>{
+    if policy.is_empty(){
+      self.conn.execute("DELETE FROM retention_policies WHERE guild=?1", params![guild])?;
+    }else{
+      self.conn.execute(
+        "INSERT OR REPLACE INTO retention_policies (guild, max_age_days, max_rows_per_channel, keep_summaries_only) VALUES (?1, ?2, ?3, ?4)",
+        params![guild, policy.max_age_days, policy.max_rows_per_channel, policy.keep_summaries_only],
+      )?;
+    }
+    Ok(())
+  }
+
+  fn retention_guilds(&mut self) -> DatabaseResult<Vec<u64>>{
+    Ok(self.matching_ids("SELECT guild FROM retention_policies", ())?.into_iter().map(|guild| guild as u64).collect())
+  }
+
+  //rows from before guilds were recorded can't be attributed to one and are never pruned
+  fn prune_candidates(&mut self, guild: u64, policy: &RetentionPolicy) -> DatabaseResult<(PruneReport, BTreeSet<i64>)>{
+    let mut report = PruneReport::default();
+    let mut doomed = BTreeSet::new();
+    if let Some(days) = policy.max_age_days{
+      let ids = self.matching_ids(
+        "SELECT id FROM messages WHERE guild=?1 AND deleted_at IS NULL AND created_at<?2",
+        params![guild, unix_now() - days as i64 * DAY_SECONDS],
+      )?;
+      report.by_age = ids.len();
+      doomed.extend(ids);
+    }
+    if let Some(rows) = policy.max_rows_per_channel{
+      let ids = self.matching_ids(
+        "SELECT id FROM (SELECT id, ROW_NUMBER() OVER (PARTITION BY channel ORDER BY id DESC) AS newest FROM messages WHERE guild=?1 AND deleted_at IS NULL) WHERE newest>?2",
+        params![guild, rows],
+      )?;
+      report.by_rows = ids.len();
+      doomed.extend(ids);
+    }
+    if policy.keep_summaries_only{
+      let ids = self.matching_ids(
+        "SELECT id FROM messages m WHERE guild=?1 AND deleted_at IS NULL AND id<=(SELECT MAX(last_message) FROM summaries s WHERE s.channel=m.channel)",
+        params![guild],
+      )?;
+      report.by_summaries = ids.len();
+      doomed.extend(ids);
+    }
+    //tombstones keep no text, any policy clears them out on the next prune
+    if !policy.is_empty(){
+      let ids = self.matching_ids(
+        "SELECT id FROM messages WHERE guild=?1 AND deleted_at IS NOT NULL",
+        params![guild],
+      )?;
+      report.tombstones = ids.len();
+      doomed.extend(ids);
+    }
+    report.total = doomed.len();
+    Ok((report, doomed))
+  }
+
+  //the fts triggers take care of the index
+  fn delete_rows(&mut self, ids: &BTreeSet<i64>) -> DatabaseResult<()>{
+    let tx = self.conn.transaction()?;
+    delete_rows_in(&tx, ids)?;
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn reclaim_space(&mut self) -> DatabaseResult<()>{
+    self.conn.execute_batch("PRAGMA incremental_vacuum;")?;
+    Ok(())
+  }
+
+  //fts segments keep the terms of replaced rows until they're merged, the wal and free pages keep whole
+  //old pages. the index is filled again from plain text rows, the wal emptied and the file rewritten
+  fn scrub(&mut self) -> DatabaseResult<()>{
+    self.conn.execute_batch(
+      "PRAGMA secure_delete=ON;
+      INSERT INTO messages_fts(messages_fts) VALUES ('delete-all');
+      INSERT INTO messages_fts(rowid, author, message) SELECT id, author, message FROM messages WHERE message NOT GLOB 'enc:*';
+      INSERT INTO messages_fts(messages_fts) VALUES ('optimize');
+      PRAGMA wal_checkpoint(TRUNCATE);
+      VACUUM;
+      PRAGMA wal_checkpoint(TRUNCATE);"
+    )?;
+    Ok(())
+  }
+
+  //summaries that were written from the user's messages go too, the rest of the history gets summarized again without them
+  //all or nothing, a forget that stops halfway would leave summaries gone but the messages still there
+  fn forget_user(&mut self, user_id: u64, guild: Option<u64>, anonymize: bool) -> DatabaseResult<usize>{
+    let tx = self.conn.transaction()?;
+    let ids: BTreeSet<i64> = {
+      let mut stmt = tx.prepare("SELECT id FROM messages WHERE user_id=?1 AND (?2 IS NULL OR guild=?2)")?;
+      let ids = stmt.query_map(params![user_id, guild], |row| row.get(0))?.collect::<rusqlite::Result<BTreeSet<i64>>>()?;
+      ids
+    };
+    tx.execute("DELETE FROM user_facts WHERE user_id=?1 AND (?2 IS NULL OR guild=?2)", params![user_id, guild])?;
+    if !ids.is_empty(){
+      tx.execute(
+        "DELETE FROM summaries WHERE EXISTS (SELECT 1 FROM messages m WHERE m.user_id=?1 AND (?2 IS NULL OR m.guild=?2) AND m.channel=summaries.channel AND m.id<=summaries.last_message)",
+        params![user_id, guild],
+      )?;
+      if anonymize{
+        tx.execute(
+          "UPDATE messages SET author=?3, user_id=NULL WHERE user_id=?1 AND (?2 IS NULL OR guild=?2)",
+          params![user_id, guild, ANONYMOUS_AUTHOR],
+        )?;
+      }else{
+        delete_rows_in(&tx, &ids)?;
+      }
+    }
+    tx.commit()?;
+    Ok(ids.len())
+  }
+
+  fn export_messages(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>{
+    let (filter, id) = message_filter(scope);
+    let mut stmt = self.conn.prepare(&format!(
+      "SELECT id, channel, guild, author, message, discord_id, user_id, source, created_at, edited_at FROM messages
+      WHERE {} AND deleted_at IS NULL ORDER BY id",
+      filter,
+    ))?;
+    let messages = stmt.query_map(params![id], |row| Ok((row.get(0)?, ExportRecord::Message{
+      channel: row.get(1)?,
+      guild: row.get(2)?,
+      author: row.get(3)?,
+      message: row.get(4)?,
+      discord_id: row.get(5)?,
+      user_id: row.get(6)?,
+      source: row.get(7)?,
+      created_at: row.get(8)?,
+      edited_at: row.get(9)?,
+    })))?.collect::<rusqlite::Result<Vec<(i64, ExportRecord)>>>()?;
+    Ok(messages)
+  }
+
+  fn export_summaries(&mut self, scope: ExportScope) -> DatabaseResult<Vec<(i64, ExportRecord)>>{
+    let (filter, id) = channel_filter(scope);
+    let mut stmt = self.conn.prepare(&format!("SELECT last_message, channel, summary, created_at FROM summaries WHERE {} ORDER BY last_message", filter))?;
+    let summaries = stmt.query_map(params![id], |row| Ok((row.get(0)?, ExportRecord::Summary{
+      channel: row.get(1)?,
+      summary: row.get(2)?,
+      created_at: row.get(3)?,
+    })))?.collect::<rusqlite::Result<Vec<(i64, ExportRecord)>>>()?;
+    Ok(summaries)
+  }
+
+  fn import_message(&mut self, imported: &ImportedMessage) -> DatabaseResult<(i64, bool)>{
+    let ImportedMessage{channel, guild, author, message, discord_id, user_id, source, created_at, edited_at} = *imported;
+    let existing = self.conn.query_row(
+      "SELECT id FROM messages WHERE channel=?1 AND author=?2 AND message=?3 AND created_at IS ?4 AND deleted_at IS NULL",
+      params![channel, author, message, created_at],
+      |row| row.get(0),
+    ).optional()?;
+    if let Some(id) = existing{
+      return Ok((id, false));
+    }
+    self.conn.execute(
+      "INSERT INTO messages (author, message, channel, guild, discord_id, user_id, source, created_at, edited_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+      params![author, message, channel, guild, discord_id, user_id, source, created_at, edited_at],
+    )?;
+    Ok((self.conn.last_insert_rowid(), true))
+  }
+
+  fn import_summary(&mut self, channel: u64, last_message: i64, summary: &str, created_at: i64) -> DatabaseResult<bool>{
+    let inserted = self.conn.execute(
+      "INSERT INTO summaries (channel, last_message, summary, created_at) SELECT ?1, ?2, ?3, ?4
+      WHERE NOT EXISTS (SELECT 1 FROM summaries WHERE channel=?1 AND summary=?3)",
+      params![channel, last_message, summary, created_at],
+    )?;
+    Ok(inserted > 0)
+  }
+
+  fn stored_texts(&mut self, column: TextColumn) -> DatabaseResult<Vec<(i64, String)>>{
+    let mut stmt = self.conn.prepare_cached(match column{
+      TextColumn::Messages => "SELECT id, message FROM messages WHERE message<>''",
+      TextColumn::Summaries => "SELECT id, summary FROM summaries WHERE summary<>''",
+      TextColumn::Facts => "SELECT id, fact FROM user_facts WHERE fact<>''",
+    })?;
+    let texts = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+    Ok(texts)
+  }
+
+  fn rewrite_text(&mut self, column: TextColumn, id: i64, text: &str) -> DatabaseResult<()>{
+    let mut stmt = self.conn.prepare_cached(match column{
+      TextColumn::Messages => "UPDATE messages SET message=?2 WHERE id=?1",
+      TextColumn::Summaries => "UPDATE summaries SET summary=?2 WHERE id=?1",
+      TextColumn::Facts => "UPDATE user_facts SET fact=?2 WHERE id=?1",
+    })?;
+    stmt.execute(params![id, text])?;
+    Ok(())
+  }
+
+  //a sqlite file only ever has the one bot writing to it
+  fn lock_maintenance(&mut self) -> DatabaseResult<bool>{
+    Ok(true)
+  }
+
+  fn unlock_maintenance(&mut self) -> DatabaseResult<()>{
+    Ok(())
+  }
+
+  fn begin(&mut self) -> DatabaseResult<()>{
+    self.conn.execute_batch("BEGIN")?;
+    Ok(())
+  }
+
+  fn commit(&mut self) -> DatabaseResult<()>{
+    self.conn.execute_batch("COMMIT")?;
+    Ok(())
+  }
+
+  fn rollback(&mut self) -> DatabaseResult<()>{
+    self.conn.execute_batch("ROLLBACK")?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests{
+  use super::*;
+
+  fn filter(query: &str) -> SearchFilter{
+    SearchFilter{
+      query: query.to_string(),
+      guild: 2,
+      author: None,
+      channel: None,
+      after: None,
+      before: None,
+      source: None,
+    }
+  }
+
+  #[test]
+  fn ciphertext_stays_out_of_the_index(){
+    let mut db = SqliteDatabase::open(Path::new(":memory:"), false).unwrap();
+    let ids = db.insert_messages(&[StorageMessage::sample("hello there"), StorageMessage::sample("enc:aGVsbG8=")]).unwrap();
+    let indexed: i64 = db.conn.query_row("SELECT COUNT(*) FROM messages_fts WHERE messages_fts MATCH 'enc OR aGVsbG8'", (), |row| row.get(0)).unwrap();
+    assert_eq!(indexed, 0);
+    assert_eq!(db.search(&filter("hello"), 10).unwrap().len(), 1);
+    //rewriting a row in and out of ciphertext keeps the index consistent
+    db.rewrite_text(TextColumn::Messages, ids[0], "enc:b3RoZXI=").unwrap();
+    db.rewrite_text(TextColumn::Messages, ids[1], "hello again").unwrap();
+    let hits = db.search(&filter("hello"), 10).unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].message, "hello again");
+    db.conn.execute("INSERT INTO messages_fts(messages_fts) VALUES ('integrity-check')", ()).unwrap();
+  }
+
+  #[test]
+  fn search_candidates_page_newest_first(){
+    let mut db = SqliteDatabase::open(Path::new(":memory:"), false).unwrap();
+    let ids = db.insert_messages(&[StorageMessage::sample("a"), StorageMessage::sample("b"), StorageMessage::sample("c")]).unwrap();
+    let first = db.search_candidates(&filter(""), None, 2).unwrap();
+    assert_eq!(first.iter().map(|(id, _)| *id).collect::<Vec<i64>>(), vec![ids[2], ids[1]]);
+    let rest = db.search_candidates(&filter(""), Some(ids[1]), 2).unwrap();
+    assert_eq!(rest.iter().map(|(id, _)| *id).collect::<Vec<i64>>(), vec![ids[0]]);
+  }
+
+  #[test]
+  fn prune_rules_skip_tombstones(){
+    let mut db = SqliteDatabase::open(Path::new(":memory:"), false).unwrap();
+    let messages: Vec<StorageMessage> = (10..13).map(|discord_id| StorageMessage{discord_id: Some(discord_id), ..StorageMessage::sample("hi")}).collect();
+    let ids = db.insert_messages(&messages).unwrap();
+    db.delete_messages(&[12], 200).unwrap();
+    let policy = RetentionPolicy{max_rows_per_channel: Some(1), ..RetentionPolicy::default()};
+    let (report, doomed) = db.prune_candidates(2, &policy).unwrap();
+    assert_eq!((report.by_rows, report.tombstones, report.total), (1, 1, 2));
+    assert_eq!(doomed, BTreeSet::from([ids[0], ids[2]]));
+  }
+}
@@ -1,72 +1,878 @@
-use rusqlite::{
-  params, 
-  Connection,
+use std::{
+  collections::{
+    HashMap,
+    HashSet,
+  },
+  sync::{
+    Arc,
+    Mutex,
+  },
+  time::{
+    SystemTime,
+    UNIX_EPOCH,
+  },
 };
-use tokio::sync::mpsc::{
-  unbounded_channel as tokio_channel,
-  UnboundedSender,
+
+use tokio::sync::{
+  mpsc::{
+    unbounded_channel as tokio_channel,
+    UnboundedSender,
+  },
+  oneshot,
 };
 
-use crate::kobold::{
-  spawn_kobold_thread, KoboldRequest, StoredMessage
+use crate::{
+  backend::{
+    SamplerPreset,
+    SamplerSettings,
+  },
+  database::{
+    database_key,
+    open_read_connection,
+    Database,
+    DatabaseResult,
+    Databases,
+  },
+  embeddings::{
+    spawn_embedding_thread,
+    EmbeddingClient,
+    EmbeddingJob,
+  },
+  encryption::MessageCipher,
+  facts::{
+    spawn_fact_thread,
+    FactRequest,
+  },
+  kobold::{
+    spawn_kobold_thread, ActiveGenerations, Generator, KoboldRequest, StoredMessage
+  },
+  persona::PersonaLibrary,
+  retention::{
+    prune_all,
+    prune_report,
+    spawn_retention_timer,
+    PruneReport,
+    RetentionPolicy,
+  },
+  summary::{
+    spawn_summary_thread,
+    summary_span,
+    SummaryRequest,
+  },
+  swipe::Swipes,
+  transfer::{
+    export,
+    import,
+    parse_records,
+    ExportFormat,
+    ExportScope,
+    ImportTarget,
+    ImportReport,
+  },
 };
 
+//more rows than could ever fit in the context window, the prompt builder does the exact trimming
+const CONTEXT_FETCH_LIMIT: u32 = 2000;
+const SEARCH_RESULT_LIMIT: u32 = 100;
+//idle read connections kept around for searches, recall and exports
+const READ_POOL_SIZE: usize = 4;
+//the most queued messages that go into one insert transaction
+const STORE_BATCH_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageSource{
+  Text,
+  Voice,
+}
+
+impl MessageSource{
+  pub fn as_str(&self) -> &'static str{
+    match self{
+      MessageSource::Text => "text",
+      MessageSource::Voice => "voice",
+    }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct StorageMessage{
   pub message: String,
   pub author: String,
   pub channel: u64,
+  pub guild: Option<u64>,
+  //the first discord message it was posted as, None for transcribed speech
+  pub discord_id: Option<u64>,
+  //the discord messages after the first that a reply too long for one message spilled into
+  pub parts: Vec<u64>,
+  //None for the bot's own replies
+  pub user_id: Option<u64>,
+  pub source: MessageSource,
+  //unix seconds
+  pub created_at: i64,
+}
+
+#[cfg(test)]
+impl StorageMessage{
+  //a user's text message in a guild channel, for tests that only care about the text
+  pub fn sample(text: &str) -> Self{
+    Self{
+      message: text.to_string(),
+      author: "Ann".to_string(),
+      channel: 1,
+      guild: Some(2),
+      discord_id: None,
+      parts: Vec::new(),
+      user_id: Some(3),
+      source: MessageSource::Text,
+      created_at: 100,
+    }
+  }
+}
+
+pub fn unix_now() -> i64{
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|r| r.as_secs() as i64).unwrap_or_default()
+}
+
+pub struct RecalledMessage{
+  pub author: String,
+  pub message: String,
+  pub created_at: Option<i64>,
+}
+
+pub struct SearchFilter{
+  pub query: String,
+  pub guild: u64,
+  pub author: Option<String>,
+  pub channel: Option<u64>,
+  //unix seconds, after is inclusive and before is exclusive
+  pub after: Option<i64>,
+  pub before: Option<i64>,
+  pub source: Option<MessageSource>,
+}
+
+pub struct SearchHit{
+  pub author: String,
+  pub message: String,
+  pub channel: u64,
+  pub discord_id: Option<u64>,
+  pub created_at: Option<i64>,
+  pub source: String,
+}
+
+pub struct PresetOverview{
+  pub presets: Vec<(String, SamplerPreset)>,
+  pub guild_preset: Option<String>,
+  pub channel_preset: Option<String>,
+}
+
+pub enum StorageRequest{
+  Store(StorageMessage),
+  //a swiped or regenerated reply takes the place of what the bot said before
+  ReplaceBotMessage{
+    guild: Option<u64>,
+    discord_id: u64,
+    message: String,
+    parts: Vec<u64>,
+  },
+  StoreEmbedding{
+    message: i64,
+    guild: u64,
+    model: String,
+    vector: Vec<f32>,
+  },
+  //the stored messages in a guild closest in meaning to the given vector, best match first
+  Recall{
+    guild: u64,
+    model: String,
+    vector: Vec<f32>,
+    limit: usize,
+    //how many of the newest embeddings are compared
+    window: u32,
+    reply: oneshot::Sender<Vec<RecalledMessage>>,
+  },
+  //best matches first, at most SEARCH_RESULT_LIMIT of them
+  Search{
+    filter: SearchFilter,
+    reply: oneshot::Sender<Vec<SearchHit>>,
+  },
+  GetRetention{
+    guild: u64,
+    reply: oneshot::Sender<RetentionPolicy>,
+  },
+  SetRetention{
+    guild: u64,
+    policy: RetentionPolicy,
+  },
+  //what the guild's policy would remove right now, without removing it
+  PreviewPrune{
+    guild: u64,
+    reply: oneshot::Sender<PruneReport>,
+  },
+  PruneAll,
+  //guild is where the export was asked for, which picks the database with DATABASE_PER_GUILD
+  Export{
+    guild: u64,
+    scope: ExportScope,
+    format: ExportFormat,
+    reply: oneshot::Sender<Result<String, String>>,
+  },
+  //a jsonl export, target keeps everything inside one guild
+  Import{
+    data: String,
+    target: Option<ImportTarget>,
+    reply: oneshot::Sender<Result<ImportReport, String>>,
+  },
+  //replies how many messages were forgotten, guild None means every guild
+  ForgetUser{
+    user_id: u64,
+    guild: Option<u64>,
+    anonymize: bool,
+    reply: oneshot::Sender<usize>,
+  },
+  //replaces what's known about a member, an empty list clears it.
+  //pass is the extraction pass the facts came out of, None when the member asked for it
+  SetFacts{
+    guild: u64,
+    user_id: u64,
+    facts: Vec<String>,
+    pass: Option<u64>,
+  },
+  ListFacts{
+    guild: u64,
+    user_id: u64,
+    reply: oneshot::Sender<Vec<String>>,
+  },
+  //a finished summary, None when the backend couldn't write one
+  StoreSummary{
+    guild: Option<u64>,
+    channel: u64,
+    last_message: i64,
+    summary: Option<String>,
+  },
+  //someone edited their message on discord
+  EditMessage{
+    guild: Option<u64>,
+    discord_id: u64,
+    message: String,
+    edited_at: i64,
+  },
+  //messages deleted on discord, single deletes and bulk deletes alike
+  DeleteMessages{
+    guild: Option<u64>,
+    discord_ids: Vec<u64>,
+    deleted_at: i64,
+  },
+  SetPersona{
+    guild: u64,
+    persona: String,
+  },
+  SavePreset{
+    guild: u64,
+    name: String,
+    preset: SamplerPreset,
+  },
+  //replies whether there was a preset to delete
+  DeletePreset{
+    guild: u64,
+    name: String,
+    reply: oneshot::Sender<bool>,
+  },
+  ListPresets{
+    guild: u64,
+    channel: u64,
+    reply: oneshot::Sender<PresetOverview>,
+  },
+  //a channel of None means the whole guild, a preset of None clears the assignment
+  AssignPreset{
+    guild: u64,
+    channel: Option<u64>,
+    preset: Option<String>,
+  },
+}
+
+impl StorageRequest{
+  //the guild whose database the request goes to, None for the main one. PruneAll and forgetting
+  //a user everywhere go to all of them instead
+  fn guild(&self) -> Option<u64>{
+    match self{
+      Self::Store(message) => message.guild,
+      Self::Import{target, ..} => target.as_ref().map(|target| target.guild),
+      Self::ReplaceBotMessage{guild, ..}
+      | Self::ForgetUser{guild, ..}
+      | Self::StoreSummary{guild, ..}
+      | Self::EditMessage{guild, ..}
+      | Self::DeleteMessages{guild, ..} => *guild,
+      Self::StoreEmbedding{guild, ..}
+      | Self::Recall{guild, ..}
+      | Self::GetRetention{guild, ..}
+      | Self::SetRetention{guild, ..}
+      | Self::PreviewPrune{guild, ..}
+      | Self::Export{guild, ..}
+      | Self::SetFacts{guild, ..}
+      | Self::ListFacts{guild, ..}
+      | Self::SetPersona{guild, ..}
+      | Self::SavePreset{guild, ..}
+      | Self::DeletePreset{guild, ..}
+      | Self::ListPresets{guild, ..}
+      | Self::AssignPreset{guild, ..} => Some(*guild),
+      Self::Search{filter, ..} => Some(filter.guild),
+      Self::PruneAll => None,
+    }
+  }
+}
+
+//guild wide assignments are stored against channel 0 so a guild and its channels share one table
+pub const GUILD_WIDE: u64 = 0;
+
+fn guild_persona(db: &mut dyn Database, guild: Option<u64>) -> Option<String>{
+  match db.guild_persona(guild?){
+    Ok(r) => r,
+    Err(err) => {
+      println!("Couldn't retrieve guild persona from the database: {}", err);
+      None
+    }
+  }
+}
+
+//the guild preset goes over the defaults first and the channel preset goes over that
+fn active_settings(db: &mut dyn Database, guild: Option<u64>, channel: u64) -> SamplerSettings{
+  let mut settings = SamplerSettings::default();
+  let guild = match guild{
+    Some(r) => r,
+    None => return settings,
+  };
+  let presets = match db.active_presets(guild, channel){
+    Ok(r) => r,
+    Err(err) => {
+      println!("Couldn't retrieve sampler presets from the database: {}", err);
+      return settings;
+    }
+  };
+  for preset_json in presets{
+    match serde_json::from_str::<SamplerPreset>(&preset_json){
+      Ok(preset) => settings.apply(&preset),
+      Err(err) => println!("Stored sampler preset is not valid: {}", err),
+    }
+  }
+  settings
+}
+
+fn assigned_preset(db: &mut dyn Database, guild: u64, channel: u64) -> Option<String>{
+  match db.assigned_preset(guild, channel){
+    Ok(r) => r,
+    Err(err) => {
+      println!("Couldn't retrieve sampler preset assignment from the database: {}", err);
+      None
+    }
+  }
+}
+
+fn preset_overview(db: &mut dyn Database, guild: u64, channel: u64) -> PresetOverview{
+  let presets = match db.presets(guild){
+    Ok(presets) => presets.into_iter()
+      .filter_map(|(name, preset_json)| Some((name, serde_json::from_str(&preset_json).ok()?)))
+      .collect(),
+    Err(err) => {
+      println!("Couldn't retrieve sampler presets from the database: {}", err);
+      Vec::new()
+    }
+  };
+  PresetOverview{
+    presets,
+    guild_preset: assigned_preset(db, guild, GUILD_WIDE),
+    channel_preset: assigned_preset(db, guild, channel),
+  }
+}
+
+//the channel's newest summary and the last row it covers
+fn latest_summary(db: &mut dyn Database, channel: u64) -> Option<(String, i64)>{
+  db.latest_summary(channel).unwrap_or_else(|err| {
+    println!("Couldn't retrieve summary from the database: {}", err);
+    None
+  })
+}
+
+fn load_facts(db: &mut dyn Database, guild: u64, user_id: u64) -> Vec<String>{
+  db.load_facts(guild, user_id).unwrap_or_else(|err| {
+    println!("Couldn't retrieve user facts from the database: {}", err);
+    Vec::new()
+  })
+}
+
+//channels that have a summary being written, so the same span isn't handed over twice
+struct SummaryQueue{
+  tx: Option<UnboundedSender<SummaryRequest>>,
+  pending: HashSet<u64>,
+  //channels whose summary was being written when someone was forgotten, it may hold what they said
+  outdated: HashSet<u64>,
+}
+
+impl SummaryQueue{
+  //SUMMARIZE_HISTORY=false keeps the backend from being asked for summaries at all
+  fn from_env(storage_tx: UnboundedSender<StorageRequest>, generator: Arc<Generator>) -> Self{
+    let enabled = std::env::var("SUMMARIZE_HISTORY").ok()
+      .and_then(|r| r.trim().parse().ok())
+      .unwrap_or(true);
+    Self{
+      tx: enabled.then(|| spawn_summary_thread(storage_tx, generator)),
+      pending: HashSet::new(),
+      outdated: HashSet::new(),
+    }
+  }
+
+  fn forget(&mut self){
+    self.outdated.extend(self.pending.iter().copied());
+  }
+
+  //sends the oldest unsummarized span off once the history no longer fits in the context window
+  fn check(&mut self, db: &mut dyn Database, message: &StorageMessage, bot_name: &str, settings: &SamplerSettings, previous: Option<(String, i64)>){
+    let channel = message.channel;
+    let tx = match &self.tx{
+      Some(r) => r,
+      None => return,
+    };
+    if self.pending.contains(&channel){
+      return;
+    }
+    let covered = previous.as_ref().map(|(_, last)| *last).unwrap_or(0);
+    let rows = match db.unsummarized(channel, covered, CONTEXT_FETCH_LIMIT){
+      Ok(r) => r,
+      Err(err) => {
+        println!("Couldn't retrieve messages to summarize from the database: {}", err);
+        return;
+      }
+    };
+    let (ids, messages): (Vec<i64>, Vec<StoredMessage>) = rows.into_iter().unzip();
+    let span = summary_span(&messages, settings.prompt_budget());
+    if span == 0{
+      return;
+    }
+    if let Err(err) = tx.send(SummaryRequest{
+      guild: message.guild,
+      channel,
+      last_message: ids[span-1],
+      previous: previous.map(|(summary, _)| summary),
+      messages: messages.into_iter().take(span).collect(),
+      bot_name: bot_name.to_string(),
+      settings: settings.clone(),
+    }){
+      println!("Unable to send old messages to summary thread: {}", err);
+      return;
+    }
+    self.pending.insert(channel);
+  }
+}
+
+//hands a row to the embedding thread, rows outside a guild are never recalled so they're skipped
+fn queue_embedding(db: &mut dyn Database, embeddings: Option<&UnboundedSender<EmbeddingJob>>, discord_id: u64){
+  let embeddings = match embeddings{
+    Some(r) => r,
+    None => return,
+  };
+  match db.embedding_job(discord_id){
+    Ok(Some(job)) => {
+      if let Err(err) = embeddings.send(job){
+        println!("Unable to send message to embedding thread: {}", err);
+      }
+    },
+    Ok(None) => {},
+    Err(err) => println!("Couldn't retrieve message to embed from the database: {}", err),
+  }
+}
+
+//counts what each member says and hands their recent messages over for fact extraction every so often
+struct FactQueue{
+  tx: Option<UnboundedSender<FactRequest>>,
+  interval: usize,
+  counts: HashMap<(u64, u64), usize>,
+  next_pass: u64,
+  //the first pass that may still write facts for a user, anything older was in flight when they
+  //cleared or forgot and would bring back what they asked to be rid of
+  cleared: HashMap<u64, u64>,
+}
+
+impl FactQueue{
+  //FACT_EXTRACTION_INTERVAL is how many messages a member sends between extraction passes, 0 turns it off
+  fn from_env(storage_tx: UnboundedSender<StorageRequest>, generator: Arc<Generator>) -> Self{
+    let interval = std::env::var("FACT_EXTRACTION_INTERVAL").ok()
+      .and_then(|r| r.trim().parse().ok())
+      .unwrap_or(20);
+    Self{
+      tx: (interval > 0).then(|| spawn_fact_thread(storage_tx, generator)),
+      interval,
+      counts: HashMap::new(),
+      next_pass: 0,
+      cleared: HashMap::new(),
+    }
+  }
+
+  //kept per user rather than per member so forgetting across every guild is one entry,
+  //a pass in another guild being dropped only means it runs again later
+  fn clear(&mut self, user_id: u64){
+    self.cleared.insert(user_id, self.next_pass);
+    self.counts.retain(|(_, user), _| *user != user_id);
+  }
+
+  fn is_stale(&self, user_id: u64, pass: u64) -> bool{
+    self.cleared.get(&user_id).is_some_and(|first| pass < *first)
+  }
+
+  fn note(&mut self, db: &mut dyn Database, message: &StorageMessage){
+    let (tx, guild, user_id) = match (&self.tx, message.guild, message.user_id){
+      (Some(tx), Some(guild), Some(user_id)) => (tx, guild, user_id),
+      _ => return,
+    };
+    let count = self.counts.entry((guild, user_id)).or_default();
+    *count += 1;
+    if *count < self.interval{
+      return;
+    }
+    *count = 0;
+    let messages = match db.member_messages(guild, user_id, self.interval){
+      Ok(r) => r,
+      Err(err) => {
+        println!("Couldn't retrieve messages for fact extraction from the database: {}", err);
+        return;
+      }
+    };
+    let pass = self.next_pass;
+    self.next_pass += 1;
+    if let Err(err) = tx.send(FactRequest{
+      guild,
+      user_id,
+      pass,
+      name: message.author.clone(),
+      known: load_facts(db, guild, user_id),
+      messages,
+      settings: active_settings(db, Some(guild), message.channel),
+    }){
+      println!("Unable to send messages to fact thread: {}", err);
+    }
+  }
+}
+
+//everything a stored message sets in motion besides the insert itself
+struct StorageWorker{
+  kobold_tx: UnboundedSender<KoboldRequest>,
+  personas: Arc<PersonaLibrary>,
+  summaries: SummaryQueue,
+  facts: FactQueue,
+  embeddings: Option<UnboundedSender<EmbeddingJob>>,
 }
 
-pub fn create_storage_thread() -> UnboundedSender<StorageMessage>{
-  let (sqlite_tx, mut sqlite_rx) = tokio_channel::<StorageMessage>();
-  let kobold_tx = spawn_kobold_thread(sqlite_tx.clone());
-  tokio::spawn(async move{
-    let conn = Connection::open("./memory.db").expect("Not able to open SQLITE db called memory.db");
-    conn.execute(
-      "CREATE TABLE IF NOT EXISTS messages(
-        id INTEGER PRIMARY KEY,
-        author TEXT NOT NULL,
-        message TEXT NOT NULL,
-        channel INTEGER NOT NULL
-    )", ()).unwrap();
-    while let Some(message_to_store) = sqlite_rx.recv().await{
-      let possible_activation_message = message_to_store.clone();
-      if let Err(err) = conn.execute("INSERT INTO messages (author, message, channel) VALUES (?1, ?2, ?3)", (
-        message_to_store.author,
-        message_to_store.message,
-        message_to_store.channel
-      )){
-        println!("Can't insert message into SQLITE3 database: {}", err);
+impl StorageWorker{
+  //the whole batch goes in as one transaction, then each message is handled as if it arrived alone.
+  //a failed batch is retried row by row so one bad message doesn't take the others down with it
+  fn store_messages(&mut self, db: &mut dyn Database, batch: Vec<StorageMessage>){
+    let ids: Vec<Option<i64>> = match db.insert_messages(&batch){
+      Ok(r) => r.into_iter().map(Some).collect(),
+      Err(err) if batch.len() > 1 => {
+        println!("Can't insert {} messages into the database at once, inserting them one by one: {}", batch.len(), err);
+        batch.iter().map(|message| match db.insert_messages(std::slice::from_ref(message)){
+          Ok(r) => r.first().copied(),
+          Err(err) => {
+            println!("Can't insert message into the database: {}", err);
+            None
+          }
+        }).collect()
+      },
+      Err(err) => {
+        println!("Can't insert message into the database: {}", err);
+        vec![None]
       }
-      let activation_phrase = std::env::var("ACTIVATION_PHRASE").unwrap().to_lowercase();
-      let bot_name = std::env::var("BOT_NAME").unwrap();
-      if possible_activation_message.message.to_lowercase().contains(&activation_phrase)
-      && possible_activation_message.author != bot_name{
-        let mut stmt = conn.prepare("SELECT author, message FROM messages WHERE channel=?1").unwrap();
-        let stored_message_iter = match stmt.query_map(
-          params![possible_activation_message.channel], 
-          |row|{
-            Ok(StoredMessage{
-              author: row.get(0)?,
-              message: row.get(1)?,
-            })
+    };
+    for (message_to_store, id) in batch.into_iter().zip(ids){
+      //a message that never made it in has nothing to answer from
+      let id = match id{
+        Some(r) => r,
+        None => continue,
+      };
+      if let (Some(embeddings), Some(guild)) = (&self.embeddings, message_to_store.guild){
+        if !message_to_store.message.trim().is_empty(){
+          if let Err(err) = embeddings.send(EmbeddingJob{
+            message: id,
+            guild,
+            text: message_to_store.message.clone(),
           }){
+            println!("Unable to send message to embedding thread: {}", err);
+          }
+        }
+      }
+      self.facts.note(db, &message_to_store);
+      self.activate(db, id, message_to_store);
+    }
+  }
+
+  //answers the message if it calls for the bot, from the history as it was when the message came in
+  //so later messages of the same batch don't end up in front of it
+  fn activate(&mut self, db: &mut dyn Database, id: i64, message_to_store: StorageMessage){
+    let activation_phrase = std::env::var("ACTIVATION_PHRASE").unwrap().to_lowercase();
+    let persona = self.personas.resolve(guild_persona(db, message_to_store.guild).as_deref()).clone();
+    if !message_to_store.message.to_lowercase().contains(&activation_phrase)
+    || message_to_store.author == persona.name{
+      return;
+    }
+    //only rows the summary doesn't cover yet are sent verbatim, the prompt builder trims the rest
+    let summary = latest_summary(db, message_to_store.channel);
+    let covered = summary.as_ref().map(|(_, last)| *last).unwrap_or(0);
+    let messages = match db.context(message_to_store.channel, covered, id, CONTEXT_FETCH_LIMIT){
+      Ok(r) => r,
+      Err(err) => {
+        println!("Couldn't retrieve messages from the database: {}", err);
+        return;
+      }
+    };
+    let settings = active_settings(db, message_to_store.guild, message_to_store.channel);
+    self.summaries.check(db, &message_to_store, &persona.name, &settings, summary.clone());
+    if let Err(err) = self.kobold_tx.send(KoboldRequest{
+      origin_channel: message_to_store.channel,
+      guild: message_to_store.guild,
+      persona,
+      settings,
+      messages,
+      summary: summary.map(|(summary, _)| summary),
+      speaker: message_to_store.author.clone(),
+      facts: match (message_to_store.guild, message_to_store.user_id){
+        (Some(guild), Some(user_id)) => load_facts(db, guild, user_id),
+        _ => Vec::new(),
+      },
+      regenerate: None,
+    }){
+      println!("Unable to send context to kobold thread: {}", err);
+    }
+  }
+}
+
+//idle connections by the database file they belong to
+type IdleConnections = HashMap<Option<u64>, Vec<Box<dyn Database>>>;
+
+//extra connections for the slow lookups, so a search or an export never holds up the writer
+#[derive(Clone)]
+struct ReadPool{
+  idle: Arc<Mutex<IdleConnections>>,
+  cipher: Option<MessageCipher>,
+}
+
+impl ReadPool{
+  fn new(cipher: Option<MessageCipher>) -> Self{
+    Self{
+      idle: Arc::default(),
+      cipher,
+    }
+  }
+
+  //runs the lookup on tokio's blocking pool, a connection that can't be opened drops the job and with it the reply
+  fn run<F>(&self, guild: Option<u64>, job: F) where F: FnOnce(&mut dyn Database) + Send + 'static{
+    let idle = self.idle.clone();
+    let cipher = self.cipher.clone();
+    let key = database_key(guild);
+    tokio::task::spawn_blocking(move ||{
+      let pooled = idle.lock().unwrap().get_mut(&key).and_then(|idle| idle.pop());
+      let mut db = match pooled{
+        Some(r) => r,
+        None => match open_read_connection(key, cipher.as_ref()){
+          Ok(r) => r,
+          Err(err) => {
+            println!("Couldn't open a read connection to the database: {}", err);
+            return;
+          }
+        },
+      };
+      job(db.as_mut());
+      let mut idle = idle.lock().unwrap();
+      let idle = idle.entry(key).or_default();
+      if idle.len() < READ_POOL_SIZE{
+        idle.push(db);
+      }
+    });
+  }
+}
+
+//logs a failed write, the requests that make them don't wait for an answer
+fn log_write(result: DatabaseResult<()>, what: &str){
+  if let Err(err) = result{
+    println!("Can't {} in the database: {}", what, err);
+  }
+}
+
+//the kobold sender is handed back too so regenerations can skip the history lookup.
+//database calls block, so the writer gets a thread of its own instead of a slot on the async runtime
+pub fn create_storage_thread(mut databases: Databases, generator: Arc<Generator>, personas: Arc<PersonaLibrary>, generations: Arc<ActiveGenerations>, swipes: Arc<Swipes>) -> (UnboundedSender<StorageRequest>, UnboundedSender<KoboldRequest>){
+  let (sqlite_tx, mut sqlite_rx) = tokio_channel::<StorageRequest>();
+  let kobold_tx = spawn_kobold_thread(sqlite_tx.clone(), generator.clone(), generations, swipes);
+  let worker_kobold_tx = kobold_tx.clone();
+  let summary_storage_tx = sqlite_tx.clone();
+  let embedding_storage_tx = sqlite_tx.clone();
+  let fact_storage_tx = sqlite_tx.clone();
+  spawn_retention_timer(sqlite_tx.clone());
+  let runtime = tokio::runtime::Handle::current();
+  std::thread::Builder::new().name("storage".to_string()).spawn(move ||{
+    //the queues below spawn their own tasks onto the runtime
+    let _runtime = runtime.enter();
+    let readers = ReadPool::new(databases.cipher().cloned());
+    let mut worker = StorageWorker{
+      kobold_tx: worker_kobold_tx,
+      personas,
+      summaries: SummaryQueue::from_env(summary_storage_tx, generator.clone()),
+      facts: FactQueue::from_env(fact_storage_tx, generator.clone()),
+      embeddings: EmbeddingClient::from_env().map(|client| spawn_embedding_thread(embedding_storage_tx, client)),
+    };
+    let mut next = None;
+    loop{
+      let request = match next.take().or_else(|| sqlite_rx.blocking_recv()){
+        Some(r) => r,
+        None => break,
+      };
+      match request{
+        StorageRequest::PruneAll => {
+          let _ = databases.each(|_, db|{
+            prune_all(db);
+            Ok(())
+          });
+          continue;
+        },
+        StorageRequest::ForgetUser{user_id, guild: None, anonymize, reply} => {
+          worker.facts.clear(user_id);
+          worker.summaries.forget();
+          let mut forgotten = 0;
+          let _ = databases.each(|_, db|{
+            match db.forget_user(user_id, None, anonymize){
+              Ok(r) => forgotten += r,
+              Err(err) => println!("Can't forget user in the database: {}", err),
+            }
+            Ok(())
+          });
+          let _ = reply.send(forgotten);
+          continue;
+        },
+        _ => {},
+      }
+      let guild = request.guild();
+      let db = match databases.get(guild){
+        Ok(r) => r,
+        Err(err) => {
+          //dropping the request drops its reply too, so nobody is left waiting
+          println!("Not able to open the database of guild {:?}: {}", guild, err);
+          continue;
+        }
+      };
+      match request{
+        //whatever piled up behind a message while voice traffic is heavy goes in together,
+        //as long as it's headed for the same database
+        StorageRequest::Store(message_to_store) => {
+          let key = database_key(message_to_store.guild);
+          let mut batch = vec![message_to_store];
+          while batch.len() < STORE_BATCH_LIMIT{
+            match sqlite_rx.try_recv(){
+              Ok(StorageRequest::Store(r)) if database_key(r.guild) == key => batch.push(r),
+              Ok(other) => {
+                next = Some(other);
+                break;
+              },
+              Err(_) => break,
+            }
+          }
+          worker.store_messages(db, batch);
+        },
+        StorageRequest::SetFacts{guild, user_id, facts, pass} => {
+          match pass{
+            Some(pass) if worker.facts.is_stale(user_id, pass) => continue,
+            Some(_) => {},
+            None => worker.facts.clear(user_id),
+          }
+          log_write(db.set_facts(guild, user_id, &facts), "save user facts");
+        },
+        StorageRequest::ListFacts{guild, user_id, reply} => {
+          let _ = reply.send(load_facts(db, guild, user_id));
+        },
+        StorageRequest::StoreEmbedding{message, guild, model, vector} => log_write(db.store_embedding(message, guild, &model, &vector), "save embedding"),
+        StorageRequest::GetRetention{guild, reply} => {
+          let policy = db.load_policy(guild).unwrap_or_else(|err| {
+            println!("Couldn't retrieve retention policy from the database: {}", err);
+            RetentionPolicy::default()
+          });
+          let _ = reply.send(policy);
+        },
+        StorageRequest::SetRetention{guild, policy} => log_write(db.save_policy(guild, &policy), "save retention policy"),
+        StorageRequest::PreviewPrune{guild, reply} => readers.run(Some(guild), move |db|{
+          let _ = reply.send(prune_report(db, guild));
+        }),
+        StorageRequest::PruneAll => {},
+        StorageRequest::Export{guild, scope, format, reply} => readers.run(Some(guild), move |db|{
+          let _ = reply.send(export(db, scope, format).map_err(|err| err.to_string()));
+        }),
+        StorageRequest::Import{data, target, reply} => {
+          let imported = parse_records(&data).and_then(|records| import(db, records, target.as_ref(), worker.embeddings.as_ref()));
+          let _ = reply.send(imported);
+        },
+        StorageRequest::ForgetUser{user_id, guild, anonymize, reply} => {
+          worker.facts.clear(user_id);
+          worker.summaries.forget();
+          let forgotten = match db.forget_user(user_id, guild, anonymize){
             Ok(r) => r,
             Err(err) => {
-              println!("Couldn't retrieve messages from sqlite database: {}", err);
-              continue;
+              println!("Can't forget user in the database: {}", err);
+              0
             }
           };
-        let messages: Vec<StoredMessage> = stored_message_iter.map(|x| {x.unwrap()}).collect();
-        if let Err(err) = kobold_tx.send(KoboldRequest{
-          origin_channel: possible_activation_message.channel,
-          messages,
-        }){
-          println!("Unable to send context to kobold thread: {}", err);
-        }
+          let _ = reply.send(forgotten);
+        },
+        StorageRequest::Search{filter, reply} => readers.run(Some(filter.guild), move |db|{
+          let hits = db.search(&filter, SEARCH_RESULT_LIMIT).unwrap_or_else(|err| {
+            println!("Couldn't search messages in the database: {}", err);
+            Vec::new()
+          });
+          let _ = reply.send(hits);
+        }),
+        StorageRequest::Recall{guild, model, vector, limit, window, reply} => readers.run(Some(guild), move |db|{
+          let recalled = db.recall(guild, &model, &vector, limit, window).unwrap_or_else(|err| {
+            println!("Couldn't retrieve embeddings from the database: {}", err);
+            Vec::new()
+          });
+          let _ = reply.send(recalled);
+        }),
+        StorageRequest::StoreSummary{channel, last_message, summary, ..} => {
+          worker.summaries.pending.remove(&channel);
+          //the span gets summarized again from what's left of it the next time the channel is busy
+          if worker.summaries.outdated.remove(&channel){
+            continue;
+          }
+          if let Some(summary) = summary{
+            log_write(db.store_summary(channel, last_message, &summary, unix_now()), "save summary");
+          }
+        },
+        StorageRequest::ReplaceBotMessage{discord_id, message, parts, ..} => {
+          log_write(db.replace_bot_message(discord_id, &message, &parts), "replace swiped message");
+          queue_embedding(db, worker.embeddings.as_ref(), discord_id);
+        },
+        StorageRequest::EditMessage{discord_id, message, edited_at, ..} => {
+          log_write(db.edit_message(discord_id, &message, edited_at), "apply message edit");
+          queue_embedding(db, worker.embeddings.as_ref(), discord_id);
+        },
+        StorageRequest::DeleteMessages{discord_ids, deleted_at, ..} => log_write(db.delete_messages(&discord_ids, deleted_at), "tombstone deleted messages"),
+        StorageRequest::SetPersona{guild, persona} => log_write(db.set_persona(guild, &persona), "save guild persona"),
+        StorageRequest::SavePreset{guild, name, preset} => {
+          let preset_json = serde_json::to_string(&preset).unwrap();
+          log_write(db.save_preset(guild, &name, &preset_json), "save sampler preset");
+        },
+        StorageRequest::DeletePreset{guild, name, reply} => {
+          let deleted = match db.delete_preset(guild, &name){
+            Ok(r) => r,
+            Err(err) => {
+              println!("Can't delete sampler preset from the database: {}", err);
+              false
+            }
+          };
+          let _ = reply.send(deleted);
+        },
+        StorageRequest::ListPresets{guild, channel, reply} => {
+          let _ = reply.send(preset_overview(db, guild, channel));
+        },
+        StorageRequest::AssignPreset{guild, channel, preset} => {
+          log_write(db.assign_preset(guild, channel.unwrap_or(GUILD_WIDE), preset.as_deref()), "save sampler preset assignment");
+        },
       }
     }
-  });
-  sqlite_tx
-}
\ No newline at end of file
+  }).expect("Not able to start the storage thread");
+  (sqlite_tx, kobold_tx)
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{
+  UnboundedSender,
+  unbounded_channel as tokio_channel,
+};
+
+use crate::{
+  backend::{
+    GenerationRequest,
+    SamplerSettings,
+  },
+  kobold::{
+    Generator,
+    StoredMessage,
+  },
+  prompt::estimate_tokens,
+  storage::StorageRequest,
+};
+
+//a span of history that fell out of the context window, to be folded into the channel's summary
+pub struct SummaryRequest{
+  pub guild: Option<u64>,
+  pub channel: u64,
+  //id of the newest row in the span, everything up to it is covered once the summary is stored
+  pub last_message: i64,
+  pub previous: Option<String>,
+  pub messages: Vec<StoredMessage>,
+  pub bot_name: String,
+  pub settings: SamplerSettings,
+}
+
+fn message_cost(message: &StoredMessage) -> usize{
+  estimate_tokens(&message.author) + estimate_tokens(&message.message) + 2
+}
+
+//how many of the oldest rows should be summarized so the rest fits in the budget with room to spare,
+//capped so the span itself still fits into one summarization prompt
+pub fn summary_span(messages: &[StoredMessage], budget: usize) -> usize{
+  let total: usize = messages.iter().map(message_cost).sum();
+  if total <= budget{
+    return 0;
+  }
+  let mut kept = 0;
+  let mut split = messages.len();
+  while split > 0 && kept + message_cost(&messages[split-1]) <= budget/2{
+    split -= 1;
+    kept += message_cost(&messages[split]);
+  }
+  let mut used = 0;
+  let mut end = 0;
+  while end < split && (end == 0 || used + message_cost(&messages[end]) <= budget/2){
+    used += message_cost(&messages[end]);
+    end += 1;
+  }
+  end
+}
+
+fn summary_prompt(request: &SummaryRequest) -> GenerationRequest{
+  let mut system = format!(
+    "You keep the long term memory of {}, a discord bot. Summarize the conversation you are given in a few short paragraphs. \
+    Keep who said what, names, decisions, promises and anything that will matter later. Leave out greetings and small talk.",
+    request.bot_name,
+  );
+  if let Some(previous) = &request.previous{
+    system.push_str("\n\nFold this summary of what came before into yours:\n");
+    system.push_str(previous);
+  }
+  let transcript = request.messages.iter()
+    .map(|msg| format!("{}: {}", msg.author, msg.message))
+    .collect::<Vec<String>>()
+    .join("\n");
+  GenerationRequest{
+    system,
+    messages: vec![StoredMessage{
+      author: "Conversation".to_string(),
+      message: transcript,
+    }],
+    bot_name: "Summary".to_string(),
+    summary: None,
+    recalled: Vec::new(),
+    speaker: String::new(),
+    facts: Vec::new(),
+    settings: request.settings.clone(),
+    genkey: format!("summary-{}-{}", request.channel, request.last_message),
+  }
+}
+
+//summaries are written one at a time in the background, a failed one is simply retried the next
+//time the channel asks for a reply
+pub fn spawn_summary_thread(storage_tx: UnboundedSender<StorageRequest>, generator: Arc<Generator>) -> UnboundedSender<SummaryRequest>{
+  let (summary_tx, mut summary_rx) = tokio_channel::<SummaryRequest>();
+  tokio::spawn(async move{
+    while let Some(request) = summary_rx.recv().await{
+      let summary = match generator.background(&summary_prompt(&request)).await{
+        Ok(r) if !r.trim().is_empty() => Some(r.trim().to_string()),
+        Ok(_) => None,
+        Err(err) => {
+          println!("Unable to summarize old messages with {} server: {}", generator.name(), err);
+          None
+        }
+      };
+      if let Err(err) = storage_tx.send(StorageRequest::StoreSummary{
+        guild: request.guild,
+        channel: request.channel,
+        last_message: request.last_message,
+        summary,
+      }){
+        println!("Unable to send summary to storage thread: {}", err);
+      }
+    }
+  });
+  summary_tx
+}
@@ -0,0 +1,131 @@
+use std::{
+  collections::{
+    HashMap,
+    VecDeque,
+  },
+  sync::Mutex,
+};
+
+use crate::kobold::KoboldRequest;
+
+//replies older than this many can't be regenerated or swiped anymore
+const MAX_TRACKED_REPLIES: usize = 500;
+
+//every alternative written for one reply, along with the context that produced them
+struct SwipeState{
+  request: KoboldRequest,
+  alternatives: Vec<String>,
+  index: usize,
+  messages: Vec<u64>,
+}
+
+//what a reply should show after it changed
+pub struct SwipeView{
+  pub channel: u64,
+  pub guild: Option<u64>,
+  pub previous: String,
+  pub text: String,
+  pub index: usize,
+  pub count: usize,
+  pub messages: Vec<u64>,
+}
+
+impl SwipeState{
+  fn view(&self, previous: String) -> SwipeView{
+    SwipeView{
+      channel: self.request.origin_channel,
+      guild: self.request.guild,
+      previous,
+      text: self.alternatives[self.index].clone(),
+      index: self.index,
+      count: self.alternatives.len(),
+      messages: self.messages.clone(),
+    }
+  }
+}
+
+#[derive(Default)]
+struct SwipeStore{
+  states: HashMap<u64, SwipeState>,
+  order: VecDeque<u64>,
+}
+
+//replies are keyed by the first discord message they were written into
+#[derive(Default)]
+pub struct Swipes{
+  store: Mutex<SwipeStore>,
+}
+
+impl Swipes{
+  pub fn record(&self, anchor: u64, request: KoboldRequest, text: String, messages: Vec<u64>) -> SwipeView{
+    let state = SwipeState{
+      request,
+      alternatives: vec![text.clone()],
+      index: 0,
+      messages,
+    };
+    let view = state.view(text);
+    let mut store = self.store.lock().unwrap();
+    store.states.insert(anchor, state);
+    store.order.push_back(anchor);
+    while store.order.len() > MAX_TRACKED_REPLIES{
+      if let Some(oldest) = store.order.pop_front(){
+        store.states.remove(&oldest);
+      }
+    }
+    view
+  }
+
+  //a regenerated reply becomes the newest alternative and the one on show
+  pub fn add_alternative(&self, anchor: u64, text: String, messages: Vec<u64>) -> Option<SwipeView>{
+    let mut store = self.store.lock().unwrap();
+    let state = store.states.get_mut(&anchor)?;
+    let previous = state.alternatives[state.index].clone();
+    state.alternatives.push(text);
+    state.index = state.alternatives.len() - 1;
+    state.messages = messages;
+    Some(state.view(previous))
+  }
+
+  pub fn step(&self, anchor: u64, forward: bool) -> Option<SwipeView>{
+    let mut store = self.store.lock().unwrap();
+    let state = store.states.get_mut(&anchor)?;
+    let previous = state.alternatives[state.index].clone();
+    if forward && state.index + 1 < state.alternatives.len(){
+      state.index += 1;
+    }else if !forward && state.index > 0{
+      state.index -= 1;
+    }
+    Some(state.view(previous))
+  }
+
+  pub fn current(&self, anchor: u64) -> Option<SwipeView>{
+    let store = self.store.lock().unwrap();
+    let state = store.states.get(&anchor)?;
+    Some(state.view(state.alternatives[state.index].clone()))
+  }
+
+  //the messages a reply ended up spread over after it was rewritten
+  pub fn set_messages(&self, anchor: u64, messages: Vec<u64>){
+    if let Some(state) = self.store.lock().unwrap().states.get_mut(&anchor){
+      state.messages = messages;
+    }
+  }
+
+  //the context kept for regenerating can hold a user's messages under whatever name they had, so
+  //forgetting someone drops every reply within reach, guild None being all of them
+  pub fn forget(&self, guild: Option<u64>){
+    let mut store = self.store.lock().unwrap();
+    store.states.retain(|_, state| guild.is_some() && state.request.guild != guild);
+    let SwipeStore{states, order} = &mut *store;
+    order.retain(|anchor| states.contains_key(anchor));
+  }
+
+  //the original context, ready to be sent back through the generation worker
+  pub fn regenerate_request(&self, anchor: u64) -> Option<KoboldRequest>{
+    let store = self.store.lock().unwrap();
+    let mut request = store.states.get(&anchor)?.request.clone();
+    request.regenerate = Some(anchor);
+    Some(request)
+  }
+}
@@ -0,0 +1,239 @@
+use base64::{
+  engine::general_purpose::STANDARD as BASE64,
+  Engine,
+};
+use serde::Deserialize;
+
+use crate::persona::Persona;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Deserialize)]
+struct TavernCardData{
+  name: String,
+  #[serde(default)]
+  description: String,
+  #[serde(default)]
+  personality: String,
+  #[serde(default)]
+  scenario: String,
+  #[serde(default)]
+  first_mes: String,
+  #[serde(default)]
+  mes_example: String,
+  #[serde(default)]
+  system_prompt: String,
+}
+
+//v2 and v3 cards nest everything under data, v1 cards are flat
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TavernCard{
+  Nested{
+    data: TavernCardData,
+  },
+  Flat(TavernCardData),
+}
+
+//tavern cards talk about {{char}} and {{user}}, in a server full of people the user is whoever is talking
+fn fill_macros(text: &str, name: &str) -> String{
+  text.replace("{{char}}", name)
+    .replace("<BOT>", name)
+    .replace("{{user}}", "User")
+    .replace("<USER>", "User")
+}
+
+//a card is recognised by the keys persona files never have
+pub fn is_card_json(json: &str) -> bool{
+  match serde_json::from_str::<serde_json::Value>(json){
+    Ok(serde_json::Value::Object(map)) => {
+      map.contains_key("spec") || map.contains_key("first_mes") || map.contains_key("data")
+    },
+    _ => false,
+  }
+}
+
+pub fn parse_card_json(json: &str) -> Result<Persona, String>{
+  let card: TavernCard = serde_json::from_str(json)
+    .map_err(|err| format!("Not a valid tavern card: {}", err))?;
+  let data = match card{
+    TavernCard::Nested{data} => data,
+    TavernCard::Flat(data) => data,
+  };
+  let name = data.name.trim().to_string();
+  if name.is_empty(){
+    return Err("Tavern card has no name".to_string());
+  }
+  //<START> only separates example chats for tavern's own parser
+  let example_dialogue = data.mes_example
+    .lines()
+    .filter(|line| line.trim() != "<START>")
+    .collect::<Vec<&str>>()
+    .join("\n");
+  Ok(Persona{
+    description: fill_macros(&data.description, &name),
+    personality: fill_macros(&data.personality, &name),
+    scenario: fill_macros(&data.scenario, &name),
+    system_prompt: fill_macros(&data.system_prompt, &name),
+    example_dialogue: fill_macros(example_dialogue.trim(), &name),
+    greeting: fill_macros(&data.first_mes, &name),
+    name,
+  })
+}
+
+//the png chunk checksum covers the chunk type and data
+fn crc32(bytes: &[u8]) -> u32{
+  let mut crc = 0xffff_ffffu32;
+  for byte in bytes{
+    crc ^= *byte as u32;
+    for _ in 0..8{
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+//walks the png chunks looking for the tEXt chunk tavern hides the base64 card json in,
+//ccv3 is preferred over chara when a card carries both
+pub fn parse_card_png(bytes: &[u8]) -> Result<Persona, String>{
+  if !bytes.starts_with(PNG_SIGNATURE){
+    return Err("Not a png file".to_string());
+  }
+  let mut offset = PNG_SIGNATURE.len();
+  let mut chara = None;
+  while offset + 8 <= bytes.len(){
+    let length = u32::from_be_bytes([bytes[offset], bytes[offset+1], bytes[offset+2], bytes[offset+3]]) as usize;
+    let chunk_type = &bytes[offset+4..offset+8];
+    let data_start = offset + 8;
+    //a crafted length must not wrap around on 32 bit targets
+    let data_end = match data_start.checked_add(length){
+      Some(end) if end <= bytes.len().saturating_sub(4) => end,
+      _ => return Err("Png file is truncated".to_string()),
+    };
+    if chunk_type == b"tEXt"{
+      let data = &bytes[data_start..data_end];
+      let stored = u32::from_be_bytes([bytes[data_end], bytes[data_end+1], bytes[data_end+2], bytes[data_end+3]]);
+      if crc32(&bytes[offset+4..data_end]) != stored{
+        return Err("Png text chunk is corrupted".to_string());
+      }
+      if let Some(split) = data.iter().position(|b| *b == 0){
+        let keyword = &data[..split];
+        let text = &data[split+1..];
+        if keyword == b"ccv3"{
+          chara = Some(text);
+          break;
+        }else if keyword == b"chara"{
+          chara = Some(text);
+        }
+      }
+    }else if chunk_type == b"IEND"{
+      break;
+    }
+    offset = data_end + 4;
+  }
+  let encoded = chara.ok_or("Png file has no embedded character card".to_string())?;
+  let decoded = BASE64.decode(encoded)
+    .map_err(|err| format!("Embedded character card is not valid base64: {}", err))?;
+  let json = String::from_utf8(decoded)
+    .map_err(|err| format!("Embedded character card is not valid utf-8: {}", err))?;
+  parse_card_json(&json)
+}
+
+#[cfg(test)]
+mod tests{
+  use super::*;
+
+  fn chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8>{
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    chunk
+  }
+
+  fn text_chunk(keyword: &str, json: &str) -> Vec<u8>{
+    let mut data = keyword.as_bytes().to_vec();
+    data.push(0);
+    data.extend_from_slice(BASE64.encode(json).as_bytes());
+    chunk(b"tEXt", &data)
+  }
+
+  fn png(chunks: &[Vec<u8>]) -> Vec<u8>{
+    let mut bytes = PNG_SIGNATURE.to_vec();
+    for chunk in chunks{
+      bytes.extend_from_slice(chunk);
+    }
+    bytes.extend_from_slice(&chunk(b"IEND", &[]));
+    bytes
+  }
+
+  #[test]
+  fn crc_matches_known_value(){
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+  }
+
+  #[test]
+  fn flat_v1_card(){
+    let persona = parse_card_json(r#"{"name": "Lily", "description": "{{char}} greets {{user}}", "first_mes": "hi"}"#).unwrap();
+    assert_eq!(persona.name, "Lily");
+    assert_eq!(persona.description, "Lily greets User");
+    assert_eq!(persona.greeting, "hi");
+  }
+
+  #[test]
+  fn nested_v2_and_v3_cards(){
+    for spec in ["chara_card_v2", "chara_card_v3"]{
+      let json = format!(r#"{{"spec": "{}", "data": {{"name": "Lily", "mes_example": "<START>\n{{{{char}}}}: hello"}}}}"#, spec);
+      assert!(is_card_json(&json));
+      let persona = parse_card_json(&json).unwrap();
+      assert_eq!(persona.name, "Lily");
+      assert_eq!(persona.example_dialogue, "Lily: hello");
+    }
+  }
+
+  #[test]
+  fn card_without_name_is_rejected(){
+    assert!(parse_card_json(r#"{"data": {"name": "  "}}"#).is_err());
+  }
+
+  #[test]
+  fn ccv3_is_preferred_over_chara(){
+    let bytes = png(&[
+      text_chunk("chara", r#"{"name": "Old"}"#),
+      text_chunk("ccv3", r#"{"data": {"name": "New"}}"#),
+    ]);
+    assert_eq!(parse_card_png(&bytes).unwrap().name, "New");
+    let bytes = png(&[text_chunk("chara", r#"{"name": "Old"}"#)]);
+    assert_eq!(parse_card_png(&bytes).unwrap().name, "Old");
+  }
+
+  #[test]
+  fn truncated_chunk_is_rejected(){
+    let mut bytes = png(&[text_chunk("chara", r#"{"name": "Lily"}"#)]);
+    bytes.truncate(PNG_SIGNATURE.len() + 12);
+    assert!(parse_card_png(&bytes).is_err());
+  }
+
+  #[test]
+  fn oversized_length_is_rejected(){
+    let mut bytes = PNG_SIGNATURE.to_vec();
+    bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+    bytes.extend_from_slice(b"tEXt");
+    bytes.extend_from_slice(&[0; 8]);
+    assert!(parse_card_png(&bytes).is_err());
+  }
+
+  #[test]
+  fn bad_crc_is_rejected(){
+    let mut text = text_chunk("chara", r#"{"name": "Lily"}"#);
+    let last = text.len() - 1;
+    text[last] ^= 0xff;
+    assert!(parse_card_png(&png(&[text])).is_err());
+  }
+
+  #[test]
+  fn png_without_card_or_signature(){
+    assert!(parse_card_png(&png(&[])).is_err());
+    assert!(parse_card_png(b"GIF89a").is_err());
+  }
+}
@@ -0,0 +1,151 @@
+use serde::Deserialize;
+
+use crate::kobold::StoredMessage;
+
+//every piece of text the model sees around a turn, a user defined template is a json file with these same keys
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChatTemplate{
+  #[serde(default)]
+  pub bos: String,
+  pub system_prefix: String,
+  pub system_suffix: String,
+  pub user_prefix: String,
+  pub user_suffix: String,
+  pub assistant_prefix: String,
+  pub assistant_suffix: String,
+  #[serde(default)]
+  pub stop_sequences: Vec<String>,
+}
+
+impl ChatTemplate{
+  pub fn llama3() -> Self{
+    Self{
+      bos: "<|begin_of_text|>".to_string(),
+      system_prefix: "<|start_header_id|>system<|end_header_id|>\n\n".to_string(),
+      system_suffix: "<|eot_id|>".to_string(),
+      user_prefix: "<|start_header_id|>user<|end_header_id|>\n\n".to_string(),
+      user_suffix: "<|eot_id|>".to_string(),
+      assistant_prefix: "<|start_header_id|>assistant<|end_header_id|>\n\n".to_string(),
+      assistant_suffix: "<|eot_id|>".to_string(),
+      stop_sequences: vec![
+        "<|eot_id|><|end_of_text|>".to_string(),
+        "<|start_header_id|>user<|end_header_id|>".to_string(),
+        "<|start_header_id|>assistant<|end_header_id|>".to_string(),
+        "<|begin_of_text|><|start_header_id|>system<|end_header_id|>".to_string(),
+      ],
+    }
+  }
+
+  pub fn chatml() -> Self{
+    Self{
+      bos: String::new(),
+      system_prefix: "<|im_start|>system\n".to_string(),
+      system_suffix: "<|im_end|>\n".to_string(),
+      user_prefix: "<|im_start|>user\n".to_string(),
+      user_suffix: "<|im_end|>\n".to_string(),
+      assistant_prefix: "<|im_start|>assistant\n".to_string(),
+      assistant_suffix: "<|im_end|>\n".to_string(),
+      stop_sequences: vec![
+        "<|im_end|>".to_string(),
+        "<|im_start|>".to_string(),
+      ],
+    }
+  }
+
+  //mistral has no system role, so the system prompt rides along as its own instruction
+  pub fn mistral() -> Self{
+    Self{
+      bos: "<s>".to_string(),
+      system_prefix: "[INST] ".to_string(),
+      system_suffix: " [/INST]".to_string(),
+      user_prefix: "[INST] ".to_string(),
+      user_suffix: " [/INST]".to_string(),
+      assistant_prefix: String::new(),
+      assistant_suffix: "</s>".to_string(),
+      stop_sequences: vec![
+        "</s>".to_string(),
+        "[INST]".to_string(),
+      ],
+    }
+  }
+
+  pub fn alpaca() -> Self{
+    Self{
+      bos: String::new(),
+      system_prefix: String::new(),
+      system_suffix: "\n\n".to_string(),
+      user_prefix: "### Instruction:\n".to_string(),
+      user_suffix: "\n\n".to_string(),
+      assistant_prefix: "### Response:\n".to_string(),
+      assistant_suffix: "\n\n".to_string(),
+      stop_sequences: vec![
+        "### Instruction:".to_string(),
+        "### Response:".to_string(),
+      ],
+    }
+  }
+
+  //gemma also lacks a system role, the usual workaround is a leading user turn
+  pub fn gemma() -> Self{
+    Self{
+      bos: "<bos>".to_string(),
+      system_prefix: "<start_of_turn>user\n".to_string(),
+      system_suffix: "<end_of_turn>\n".to_string(),
+      user_prefix: "<start_of_turn>user\n".to_string(),
+      user_suffix: "<end_of_turn>\n".to_string(),
+      assistant_prefix: "<start_of_turn>model\n".to_string(),
+      assistant_suffix: "<end_of_turn>\n".to_string(),
+      stop_sequences: vec![
+        "<end_of_turn>".to_string(),
+        "<start_of_turn>".to_string(),
+      ],
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self>{
+    match name.to_lowercase().as_str(){
+      "llama3" => Some(Self::llama3()),
+      "chatml" => Some(Self::chatml()),
+      "mistral" => Some(Self::mistral()),
+      "alpaca" => Some(Self::alpaca()),
+      "gemma" => Some(Self::gemma()),
+      _ => None,
+    }
+  }
+
+  pub fn from_file(path: &str) -> Result<Self, String>{
+    let contents = std::fs::read_to_string(path)
+      .map_err(|err| format!("Unable to read chat template file {}: {}", path, err))?;
+    serde_json::from_str(&contents)
+      .map_err(|err| format!("Chat template file {} is not valid: {}", path, err))
+  }
+
+  //CHAT_TEMPLATE is either one of the built in names or a path to a json template file
+  pub fn from_env() -> Result<Self, String>{
+    let setting = match std::env::var("CHAT_TEMPLATE"){
+      Ok(r) if !r.trim().is_empty() => r,
+      _ => return Ok(Self::llama3()),
+    };
+    match Self::from_name(setting.trim()){
+      Some(r) => Ok(r),
+      None => Self::from_file(setting.trim()),
+    }
+  }
+
+  pub fn render_system(&self, system: &str) -> String{
+    format!("{}{}{}{}", self.bos, self.system_prefix, system, self.system_suffix)
+  }
+
+  //the bot's own messages go back in as assistant turns so the model sees a proper conversation
+  pub fn render_message(&self, message: &StoredMessage, bot_name: &str) -> String{
+    if message.author == bot_name{
+      format!("{}{}: {}{}", self.assistant_prefix, message.author, message.message, self.assistant_suffix)
+    }else{
+      format!("{}{}: {}{}", self.user_prefix, message.author, message.message, self.user_suffix)
+    }
+  }
+
+  pub fn render_reply_start(&self, bot_name: &str) -> String{
+    format!("{}{}:", self.assistant_prefix, bot_name)
+  }
+}
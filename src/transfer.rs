@@ -0,0 +1,484 @@
+use std::collections::{
+  BTreeMap,
+  HashMap,
+  HashSet,
+};
+
+use chrono::DateTime;
+use serde::{
+  Serialize,
+  Deserialize,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+  backend::SamplerPreset,
+  database::{
+    database_key,
+    per_guild,
+    Database,
+    DatabaseResult,
+    Databases,
+  },
+  embeddings::EmbeddingJob,
+  encryption::{
+    rotate_key,
+    MessageCipher,
+  },
+  retention::RetentionPolicy,
+  storage::GUILD_WIDE,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportScope{
+  Channel(u64),
+  Guild(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat{
+  Jsonl,
+  Markdown,
+}
+
+//one line of a jsonl export, records are written oldest first so a summary always follows
+//the messages it covers
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExportRecord{
+  Message{
+    channel: u64,
+    guild: Option<u64>,
+    author: String,
+    message: String,
+    discord_id: Option<u64>,
+    user_id: Option<u64>,
+    source: String,
+    created_at: Option<i64>,
+    edited_at: Option<i64>,
+  },
+  //covers every message of its channel above it in the file
+  Summary{
+    channel: u64,
+    summary: String,
+    created_at: i64,
+  },
+  Persona{
+    guild: u64,
+    persona: String,
+  },
+  Preset{
+    guild: u64,
+    name: String,
+    settings: SamplerPreset,
+  },
+  PresetAssignment{
+    guild: u64,
+    channel: u64,
+    preset: String,
+  },
+  Retention{
+    guild: u64,
+    max_age_days: Option<u32>,
+    max_rows_per_channel: Option<u32>,
+    keep_summaries_only: bool,
+  },
+}
+
+impl ExportRecord{
+  //settings records belong to a guild, messages only when it was recorded
+  fn guild(&self) -> Option<u64>{
+    match self{
+      ExportRecord::Message{guild, ..} => *guild,
+      ExportRecord::Summary{..} => None,
+      ExportRecord::Persona{guild, ..}
+      | ExportRecord::Preset{guild, ..}
+      | ExportRecord::PresetAssignment{guild, ..}
+      | ExportRecord::Retention{guild, ..} => Some(*guild),
+    }
+  }
+
+  fn channel(&self) -> Option<u64>{
+    match self{
+      ExportRecord::Message{channel, ..}
+      | ExportRecord::Summary{channel, ..}
+      | ExportRecord::PresetAssignment{channel, ..} => Some(*channel),
+      _ => None,
+    }
+  }
+}
+
+//a message record on its way into the database
+#[derive(Clone, Copy)]
+pub struct ImportedMessage<'a>{
+  pub channel: u64,
+  pub guild: Option<u64>,
+  pub author: &'a str,
+  pub message: &'a str,
+  pub discord_id: Option<u64>,
+  pub user_id: Option<u64>,
+  pub source: &'a str,
+  pub created_at: Option<i64>,
+  pub edited_at: Option<i64>,
+}
+
+//where an import from a discord command may write: the guild it was run in and the channels it has
+#[derive(Debug)]
+pub struct ImportTarget{
+  pub guild: u64,
+  pub channels: HashSet<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport{
+  pub messages: usize,
+  //already in the database or belonging to another guild
+  pub skipped: usize,
+  pub summaries: usize,
+  pub settings: usize,
+}
+
+fn settings_records(db: &mut dyn Database, scope: ExportScope) -> DatabaseResult<Vec<ExportRecord>>{
+  let mut records: Vec<ExportRecord> = db.preset_assignments(scope)?.into_iter()
+    .map(|(guild, channel, preset)| ExportRecord::PresetAssignment{guild, channel, preset})
+    .collect();
+  let guild = match scope{
+    ExportScope::Guild(guild) => guild,
+    //a channel only carries its own preset assignment, the presets themselves belong to the guild
+    ExportScope::Channel(_) => return Ok(records),
+  };
+  let mut settings = Vec::new();
+  if let Some(persona) = db.guild_persona(guild)?{
+    settings.push(ExportRecord::Persona{guild, persona});
+  }
+  for (name, preset_json) in db.presets(guild)?{
+    match serde_json::from_str(&preset_json){
+      Ok(preset) => settings.push(ExportRecord::Preset{guild, name, settings: preset}),
+      Err(err) => println!("Sampler preset {} can't be read and is left out of the export: {}", name, err),
+    }
+  }
+  //assignments go after the presets they point at
+  settings.append(&mut records);
+  let policy = db.load_policy(guild)?;
+  if !policy.is_empty(){
+    settings.push(ExportRecord::Retention{
+      guild,
+      max_age_days: policy.max_age_days,
+      max_rows_per_channel: policy.max_rows_per_channel,
+      keep_summaries_only: policy.keep_summaries_only,
+    });
+  }
+  Ok(settings)
+}
+
+//settings first, then messages with each summary slotted in after the last message it covers.
+//deleted messages and embeddings are left out, the importing side embeds again if it's set up to
+pub fn export_records(db: &mut dyn Database, scope: ExportScope) -> DatabaseResult<Vec<ExportRecord>>{
+  let mut records = settings_records(db, scope)?;
+  let mut summaries = db.export_summaries(scope)?.into_iter().peekable();
+  for (id, message) in db.export_messages(scope)?{
+    while summaries.peek().is_some_and(|(last, _)| *last < id){
+      records.push(summaries.next().unwrap().1);
+    }
+    records.push(message);
+  }
+  records.extend(summaries.map(|(_, summary)| summary));
+  Ok(records)
+}
+
+fn format_time(time: Option<i64>) -> String{
+  time.and_then(|time| DateTime::from_timestamp(time, 0))
+    .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+    .unwrap_or("unknown time".to_string())
+}
+
+//for people to read, only jsonl can be imported again
+fn render_markdown(scope: ExportScope, records: &[ExportRecord]) -> String{
+  let mut markdown = match scope{
+    ExportScope::Channel(channel) => format!("# Memory of channel {}\n", channel),
+    ExportScope::Guild(guild) => format!("# Memory of server {}\n", guild),
+  };
+  let mut current_channel = None;
+  for record in records{
+    match record{
+      ExportRecord::Persona{persona, ..} => markdown.push_str(&format!("\n- Persona: {}", persona)),
+      ExportRecord::Preset{name, settings, ..} => markdown.push_str(&format!("\n- Preset {}: `{}`", name, serde_json::to_string(settings).unwrap_or_default())),
+      ExportRecord::PresetAssignment{channel, preset, ..} => markdown.push_str(&format!("\n- Channel {} uses preset {}", channel, preset)),
+      ExportRecord::Retention{max_age_days, max_rows_per_channel, keep_summaries_only, ..} => markdown.push_str(&format!(
+        "\n- Retention: max age {} days, max rows per channel {}, keep summaries only {}",
+        max_age_days.map(|days| days.to_string()).unwrap_or("unlimited".to_string()),
+        max_rows_per_channel.map(|rows| rows.to_string()).unwrap_or("unlimited".to_string()),
+        keep_summaries_only,
+      )),
+      ExportRecord::Message{channel, author, message, source, created_at, ..} => {
+        if current_channel != Some(*channel){
+          markdown.push_str(&format!("\n\n## Channel {}\n", channel));
+          current_channel = Some(*channel);
+        }
+        markdown.push_str(&format!("\n**{}** ({}, {}): {}\n", author, format_time(*created_at), source, message));
+      },
+      ExportRecord::Summary{channel, summary, created_at} => {
+        if current_channel != Some(*channel){
+          markdown.push_str(&format!("\n\n## Channel {}\n", channel));
+          current_channel = Some(*channel);
+        }
+        markdown.push_str(&format!("\n> **Summary written {}:** {}\n", format_time(Some(*created_at)), summary.replace('\n', "\n> ")));
+      },
+    }
+  }
+  markdown.push('\n');
+  markdown
+}
+
+pub fn export(db: &mut dyn Database, scope: ExportScope, format: ExportFormat) -> DatabaseResult<String>{
+  let records = export_records(db, scope)?;
+  Ok(match format{
+    ExportFormat::Jsonl => records.iter()
+      .map(|record| serde_json::to_string(record).unwrap() + "\n")
+      .collect(),
+    ExportFormat::Markdown => render_markdown(scope, &records),
+  })
+}
+
+//rows that already exist are matched up instead of inserted, so importing the same file twice is harmless
+fn import_record(
+  db: &mut dyn Database,
+  record: &ExportRecord,
+  report: &mut ImportReport,
+  last_message: &mut HashMap<u64, i64>,
+  to_embed: &mut Vec<EmbeddingJob>,
+) -> DatabaseResult<()>{
+  match record{
+    ExportRecord::Message{channel, guild, author, message, discord_id, user_id, source, created_at, edited_at} => {
+      let (id, inserted) = db.import_message(&ImportedMessage{
+        channel: *channel,
+        guild: *guild,
+        author,
+        message,
+        discord_id: *discord_id,
+        user_id: *user_id,
+        source,
+        created_at: *created_at,
+        edited_at: *edited_at,
+      })?;
+      last_message.insert(*channel, id);
+      if inserted{
+        report.messages += 1;
+        if let Some(guild) = guild.filter(|_| !message.trim().is_empty()){
+          to_embed.push(EmbeddingJob{message: id, guild, text: message.clone()});
+        }
+      }else{
+        report.skipped += 1;
+      }
+    },
+    //the newest message seen in the channel is what a summary that follows covers up to
+    ExportRecord::Summary{channel, summary, created_at} => match last_message.get(channel){
+      Some(last) if db.import_summary(*channel, *last, summary, *created_at)? => report.summaries += 1,
+      _ => report.skipped += 1,
+    },
+    ExportRecord::Persona{guild, persona} => {
+      db.set_persona(*guild, persona)?;
+      report.settings += 1;
+    },
+    ExportRecord::Preset{guild, name, settings} => {
+      db.save_preset(*guild, name, &serde_json::to_string(settings).unwrap())?;
+      report.settings += 1;
+    },
+    ExportRecord::PresetAssignment{guild, channel, preset} => {
+      db.assign_preset(*guild, *channel, Some(preset))?;
+      report.settings += 1;
+    },
+    ExportRecord::Retention{guild, max_age_days, max_rows_per_channel, keep_summaries_only} => {
+      db.save_policy(*guild, &RetentionPolicy{
+        max_age_days: *max_age_days,
+        max_rows_per_channel: *max_rows_per_channel,
+        keep_summaries_only: *keep_summaries_only,
+      })?;
+      report.settings += 1;
+    },
+  }
+  Ok(())
+}
+
+pub fn parse_records(data: &str) -> Result<Vec<ExportRecord>, String>{
+  let mut records = Vec::new();
+  for (line_number, line) in data.lines().enumerate(){
+    if line.trim().is_empty(){
+      continue;
+    }
+    let record: ExportRecord = serde_json::from_str(line)
+      .map_err(|err| format!("Line {} is not an export record: {}", line_number+1, err))?;
+    records.push(record);
+  }
+  Ok(records)
+}
+
+//target keeps an import from a discord command inside the guild it was run in, messages with no
+//recorded guild are taken to belong to it. records for channels the guild doesn't have are skipped
+//so a file can't write into another guild's channels
+pub fn import(
+  db: &mut dyn Database,
+  records: Vec<ExportRecord>,
+  target: Option<&ImportTarget>,
+  embeddings: Option<&UnboundedSender<EmbeddingJob>>,
+) -> Result<ImportReport, String>{
+  let mut report = ImportReport::default();
+  let mut to_embed = Vec::new();
+  let mut last_message: HashMap<u64, i64> = HashMap::new();
+  db.begin().map_err(|err| err.to_string())?;
+  for mut record in records{
+    if let Some(target) = target{
+      let foreign_guild = record.guild().is_some_and(|guild| guild != target.guild);
+      let foreign_channel = record.channel().is_some_and(|channel| channel != GUILD_WIDE && !target.channels.contains(&channel));
+      if foreign_guild || foreign_channel{
+        report.skipped += 1;
+        continue;
+      }
+      if let ExportRecord::Message{guild, ..} = &mut record{
+        *guild = Some(target.guild);
+      }
+    }
+    if let Err(err) = import_record(db, &record, &mut report, &mut last_message, &mut to_embed){
+      if let Err(err) = db.rollback(){
+        println!("Can't roll back failed import: {}", err);
+      }
+      return Err(format!("Import failed, nothing was imported: {}", err));
+    }
+  }
+  db.commit().map_err(|err| err.to_string())?;
+  if let Some(embeddings) = embeddings{
+    for job in to_embed{
+      if let Err(err) = embeddings.send(job){
+        println!("Unable to send imported message to embedding thread: {}", err);
+      }
+    }
+  }
+  Ok(report)
+}
+
+//with DATABASE_PER_GUILD every guild's records go to the guild's own file, and a summary goes wherever
+//the messages of its channel went
+fn split_by_database(records: Vec<ExportRecord>) -> BTreeMap<Option<u64>, Vec<ExportRecord>>{
+  let mut channels = HashMap::new();
+  let mut split: BTreeMap<Option<u64>, Vec<ExportRecord>> = BTreeMap::new();
+  for record in records{
+    let key = match &record{
+      ExportRecord::Message{channel, guild, ..} => {
+        let key = database_key(*guild);
+        channels.insert(*channel, key);
+        key
+      },
+      ExportRecord::Summary{channel, ..} => channels.get(channel).copied().flatten(),
+      other => database_key(other.guild()),
+    };
+    split.entry(key).or_default().push(record);
+  }
+  split
+}
+
+const CLI_USAGE: &str = "Usage:
+  lily export (--guild <id> | [--guild <id>] --channel <id>) [--format jsonl|markdown] [--output <file>]
+  lily import <file>
+  lily rotate-key [<new key file>]
+With DATABASE_PER_GUILD a channel export also needs the channel's --guild. rotate-key re-encrypts stored
+conversations from the configured key to the one in the file, or back to plain text without a file. Stop the
+bot while it runs";
+
+//handles `lily export ...`, `lily import ...` and `lily rotate-key ...` without starting the bot, None when
+//the arguments aren't a cli command at all. database calls block, so they run off the async runtime
+pub fn run_cli(args: &[String]) -> Option<Result<(), String>>{
+  let command = args.first()?.as_str();
+  if !["export", "import", "rotate-key"].contains(&command){
+    return None;
+  }
+  Some(std::thread::scope(|scope| scope.spawn(|| cli_command(command, &args[1..])).join().unwrap()))
+}
+
+fn cli_command(command: &str, args: &[String]) -> Result<(), String>{
+  let cipher = MessageCipher::from_env()?;
+  let mut databases = Databases::open(cipher.clone()).map_err(|err| format!("Unable to open the database: {}", err))?;
+  if command == "import"{
+    let path = args.first().ok_or(CLI_USAGE.to_string())?;
+    let data = std::fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {}", path, err))?;
+    //each database file is imported in a transaction of its own
+    let mut report = ImportReport::default();
+    for (key, records) in split_by_database(parse_records(&data)?){
+      let db = databases.get(key).map_err(|err| format!("Unable to open the database: {}", err))?;
+      let imported = import(db, records, None, None)?;
+      report.messages += imported.messages;
+      report.skipped += imported.skipped;
+      report.summaries += imported.summaries;
+      report.settings += imported.settings;
+    }
+    println!(
+      "Imported {} messages, {} summaries and {} settings, skipped {} records",
+      report.messages, report.summaries, report.settings, report.skipped,
+    );
+    return Ok(());
+  }
+  if command == "rotate-key"{
+    let new = args.first().map(|path| MessageCipher::from_file(path)).transpose()?;
+    let mut rewritten = 0;
+    //with DATABASE_PER_GUILD each file rotates on its own and a failure leaves that file and the ones after it
+    //as they were. files already done are skipped over when it's run again with the same keys
+    for key in databases.keys(){
+      let name = match key{
+        Some(guild) => format!("the database of guild {}", guild),
+        None => "the main database".to_string(),
+      };
+      let rotated = databases.get(key).and_then(|db| rotate_key(db, cipher.as_ref(), new.as_ref()));
+      match rotated{
+        Ok(r) => {
+          println!("Rotated {}", name);
+          rewritten += r;
+        },
+        Err(err) => return Err(format!(
+          "Unable to rotate {}: {}. The databases listed above are on the new key already, the rest are not. Run rotate-key again with the same keys to finish",
+          name,
+          err,
+        )),
+      }
+    }
+    match new{
+      Some(_) => println!("Re-encrypted {} stored texts, point ENCRYPTION_KEY_FILE at the new key before starting the bot again", rewritten),
+      None => println!("Decrypted {} stored texts, unset ENCRYPTION_KEY and ENCRYPTION_KEY_FILE before starting the bot again", rewritten),
+    }
+    return Ok(());
+  }
+  let mut guild = None;
+  let mut channel = None;
+  let mut format = ExportFormat::Jsonl;
+  let mut output = None;
+  let mut args = args.iter();
+  while let Some(arg) = args.next(){
+    let value = args.next().ok_or(CLI_USAGE.to_string())?;
+    match arg.as_str(){
+      "--guild" => guild = Some(value.parse().map_err(|_| CLI_USAGE.to_string())?),
+      "--channel" => channel = Some(value.parse().map_err(|_| CLI_USAGE.to_string())?),
+      "--format" => format = match value.as_str(){
+        "jsonl" => ExportFormat::Jsonl,
+        "markdown" | "md" => ExportFormat::Markdown,
+        _ => return Err(CLI_USAGE.to_string()),
+      },
+      "--output" => output = Some(value.clone()),
+      _ => return Err(CLI_USAGE.to_string()),
+    }
+  }
+  let scope = match (guild, channel){
+    (_, Some(channel)) => ExportScope::Channel(channel),
+    (Some(guild), None) => ExportScope::Guild(guild),
+    (None, None) => return Err(CLI_USAGE.to_string()),
+  };
+  if per_guild() && guild.is_none(){
+    return Err(CLI_USAGE.to_string());
+  }
+  let db = databases.get(guild).map_err(|err| format!("Unable to open the database: {}", err))?;
+  let exported = export(db, scope, format).map_err(|err| format!("Unable to export: {}", err))?;
+  match output{
+    Some(path) => std::fs::write(&path, exported).map_err(|err| format!("Unable to write {}: {}", path, err)),
+    None => {
+      print!("{}", exported);
+      Ok(())
+    },
+  }
+}
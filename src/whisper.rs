@@ -3,7 +3,12 @@ use tokio::sync::mpsc::UnboundedSender;
 use futures_util::StreamExt;
 use serde::Deserialize;
 use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
-use crate::storage::StorageMessage;
+use crate::storage::{
+  unix_now,
+  MessageSource,
+  StorageMessage,
+  StorageRequest,
+};
 
 #[derive(Deserialize)]
 struct WhisperResponse{
@@ -15,7 +20,9 @@ pub type WhisperSink = futures_util::stream::SplitSink<
     tokio_tungstenite::MaybeTlsStream<
       tokio::net::TcpStream>>, TungsteniteMessage>;
 
-pub async fn spawn_whisper_thread(storage_tx: UnboundedSender<StorageMessage>, display_name: String, channel: u64) -> WhisperSink{
+pub async fn spawn_whisper_thread(storage_tx: UnboundedSender<StorageRequest>, display_name: String, user_id: u64, channel: u64, guild: u64) -> WhisperSink{
+  //the utterance is dated from when the speaker started, not when whisper got done with it
+  let started_at = unix_now();
   let(ws_stream, _) = connect_async(
     std::env::var("WHISPER_URL").expect("Expected WHISPER_URL in the environment variables")
   ).await.expect("Failed to connect to whisper server");
@@ -51,11 +58,17 @@ pub async fn spawn_whisper_thread(storage_tx: UnboundedSender<StorageMessage>, d
     if full_transcription == String::new(){
       return;
     }
-    if let Err(err) = storage_tx.send(StorageMessage{
+    if let Err(err) = storage_tx.send(StorageRequest::Store(StorageMessage{
       channel,
+      guild: Some(guild),
       author: display_name,
       message: full_transcription,
-    }){
+      discord_id: None,
+      parts: Vec::new(),
+      user_id: Some(user_id),
+      source: MessageSource::Voice,
+      created_at: started_at,
+    })){
       println!("Error sending trascription message: {}", err);
     };
   });